@@ -1,10 +1,26 @@
-use crate::db::{AccountsDb, TransactionExtrasDb, TransactionsDb};
+use crate::db::{
+    AccountsDb, BalanceSnapshotsDb, ChangesDb, ExportBatchesDb, InvestmentSyncStatesDb, InvestmentsDb, MarketOrdersDb, ReconciliationsDb, SyncRunsDb,
+    SyncStatesDb, TransactionExtrasDb, TransactionsDb,
+};
+use crate::events::EventBus;
+use crate::jobs::JobManager;
 use crate::powens::PowensApi;
 
 #[derive(Clone)]
 pub struct AppState {
     pub account_db: AccountsDb,
+    pub balance_snapshot_db: BalanceSnapshotsDb,
+    pub change_db: ChangesDb,
+    pub export_batch_db: ExportBatchesDb,
+    pub investment_db: InvestmentsDb,
+    pub investment_sync_state_db: InvestmentSyncStatesDb,
+    pub market_order_db: MarketOrdersDb,
+    pub reconciliation_db: ReconciliationsDb,
+    pub sync_run_db: SyncRunsDb,
+    pub sync_state_db: SyncStatesDb,
     pub transaction_db: TransactionsDb,
     pub transaction_extras_db: TransactionExtrasDb,
+    pub event_bus: EventBus,
+    pub job_manager: JobManager,
     pub powens_api: PowensApi,
 }
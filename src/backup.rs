@@ -0,0 +1,634 @@
+//! Backup and restore of the DBs and the active AI prompts
+
+use crate::app_state::AppState;
+use crate::config::env_or_default;
+use crate::db::{
+    ACCOUNTS_DB_FILE, BALANCE_SNAPSHOTS_DB_FILE, BalanceSnapshot, CHANGES_DB_FILE, Change,
+    ChangeSource, Cipher, EXPORT_BATCHES_DB_FILE, ExportBatch, INVESTMENT_SYNC_STATES_DB_FILE,
+    INVESTMENTS_DB_FILE, InvestmentSyncState, MARKET_ORDERS_DB_FILE, RECONCILIATIONS_DB_FILE,
+    Reconciliation, SYNC_RUNS_DB_FILE, SYNC_STATES_DB_FILE, SchemaVersioned, StructFileDb, SyncRun,
+    SyncState, TRANSACTION_DB_FILE, TRANSACTION_EXTRAS_DB_FILE, TransactionExtras,
+    decrypt_if_encrypted, deserialize_json_file, encrypt_if_enabled, serialize_json_file,
+};
+use crate::genai::{EXPENSES_PROMPT_FILE, INCOME_PROMPT_FILE, read_ai_prompt_file};
+use crate::powens::{Account, HasId, Investment, MarketOrder, Sortable, Transaction};
+use chrono::Utc;
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use tracing::{error, info};
+
+/// Archives bigger than this can't be restored.
+pub const MAX_BACKUP_SIZE: usize = 200 * 1024 * 1024;
+/// Archives which files are bigger than this once decompressed can't be restored.
+const MAX_BACKUP_CONTENT_SIZE: u64 = 1024 * 1024 * 1024;
+
+const BACKUP_FILE_PREFIX: &str = "backup-";
+const BACKUP_FILE_EXTENSION: &str = ".tar.gz";
+const ENCRYPTED_FILE_EXTENSION: &str = ".enc";
+/// Encrypted archives are bound to this name, so they can't be mistaken for a DB.
+const BACKUP_ENCRYPTION_NAME: &str = "backup";
+const BACKUP_DATETIME_FORMAT: &str = "%Y%m%d-%H%M%S";
+/// Suffix of the backups of the data replaced by a restore, which are not rotated.
+const PRE_RESTORE_SUFFIX: &str = "-pre-restore";
+
+const DB_FILES: [&str; 12] = [
+    ACCOUNTS_DB_FILE,
+    BALANCE_SNAPSHOTS_DB_FILE,
+    CHANGES_DB_FILE,
+    EXPORT_BATCHES_DB_FILE,
+    INVESTMENTS_DB_FILE,
+    INVESTMENT_SYNC_STATES_DB_FILE,
+    MARKET_ORDERS_DB_FILE,
+    RECONCILIATIONS_DB_FILE,
+    SYNC_RUNS_DB_FILE,
+    SYNC_STATES_DB_FILE,
+    TRANSACTION_DB_FILE,
+    TRANSACTION_EXTRAS_DB_FILE,
+];
+const AI_PROMPT_FILES: [&str; 2] = [INCOME_PROMPT_FILE, EXPENSES_PROMPT_FILE];
+
+/// Content of a backup archive, validated and ready to be restored.
+pub struct Backup {
+    accounts: Vec<Account>,
+    balance_snapshots: Vec<BalanceSnapshot>,
+    changes: Vec<Change>,
+    export_batches: Vec<ExportBatch>,
+    investments: Vec<Investment>,
+    investment_sync_states: Vec<InvestmentSyncState>,
+    market_orders: Vec<MarketOrder>,
+    reconciliations: Vec<Reconciliation>,
+    sync_runs: Vec<SyncRun>,
+    sync_states: Vec<SyncState>,
+    transactions: Vec<Transaction>,
+    transaction_extras: Vec<TransactionExtras>,
+    /// AI prompt files found in the archive, with their content.
+    ai_prompts: Vec<(&'static str, Vec<u8>)>,
+}
+
+/// A backup archive, named after its creation datetime.
+pub struct BackupArchive {
+    /// ex: `backup-20250101-030000.tar.gz`, with a `.enc` extension when encrypted.
+    pub file_name: String,
+    pub content: Vec<u8>,
+    pub encrypted: bool,
+}
+
+/**
+Create a tar.gz archive of all DBs and of the active AI prompts.
+
+DBs are exported as JSON files whatever the storage backend is, with the same path as the JSON
+backend, ex: `db/accounts.json`. When DB encryption is enabled, the archive is encrypted too.
+*/
+pub fn create_backup(app_state: &AppState) -> Result<BackupArchive, Box<dyn std::error::Error>> {
+    create_backup_with_suffix(app_state, "")
+}
+
+/// Create a backup, the suffix is added to the file name before the extension.
+fn create_backup_with_suffix(
+    app_state: &AppState,
+    suffix: &str,
+) -> Result<BackupArchive, Box<dyn std::error::Error>> {
+    let mut archive = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+
+    append_db(&mut archive, ACCOUNTS_DB_FILE, &app_state.account_db)?;
+    append_db(
+        &mut archive,
+        BALANCE_SNAPSHOTS_DB_FILE,
+        &app_state.balance_snapshot_db,
+    )?;
+    append_db(&mut archive, CHANGES_DB_FILE, &app_state.change_db)?;
+    append_db(
+        &mut archive,
+        EXPORT_BATCHES_DB_FILE,
+        &app_state.export_batch_db,
+    )?;
+    append_db(&mut archive, INVESTMENTS_DB_FILE, &app_state.investment_db)?;
+    append_db(
+        &mut archive,
+        INVESTMENT_SYNC_STATES_DB_FILE,
+        &app_state.investment_sync_state_db,
+    )?;
+    append_db(
+        &mut archive,
+        MARKET_ORDERS_DB_FILE,
+        &app_state.market_order_db,
+    )?;
+    append_db(
+        &mut archive,
+        RECONCILIATIONS_DB_FILE,
+        &app_state.reconciliation_db,
+    )?;
+    append_db(&mut archive, SYNC_RUNS_DB_FILE, &app_state.sync_run_db)?;
+    append_db(&mut archive, SYNC_STATES_DB_FILE, &app_state.sync_state_db)?;
+    append_db(&mut archive, TRANSACTION_DB_FILE, &app_state.transaction_db)?;
+    append_db(
+        &mut archive,
+        TRANSACTION_EXTRAS_DB_FILE,
+        &app_state.transaction_extras_db,
+    )?;
+
+    // the example file is archived as the AI prompt file if it is the one in use
+    for file_path in AI_PROMPT_FILES {
+        if let Ok(content) = read_ai_prompt_file(file_path) {
+            append_file(&mut archive, file_path, content.as_bytes())?;
+        }
+    }
+
+    let cipher = Cipher::from_env()?;
+    let content = encrypt_if_enabled(
+        cipher.as_ref(),
+        archive.into_inner()?.finish()?,
+        BACKUP_ENCRYPTION_NAME,
+    )?;
+    let mut file_name = format!(
+        "{}{}{}{}",
+        BACKUP_FILE_PREFIX,
+        Utc::now().format(BACKUP_DATETIME_FORMAT),
+        suffix,
+        BACKUP_FILE_EXTENSION
+    );
+    if cipher.is_some() {
+        file_name.push_str(ENCRYPTED_FILE_EXTENSION);
+    }
+
+    Ok(BackupArchive {
+        file_name,
+        content,
+        encrypted: cipher.is_some(),
+    })
+}
+
+fn append_db<T>(
+    archive: &mut tar::Builder<GzEncoder<Vec<u8>>>,
+    file_path: &str,
+    db: &StructFileDb<T>,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    T: serde::Serialize
+        + for<'de> serde::Deserialize<'de>
+        + Clone
+        + HasId
+        + Sortable
+        + SchemaVersioned
+        + PartialEq,
+{
+    append_file(
+        archive,
+        file_path,
+        serialize_json_file(&db.data())?.as_bytes(),
+    )
+}
+
+fn append_file(
+    archive: &mut tar::Builder<GzEncoder<Vec<u8>>>,
+    file_path: &str,
+    content: &[u8],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(content.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(Utc::now().timestamp() as u64);
+    header.set_cksum();
+    archive.append_data(&mut header, file_path, content)?;
+    Ok(())
+}
+
+/**
+Read and validate a backup archive, decrypted if it is encrypted.
+
+DB files must be in the archive and readable, except the DBs added after the first backups. Older
+schema versions are migrated. AI prompt files are optional, the current ones are kept if they are
+missing. The decompressed content is limited to `MAX_BACKUP_CONTENT_SIZE`.
+*/
+pub fn read_backup(archive: &[u8]) -> Result<Backup, Box<dyn std::error::Error>> {
+    let archive = decrypt_if_encrypted(
+        Cipher::from_env()?.as_ref(),
+        archive.to_vec(),
+        BACKUP_ENCRYPTION_NAME,
+    )?;
+
+    let mut files: HashMap<String, Vec<u8>> = HashMap::new();
+    let mut remaining_size = MAX_BACKUP_CONTENT_SIZE;
+    let mut entries = tar::Archive::new(GzDecoder::new(archive.as_slice()));
+    for entry in entries.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_string_lossy().to_string();
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        if !is_backup_file(&path) {
+            return Err(format!("Unexpected file in archive: {}", path).into());
+        }
+        // the size in the header can't be trusted, read at most one byte more than allowed
+        let mut content = Vec::new();
+        (&mut entry)
+            .take(remaining_size + 1)
+            .read_to_end(&mut content)?;
+        if content.len() as u64 > remaining_size {
+            return Err(format!(
+                "Archive content is bigger than {} bytes once decompressed",
+                MAX_BACKUP_CONTENT_SIZE
+            )
+            .into());
+        }
+        remaining_size -= content.len() as u64;
+        files.insert(path, content);
+    }
+
+    let mut ai_prompts = Vec::new();
+    for file_path in AI_PROMPT_FILES {
+        if let Some(content) = files.remove(file_path) {
+            serde_json::from_slice::<serde_json::Value>(&content)
+                .map_err(|e| format!("Invalid {}: {}", file_path, e))?;
+            ai_prompts.push((file_path, content));
+        }
+    }
+
+    Ok(Backup {
+        accounts: read_db_file(&files, ACCOUNTS_DB_FILE)?,
+        balance_snapshots: read_db_file(&files, BALANCE_SNAPSHOTS_DB_FILE)?,
+        changes: read_optional_db_file(&files, CHANGES_DB_FILE)?,
+        export_batches: read_optional_db_file(&files, EXPORT_BATCHES_DB_FILE)?,
+        investments: read_db_file(&files, INVESTMENTS_DB_FILE)?,
+        investment_sync_states: read_optional_db_file(&files, INVESTMENT_SYNC_STATES_DB_FILE)?,
+        market_orders: read_db_file(&files, MARKET_ORDERS_DB_FILE)?,
+        reconciliations: read_db_file(&files, RECONCILIATIONS_DB_FILE)?,
+        sync_runs: read_db_file(&files, SYNC_RUNS_DB_FILE)?,
+        sync_states: read_db_file(&files, SYNC_STATES_DB_FILE)?,
+        transactions: read_db_file(&files, TRANSACTION_DB_FILE)?,
+        transaction_extras: read_db_file(&files, TRANSACTION_EXTRAS_DB_FILE)?,
+        ai_prompts,
+    })
+}
+
+fn is_backup_file(path: &str) -> bool {
+    DB_FILES.contains(&path) || AI_PROMPT_FILES.contains(&path)
+}
+
+fn read_db_file<T>(
+    files: &HashMap<String, Vec<u8>>,
+    file_path: &str,
+) -> Result<Vec<T>, Box<dyn std::error::Error>>
+where
+    T: for<'de> serde::Deserialize<'de> + SchemaVersioned,
+{
+    let content = files
+        .get(file_path)
+        .ok_or(format!("Missing {} in archive", file_path))?;
+    let content = std::str::from_utf8(content)?;
+    if content.is_empty() {
+        return Ok(Vec::new());
+    }
+    let (_, data) =
+        deserialize_json_file(content).map_err(|e| format!("Invalid {}: {}", file_path, e))?;
+    Ok(data)
+}
+
+/// Read a DB file which older backups don't have, empty if missing.
+fn read_optional_db_file<T>(
+    files: &HashMap<String, Vec<u8>>,
+    file_path: &str,
+) -> Result<Vec<T>, Box<dyn std::error::Error>>
+where
+    T: for<'de> serde::Deserialize<'de> + SchemaVersioned,
+{
+    if !files.contains_key(file_path) {
+        return Ok(Vec::new());
+    }
+    read_db_file(files, file_path)
+}
+
+/**
+Replace all DBs and the AI prompts by the content of a backup.
+
+Waits for the running job to finish and keeps the others waiting during the restore. The current
+data is first saved as a backup in `BACKUP_DIR`. If a DB can't be saved, those already restored are
+rolled back.
+*/
+pub async fn restore_backup(
+    app_state: &AppState,
+    backup: Backup,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let _write_guard = app_state.job_manager.lock_writes().await;
+
+    let pre_restore_path = write_backup(app_state, PRE_RESTORE_SUFFIX)?;
+    info!(
+        "Restoring backup, current data saved in {}.",
+        pre_restore_path
+    );
+
+    // the replaced transactions and extras are recorded in the restored change log
+    let previous_transactions = app_state.transaction_db.data();
+    let previous_transaction_extras = app_state.transaction_extras_db.data();
+
+    let mut swaps: Vec<Box<dyn Swap>> = vec![
+        DbSwap::boxed(ACCOUNTS_DB_FILE, &app_state.account_db, backup.accounts),
+        DbSwap::boxed(
+            BALANCE_SNAPSHOTS_DB_FILE,
+            &app_state.balance_snapshot_db,
+            backup.balance_snapshots,
+        ),
+        DbSwap::boxed(CHANGES_DB_FILE, &app_state.change_db, backup.changes),
+        DbSwap::boxed(
+            EXPORT_BATCHES_DB_FILE,
+            &app_state.export_batch_db,
+            backup.export_batches,
+        ),
+        DbSwap::boxed(
+            INVESTMENTS_DB_FILE,
+            &app_state.investment_db,
+            backup.investments,
+        ),
+        DbSwap::boxed(
+            INVESTMENT_SYNC_STATES_DB_FILE,
+            &app_state.investment_sync_state_db,
+            backup.investment_sync_states,
+        ),
+        DbSwap::boxed(
+            MARKET_ORDERS_DB_FILE,
+            &app_state.market_order_db,
+            backup.market_orders,
+        ),
+        DbSwap::boxed(
+            RECONCILIATIONS_DB_FILE,
+            &app_state.reconciliation_db,
+            backup.reconciliations,
+        ),
+        DbSwap::boxed(SYNC_RUNS_DB_FILE, &app_state.sync_run_db, backup.sync_runs),
+        DbSwap::boxed(
+            SYNC_STATES_DB_FILE,
+            &app_state.sync_state_db,
+            backup.sync_states,
+        ),
+        DbSwap::boxed(
+            TRANSACTION_DB_FILE,
+            &app_state.transaction_db,
+            backup.transactions,
+        ),
+        DbSwap::boxed(
+            TRANSACTION_EXTRAS_DB_FILE,
+            &app_state.transaction_extras_db,
+            backup.transaction_extras,
+        ),
+    ];
+    for (file_path, content) in backup.ai_prompts {
+        swaps.push(Box::new(FileSwap {
+            file_path,
+            content,
+            previous: None,
+        }));
+    }
+
+    for index in 0..swaps.len() {
+        if let Err(e) = swaps[index].apply() {
+            let message = format!("Error restoring {}: {}", swaps[index].name(), e);
+            error!("{}, rolling back.", message);
+            for swap in swaps[..index].iter_mut().rev() {
+                if let Err(e) = swap.rollback() {
+                    error!("Error rolling back {}: {}", swap.name(), e);
+                }
+            }
+            return Err(message.into());
+        }
+    }
+
+    info!("Backup restored.");
+
+    // the restore is done even if it can't be recorded
+    let recorded = app_state
+        .change_db
+        .record_replaced(
+            &app_state.transaction_db,
+            previous_transactions,
+            ChangeSource::Restore,
+        )
+        .and_then(|transactions| {
+            Ok(transactions
+                + app_state.change_db.record_replaced(
+                    &app_state.transaction_extras_db,
+                    previous_transaction_extras,
+                    ChangeSource::Restore,
+                )?)
+        });
+    match recorded {
+        Ok(count) => info!("Recorded {} changes made by the restore.", count),
+        Err(e) => error!("Error recording the changes made by the restore: {:#?}", e),
+    }
+    Ok(())
+}
+
+/// Replacement of a DB or a file, which can be rolled back once applied.
+trait Swap: Send {
+    fn name(&self) -> &str;
+    fn apply(&mut self) -> Result<(), Box<dyn std::error::Error>>;
+    fn rollback(&mut self) -> Result<(), Box<dyn std::error::Error>>;
+}
+
+struct DbSwap<T>
+where
+    T: serde::Serialize + for<'de> serde::Deserialize<'de> + Clone,
+{
+    file_path: &'static str,
+    db: StructFileDb<T>,
+    data: Vec<T>,
+    previous: Vec<T>,
+}
+
+impl<T> DbSwap<T>
+where
+    T: serde::Serialize
+        + for<'de> serde::Deserialize<'de>
+        + Clone
+        + HasId
+        + Sortable
+        + SchemaVersioned
+        + PartialEq
+        + Send
+        + 'static,
+{
+    fn boxed(file_path: &'static str, db: &StructFileDb<T>, data: Vec<T>) -> Box<dyn Swap> {
+        Box::new(DbSwap {
+            file_path,
+            db: db.clone(),
+            data,
+            previous: Vec::new(),
+        })
+    }
+}
+
+impl<T> Swap for DbSwap<T>
+where
+    T: serde::Serialize
+        + for<'de> serde::Deserialize<'de>
+        + Clone
+        + HasId
+        + Sortable
+        + SchemaVersioned
+        + PartialEq
+        + Send,
+{
+    fn name(&self) -> &str {
+        self.file_path
+    }
+
+    fn apply(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.previous = self.db.data();
+        self.db.save(std::mem::take(&mut self.data))
+    }
+
+    fn rollback(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.db.save(std::mem::take(&mut self.previous))
+    }
+}
+
+struct FileSwap {
+    file_path: &'static str,
+    content: Vec<u8>,
+    /// Content before apply, None if the file did not exist.
+    previous: Option<Vec<u8>>,
+}
+
+impl Swap for FileSwap {
+    fn name(&self) -> &str {
+        self.file_path
+    }
+
+    fn apply(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.previous = fs::read(self.file_path).ok();
+        write_file(self.file_path, &self.content)
+    }
+
+    fn rollback(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        match &self.previous {
+            Some(previous) => write_file(self.file_path, previous),
+            None => Ok(fs::remove_file(self.file_path)?),
+        }
+    }
+}
+
+/// Write a file through a temporary file, so it is never partially written.
+fn write_file(file_path: &str, content: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(folder_path) = Path::new(file_path).parent()
+        && !folder_path.as_os_str().is_empty()
+    {
+        fs::create_dir_all(folder_path)?;
+    }
+    let tmp_path = format!("{}.tmp", file_path);
+    fs::write(&tmp_path, content)?;
+    fs::rename(&tmp_path, file_path)?;
+    Ok(())
+}
+
+/**
+Write a backup in `BACKUP_DIR`, then delete the oldest ones to keep only `BACKUP_KEEP` backups.
+
+Returns the path of the backup.
+*/
+pub fn run_scheduled_backup(app_state: &AppState) -> Result<String, Box<dyn std::error::Error>> {
+    let file_path = write_backup(app_state, "")?;
+    info!("Scheduled backup saved in {}.", file_path);
+
+    let keep = env_or_default("BACKUP_KEEP", 7usize)?;
+    rotate_backups(&backup_dir()?, keep)?;
+
+    Ok(file_path)
+}
+
+fn backup_dir() -> Result<String, Box<dyn std::error::Error>> {
+    env_or_default("BACKUP_DIR", "backups".to_string())
+}
+
+/// Write a backup in `BACKUP_DIR`, the suffix is added to the file name before the extension.
+fn write_backup(app_state: &AppState, suffix: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let archive = create_backup_with_suffix(app_state, suffix)?;
+    let file_path = Path::new(&backup_dir()?)
+        .join(archive.file_name)
+        .to_string_lossy()
+        .to_string();
+    write_file(&file_path, &archive.content)?;
+    Ok(file_path)
+}
+
+/**
+Delete the oldest scheduled backups of the folder, backup file names are sorted by creation datetime.
+
+The backups made before a restore are kept, they are the only copy of the replaced data.
+*/
+fn rotate_backups(backup_dir: &str, keep: usize) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file_names: Vec<String> = fs::read_dir(backup_dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .filter(|name| is_scheduled_backup(name))
+        .collect();
+    file_names.sort();
+
+    let count = file_names.len().saturating_sub(keep);
+    for file_name in &file_names[..count] {
+        fs::remove_file(Path::new(backup_dir).join(file_name))?;
+        info!("Deleted old backup {}.", file_name);
+    }
+    Ok(())
+}
+
+fn is_scheduled_backup(file_name: &str) -> bool {
+    let Some(name) = file_name.strip_prefix(BACKUP_FILE_PREFIX) else {
+        return false;
+    };
+    let name = name.strip_suffix(ENCRYPTED_FILE_EXTENSION).unwrap_or(name);
+    name.strip_suffix(BACKUP_FILE_EXTENSION)
+        .is_some_and(|name| !name.ends_with(PRE_RESTORE_SUFFIX))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pre_restore_backups_are_not_scheduled_backups() {
+        assert!(is_scheduled_backup("backup-20250101-030000.tar.gz"));
+        assert!(is_scheduled_backup("backup-20250101-030000.tar.gz.enc"));
+        assert!(!is_scheduled_backup(
+            "backup-20250101-030000-pre-restore.tar.gz"
+        ));
+        assert!(!is_scheduled_backup(
+            "backup-20250101-030000-pre-restore.tar.gz.enc"
+        ));
+        assert!(!is_scheduled_backup("backup-20250101-030000.tar.gz.tmp"));
+        assert!(!is_scheduled_backup("notes.tar.gz"));
+    }
+
+    #[test]
+    fn rotation_keeps_the_newest_and_the_pre_restore_backups() {
+        let dir = std::env::temp_dir().join(format!(
+            "powens-maybe-finance-connector-backups-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let file_names = [
+            "backup-20250101-030000.tar.gz",
+            "backup-20250101-120000-pre-restore.tar.gz",
+            "backup-20250102-030000.tar.gz.enc",
+            "backup-20250103-030000.tar.gz",
+        ];
+        for file_name in file_names {
+            fs::write(dir.join(file_name), b"").unwrap();
+        }
+
+        rotate_backups(&dir.to_string_lossy(), 2).unwrap();
+
+        let mut remaining: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|it| it.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        remaining.sort();
+        assert_eq!(remaining, file_names[1..]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
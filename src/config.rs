@@ -0,0 +1,13 @@
+//! Helpers to read the configuration from env vars
+
+/// Read and parse an env var, or use the default value if it is not set.
+pub fn env_or_default<T>(key: &str, default: T) -> Result<T, Box<dyn std::error::Error>>
+where
+    T: std::str::FromStr,
+    T::Err: std::error::Error + 'static,
+{
+    match dotenv::var(key) {
+        Ok(value) => Ok(value.parse::<T>()?),
+        Err(_) => Ok(default),
+    }
+}
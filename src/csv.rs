@@ -1,16 +1,23 @@
 mod account;
+mod balance;
+mod holding;
+mod trade;
 mod transaction;
 
 pub use account::*;
+pub use balance::*;
+pub use holding::*;
+pub use trade::*;
 pub use transaction::*;
 
 trait ToCsv {
     fn header_row() -> &'static str;
     fn to_csv_row(&self) -> String;
 
+    /// Quote a value containing a separator, a quote or a line break, inner quotes are doubled.
     fn format_csv_value(s: &str) -> String {
-        if s.contains(',') {
-            return format!("\"{}\"", s)
+        if s.contains([',', '"', '\n', '\r']) {
+            return format!("\"{}\"", s.replace('"', "\"\""))
         }
 
         s.to_string()
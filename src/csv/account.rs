@@ -1,4 +1,5 @@
 use crate::csv::ToCsv;
+use crate::currency::CurrencyConverter;
 use crate::powens::Account;
 
 #[derive(Debug, Clone, PartialEq)]
@@ -22,6 +23,19 @@ impl From<&Account> for AccountCsv {
     }
 }
 
+impl AccountCsv {
+    /// Convert the balance to the base currency, returns false if the currency has no rate.
+    pub fn convert(&mut self, converter: &CurrencyConverter) -> bool {
+        let Some(balance) = converter.convert(self.balance, &self.currency) else {
+            return false;
+        };
+
+        self.balance = balance;
+        self.currency = converter.base.clone();
+        true
+    }
+}
+
 impl ToCsv for AccountCsv {
     fn header_row() -> &'static str {
         "Entity type,Name,Balance,Currency"
@@ -0,0 +1,53 @@
+use crate::csv::ToCsv;
+use crate::db::BalanceSnapshot;
+use crate::powens::Account;
+
+/**
+A balance of an account on a day, in Maybe's account valuations import format.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct BalanceCsv {
+    pub id: u64,
+    pub date: String,
+    pub account: String,
+    pub balance: f64,
+    pub currency: String,
+}
+
+impl From<&BalanceSnapshot> for BalanceCsv {
+    fn from(snapshot: &BalanceSnapshot) -> Self {
+        BalanceCsv {
+            id: snapshot.id,
+            date: snapshot.date.clone(),
+            account: String::new(),
+            balance: snapshot.balance,
+            currency: snapshot.currency.clone(),
+        }
+    }
+}
+
+impl BalanceCsv {
+    pub fn set_account(&mut self, account: &Account) {
+        self.account = account.name.clone();
+    }
+}
+
+impl ToCsv for BalanceCsv {
+    fn header_row() -> &'static str {
+        "date,account,balance,currency"
+    }
+
+    fn to_csv_row(&self) -> String {
+        let BalanceCsv {
+            date,
+            account,
+            balance,
+            currency,
+            ..
+        } = self;
+
+        let account = Self::format_csv_value(account);
+
+        format!("{date},{account},{balance:.2},{currency}")
+    }
+}
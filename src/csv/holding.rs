@@ -0,0 +1,116 @@
+use crate::csv::ToCsv;
+use crate::powens::{Account, Investment};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HoldingCsv {
+    pub id: u64,
+    pub date: String,
+    pub account: String,
+    pub name: String,
+    pub isin: String,
+    pub ticker: String,
+    pub quantity: f64,
+    pub unit_price: Option<f64>,
+    pub unit_value: Option<f64>,
+    pub valuation: f64,
+    pub currency: String,
+}
+
+impl From<&Investment> for HoldingCsv {
+    fn from(inv: &Investment) -> Self {
+        // keep only the date part of the datetime
+        let date = inv
+            .vdate
+            .clone()
+            .or(inv.last_update.clone())
+            .unwrap_or_default()
+            .chars()
+            .take(10)
+            .collect();
+
+        let isin = if inv.code_type.as_deref().unwrap_or("ISIN") == "ISIN" {
+            inv.code.clone().unwrap_or_default()
+        } else {
+            String::new()
+        };
+
+        HoldingCsv {
+            id: inv.id,
+            date,
+            account: String::new(),
+            name: inv.label.clone(),
+            isin,
+            ticker: inv.stock_symbol.clone().unwrap_or_default(),
+            quantity: inv.quantity,
+            unit_price: inv.unitprice,
+            unit_value: inv.unitvalue,
+            valuation: inv.valuation,
+            currency: String::new(),
+        }
+    }
+}
+
+impl HoldingCsv {
+    pub fn set_account(&mut self, account: &Account) {
+        self.account = account.name.clone();
+        self.currency = account.currency.id.clone();
+    }
+}
+
+impl ToCsv for HoldingCsv {
+    fn header_row() -> &'static str {
+        "date,account,name,isin,ticker,quantity,unit_price,unit_value,valuation,currency"
+    }
+
+    fn to_csv_row(&self) -> String {
+        let HoldingCsv {
+            date,
+            account,
+            name,
+            isin,
+            ticker,
+            quantity,
+            unit_price,
+            unit_value,
+            valuation,
+            currency,
+            ..
+        } = self;
+
+        let account = Self::format_csv_value(account);
+        let name = Self::format_csv_value(name);
+        let isin = Self::format_csv_value(isin);
+        let ticker = Self::format_csv_value(ticker);
+        let unit_price = unit_price.map(|it| it.to_string()).unwrap_or_default();
+        let unit_value = unit_value.map(|it| it.to_string()).unwrap_or_default();
+
+        format!("{date},{account},{name},{isin},{ticker},{quantity},{unit_price},{unit_value},{valuation:.2},{currency}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_values_are_escaped() {
+        let holding = HoldingCsv {
+            id: 1,
+            date: "2026-10-01".to_string(),
+            account: "PEA".to_string(),
+            name: "World, \"ESG\"".to_string(),
+            isin: "FR0011869353".to_string(),
+            ticker: "EWLD,PA".to_string(),
+            quantity: 2.0,
+            unit_price: Some(20.5),
+            unit_value: None,
+            valuation: 41.0,
+            currency: "EUR".to_string(),
+        };
+
+        assert_eq!(
+            holding.to_csv_row(),
+            "2026-10-01,PEA,\"World, \"\"ESG\"\"\",FR0011869353,\"EWLD,PA\",2,20.5,,41.00,EUR"
+        );
+    }
+}
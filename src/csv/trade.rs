@@ -0,0 +1,99 @@
+use crate::csv::ToCsv;
+use crate::powens::{Account, Investment, MarketOrder};
+
+/**
+A trade, in Maybe's trades import format.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct TradeCsv {
+    pub id: u64,
+    pub date: String,
+    pub ticker: String,
+    pub exchange_operating_mic: String,
+    pub currency: String,
+    /// Negative when selling.
+    pub qty: f64,
+    pub price: f64,
+    pub account: String,
+    pub name: String,
+}
+
+impl From<&MarketOrder> for TradeCsv {
+    fn from(order: &MarketOrder) -> Self {
+        // keep only the date part of the datetime
+        let date = order
+            .exec_date
+            .clone()
+            .or(order.date.clone())
+            .unwrap_or_default()
+            .chars()
+            .take(10)
+            .collect();
+
+        let quantity = order.quantity.unwrap_or_default().abs();
+
+        TradeCsv {
+            id: order.id,
+            date,
+            ticker: order
+                .stock_symbol
+                .clone()
+                .or(order.code.clone())
+                .unwrap_or_default(),
+            exchange_operating_mic: String::new(),
+            currency: String::new(),
+            qty: if order.is_sell() { -quantity } else { quantity },
+            price: order.unitprice.or(order.unitvalue).unwrap_or_default(),
+            account: String::new(),
+            name: order.label.clone().unwrap_or_default(),
+        }
+    }
+}
+
+impl TradeCsv {
+    pub fn set_account(&mut self, account: &Account) {
+        self.account = account.name.clone();
+        self.currency = account.currency.id.clone();
+    }
+
+    /// Complete missing ticker and name from the investment of the same security.
+    pub fn set_investment(&mut self, investment: &Investment) {
+        if self.ticker.is_empty() {
+            self.ticker = investment
+                .stock_symbol
+                .clone()
+                .or(investment.code.clone())
+                .unwrap_or_default();
+        }
+
+        if self.name.is_empty() {
+            self.name = investment.label.clone();
+        }
+    }
+}
+
+impl ToCsv for TradeCsv {
+    fn header_row() -> &'static str {
+        "date,ticker,exchange_operating_mic,currency,qty,price,account,name"
+    }
+
+    fn to_csv_row(&self) -> String {
+        let TradeCsv {
+            date,
+            ticker,
+            exchange_operating_mic,
+            currency,
+            qty,
+            price,
+            account,
+            name,
+            ..
+        } = self;
+
+        let ticker = Self::format_csv_value(ticker);
+        let account = Self::format_csv_value(account);
+        let name = Self::format_csv_value(name);
+
+        format!("{date},{ticker},{exchange_operating_mic},{currency},{qty},{price},{account},{name}")
+    }
+}
@@ -1,4 +1,5 @@
 use crate::csv::ToCsv;
+use crate::currency::CurrencyConverter;
 use crate::db::TransactionExtras;
 use crate::powens::{Account, Transaction};
 
@@ -8,6 +9,7 @@ pub struct TransactionCsv {
     pub date: String,
     pub amount: f64,
     pub name: String,
+    pub currency: String,
     pub category: String,
     pub tags: String,
     pub account: String,
@@ -21,10 +23,16 @@ impl From<&Transaction> for TransactionCsv {
             date: t.date.clone(),
             amount: t.value,
             name: t.wording.clone(),
+            currency: String::new(),
             category: String::new(),
             tags: String::new(),
             account: String::new(),
-            notes: String::new(),
+            notes: match (t.original_value, &t.original_currency) {
+                (Some(value), Some(currency)) => {
+                    format!("Original amount: {value:.2} {}", currency.id)
+                }
+                _ => String::new(),
+            },
         }
     }
 }
@@ -32,6 +40,28 @@ impl From<&Transaction> for TransactionCsv {
 impl TransactionCsv {
     pub fn set_account(&mut self, account: &Account) {
         self.account = account.name.clone();
+        self.currency = account.currency.id.clone();
+    }
+
+    /// Convert the amount to the base currency, returns false if the currency has no rate.
+    pub fn convert(&mut self, converter: &CurrencyConverter) -> bool {
+        if self.currency == converter.base {
+            return true;
+        }
+
+        let Some(amount) = converter.convert(self.amount, &self.currency) else {
+            return false;
+        };
+
+        let note = format!("Converted from {:.2} {}", self.amount, self.currency);
+        self.notes = if self.notes.is_empty() {
+            note
+        } else {
+            format!("{}. {note}", self.notes)
+        };
+        self.amount = amount;
+        self.currency = converter.base.clone();
+        true
     }
     
     pub fn set_extras(&mut self, extras: &TransactionExtras) {
@@ -47,7 +77,7 @@ impl TransactionCsv {
 
 impl ToCsv for TransactionCsv {
     fn header_row() -> &'static str {
-        "date,amount,name,category,tags,account,notes"
+        "date,amount,name,currency,category,tags,account,notes"
     }
 
     fn to_csv_row(&self) -> String {
@@ -55,6 +85,7 @@ impl ToCsv for TransactionCsv {
             date,
             amount,
             name,
+            currency,
             category,
             tags,
             account,
@@ -63,8 +94,36 @@ impl ToCsv for TransactionCsv {
         } = self;
 
         let name = Self::format_csv_value(name);
+        let category = Self::format_csv_value(category);
+        let tags = Self::format_csv_value(tags);
+        let account = Self::format_csv_value(account);
         let notes = Self::format_csv_value(notes);
 
-        format!("{date},{amount:.2},{name},{category},{tags},{account},{notes}")
+        format!("{date},{amount:.2},{name},{currency},{category},{tags},{account},{notes}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_values_are_escaped() {
+        let transaction = TransactionCsv {
+            id: 1,
+            date: "2026-10-01".to_string(),
+            amount: -12.5,
+            name: "CB \"SHOP\", PARIS".to_string(),
+            currency: "EUR".to_string(),
+            category: "Food, Drinks".to_string(),
+            tags: "a,b|c".to_string(),
+            account: "Joint \"Main\"".to_string(),
+            notes: String::new(),
+        };
+
+        assert_eq!(
+            transaction.to_csv_row(),
+            "2026-10-01,-12.50,\"CB \"\"SHOP\"\", PARIS\",EUR,\"Food, Drinks\",\"a,b|c\",\"Joint \"\"Main\"\"\","
+        );
     }
 }
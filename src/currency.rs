@@ -0,0 +1,52 @@
+/*!
+Conversion of amounts to a base currency, using rates from a local file.
+
+The rates file is a JSON object giving the value of one unit of each currency in the base currency,
+ex: with `BASE_CURRENCY=EUR`, `{"USD": 0.92, "GBP": 1.17}`.
+*/
+
+use std::collections::HashMap;
+use std::fs;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CurrencyConverter {
+    /// ex: EUR
+    pub base: String,
+    /// Value of one unit of a currency in the base currency.
+    pub rates: HashMap<String, f64>,
+}
+
+impl CurrencyConverter {
+    /// Load the converter from `BASE_CURRENCY` and the rates file `CURRENCY_RATES_FILE`.
+    ///
+    /// Returns None if `BASE_CURRENCY` is not set. The file is read on each call, so that rates can be updated
+    /// without restarting.
+    pub fn from_env() -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        let base = dotenv::var("BASE_CURRENCY").unwrap_or_default();
+        if base.is_empty() {
+            return Ok(None);
+        }
+
+        let rates_file = dotenv::var("CURRENCY_RATES_FILE")
+            .unwrap_or_else(|_| "currency-rates.json".to_string());
+        let content = fs::read_to_string(&rates_file)
+            .map_err(|e| format!("Failed to read currency rates file {rates_file}: {e}"))?;
+        let rates: HashMap<String, f64> = serde_json::from_str(&content)?;
+
+        Ok(Some(CurrencyConverter { base, rates }))
+    }
+
+    /// Same as `from_env`, but fails if `BASE_CURRENCY` is not set.
+    pub fn required_from_env() -> Result<Self, Box<dyn std::error::Error>> {
+        Self::from_env()?.ok_or_else(|| "BASE_CURRENCY is not configured".into())
+    }
+
+    /// Convert an amount to the base currency, None if the rate of the currency is unknown.
+    pub fn convert(&self, amount: f64, currency: &str) -> Option<f64> {
+        if currency == self.base {
+            return Some(amount);
+        }
+
+        self.rates.get(currency).map(|rate| amount * rate)
+    }
+}
@@ -1,4 +1,15 @@
 mod db_base;
+mod db_encryption;
+mod db_storage;
 mod db_structs;
 
-pub use self::db_structs::*;
\ No newline at end of file
+pub use self::db_base::{Batch, IndexRange, QueryOptions, QueryPage, StructFileDb, UpsertOutcome};
+pub use self::db_encryption::{
+    Cipher, Encryptable, decrypt_if_encrypted, encrypt_if_enabled, is_key_file_used,
+    rotate_encryption_key,
+};
+pub use self::db_storage::{
+    JsonFileStorage, SchemaVersioned, SqliteStorage, StorageBackend, StorageBackendKind,
+    deserialize_json_file, serialize_json_file, sqlite_path,
+};
+pub use self::db_structs::*;
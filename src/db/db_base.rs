@@ -1,12 +1,14 @@
 //! Base implementation of a File Database for Struct
 
+use super::db_encryption::{Cipher, Encryptable};
+use super::db_storage::{SchemaVersioned, StorageBackend, StorageBackendKind, open_storage};
 use crate::powens::{HasId, Sortable};
-use serde_json;
-use std::fs;
-use std::fs::File;
-use std::io::{Read, Write};
-use std::sync::{Arc, Mutex, MutexGuard};
-use tracing::{debug, info};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::{Bound, RangeBounds};
+use std::sync::{Arc, Mutex};
+use tracing::debug;
 
 #[derive(Clone)]
 pub struct StructFileDb<T>
@@ -20,146 +22,511 @@ impl<T> StructFileDb<T>
 where
     T: serde::Serialize + for<'de> serde::Deserialize<'de> + Clone,
 {
-    pub fn new(file_path: String) -> Result<Self, Box<dyn std::error::Error>> {
+    pub fn data(&self) -> Vec<T> {
+        let mutex = self.db.lock().unwrap();
+        mutex.data.clone()
+    }
+
+    pub fn is_data_empty(&self) -> bool {
+        let mutex = self.db.lock().unwrap();
+        mutex.data.is_empty()
+    }
+}
+
+/**
+Sorting and pagination of [`StructFileDb::query`].
+*/
+#[derive(Debug, Clone, Default)]
+pub struct QueryOptions {
+    pub descending: bool,
+    /// Next cursor of the previous page, to get the next page.
+    pub cursor: Option<String>,
+    /// Size of a page, all matching data if not set.
+    pub limit: Option<usize>,
+    /// Only scan the data in this range of a secondary index, all data if not set.
+    pub index_range: Option<IndexRange>,
+}
+
+/**
+Range of keys of a secondary index, bounds included.
+*/
+#[derive(Debug, Clone)]
+pub struct IndexRange {
+    pub name: &'static str,
+    pub min: Option<String>,
+    pub max: Option<String>,
+}
+
+impl IndexRange {
+    fn bounds(&self) -> Option<(Bound<String>, Bound<String>)> {
+        if let (Some(min), Some(max)) = (&self.min, &self.max)
+            && min > max
+        {
+            return None;
+        }
+        let bound = |it: &Option<String>| match it {
+            Some(it) => Bound::Included(it.clone()),
+            None => Bound::Unbounded,
+        };
+        Some((bound(&self.min), bound(&self.max)))
+    }
+}
+
+/**
+A page of the data matching a query.
+*/
+#[derive(Debug, Clone)]
+pub struct QueryPage<T> {
+    pub data: Vec<T>,
+    /// Number of data matching the query, in all pages.
+    pub total: usize,
+    /// Cursor to get the next page, None on the last page.
+    pub next_cursor: Option<String>,
+}
+
+/// Cursor of the data having the given sort key and id, opaque for the clients.
+fn encode_cursor<K: serde::Serialize>(
+    key: &K,
+    id: u64,
+) -> Result<String, Box<dyn std::error::Error>> {
+    Ok(URL_SAFE_NO_PAD.encode(serde_json::to_string(&(key, id))?))
+}
+
+fn decode_cursor<K: for<'de> serde::Deserialize<'de>>(
+    cursor: &str,
+) -> Result<(K, u64), Box<dyn std::error::Error>> {
+    let json = URL_SAFE_NO_PAD
+        .decode(cursor)
+        .map_err(|_| "Invalid cursor")?;
+    serde_json::from_slice(&json).map_err(|_| "Invalid cursor".into())
+}
+
+/**
+Result of an upsert.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpsertOutcome {
+    Inserted,
+    Updated,
+    /// The data is equal to the existing one, nothing is saved.
+    Unchanged,
+}
+
+impl<T> StructFileDb<T>
+where
+    T: serde::Serialize
+        + for<'de> serde::Deserialize<'de>
+        + Clone
+        + HasId
+        + Sortable
+        + SchemaVersioned
+        + PartialEq,
+{
+    /// Load the DB from the backend chosen by `DB_BACKEND`, with `file_path` as JSON file, ex:
+    /// `db/accounts.json`.
+    pub fn new(file_path: String) -> Result<Self, Box<dyn std::error::Error>>
+    where
+        T: 'static,
+    {
+        let storage = open_storage(StorageBackendKind::from_env()?, &file_path)?;
+        Self::with_storage(storage)
+    }
+
+    pub fn with_storage(
+        storage: Box<dyn StorageBackend<T>>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut base = BaseStructFileDb::<T> {
+            storage,
+            data: Vec::new(),
+            ids: HashMap::new(),
+            indexes: Vec::new(),
+        };
+        base.reload()?;
+        Self::sort(&mut base.data);
+        base.rebuild_indexes();
+
         Ok(StructFileDb::<T> {
-            db: Arc::new(Mutex::new(BaseStructFileDb::<T>::new(file_path)?)),
+            db: Arc::new(Mutex::new(base)),
         })
     }
 
+    /**
+    Add a secondary index, built from the key of each data, ex: transactions by account.
+
+    Query it with [`StructFileDb::find_by_index`] and [`StructFileDb::find_by_index_range`].
+    */
+    pub fn with_index(self, name: &'static str, key: fn(&T) -> String) -> Self {
+        {
+            let mut mutex = self.db.lock().unwrap();
+            mutex.indexes.push(SecondaryIndex {
+                name,
+                key,
+                positions: BTreeMap::new(),
+            });
+            mutex.rebuild_indexes();
+        }
+        self
+    }
+
     pub fn save(&self, data: Vec<T>) -> Result<(), Box<dyn std::error::Error>> {
         let mut mutex = self.db.lock().unwrap();
         mutex.data = data;
+        mutex.rebuild_indexes();
         mutex.save()
     }
 
     pub fn reload(&self) -> Result<(), Box<dyn std::error::Error>> {
         let mut mutex = self.db.lock().unwrap();
-        mutex.reload()
+        mutex.reload()?;
+        Self::sort(&mut mutex.data);
+        mutex.rebuild_indexes();
+        Ok(())
     }
 
-    pub fn data(&self) -> Vec<T> {
-        let mutex = self.db.lock().unwrap();
-        mutex.data.clone()
+    fn sort(data: &mut [T]) {
+        data.sort_by(|a, b| a.sortable_value().cmp(&b.sortable_value()));
     }
 
-    pub fn is_data_empty(&self) -> bool {
+    pub fn find_by_id(&self, id: u64) -> Option<T> {
         let mutex = self.db.lock().unwrap();
-        mutex.data.is_empty()
+        mutex.ids.get(&id).map(|index| mutex.data[*index].clone())
     }
-}
 
-impl<T> StructFileDb<T>
-where
-    T: serde::Serialize + for<'de> serde::Deserialize<'de> + Clone + HasId + Sortable,
-{
-    fn sort_and_save(
-        &self,
-        mutex: &mut MutexGuard<BaseStructFileDb<T>>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    /// Data having the given key in a secondary index, in the DB order.
+    pub fn find_by_index(&self, name: &str, key: &str) -> Vec<T> {
+        let mutex = self.db.lock().unwrap();
         mutex
-            .data
-            .sort_by(|a, b| a.sortable_value().cmp(&b.sortable_value()));
-        mutex.save()
+            .index(name)
+            .positions
+            .get(key)
+            .map(|positions| positions.iter().map(|it| mutex.data[*it].clone()).collect())
+            .unwrap_or_default()
     }
 
-    pub fn find_by_id(&self, id: u64) -> Option<T> {
+    /// Data which key in a secondary index is in the given range, sorted by key then DB order.
+    pub fn find_by_index_range(&self, name: &str, range: impl RangeBounds<String>) -> Vec<T> {
         let mutex = self.db.lock().unwrap();
-        mutex.data.iter().find(|x| x.id() == id).cloned()
+        mutex
+            .index(name)
+            .positions
+            .range(range)
+            .flat_map(|(_, positions)| positions.iter().map(|it| mutex.data[*it].clone()))
+            .collect()
     }
 
-    pub fn delete_by_id(&self, id: u64) -> Result<(), Box<dyn std::error::Error>> {
-        let mut mutex = self.db.lock().unwrap();
-        mutex.data.retain(|x| x.id() != id);
-        self.sort_and_save(&mut mutex)
+    /**
+    Data matching the filter, sorted by the sort key then id, and paginated with a cursor.
+
+    Only the data in the index range of the options is scanned, if set. Only the data of the
+    returned page is cloned.
+    */
+    pub fn query<K>(
+        &self,
+        filter: impl Fn(&T) -> bool,
+        sort_key: impl Fn(&T) -> K,
+        options: &QueryOptions,
+    ) -> Result<QueryPage<T>, Box<dyn std::error::Error>>
+    where
+        K: Ord + serde::Serialize + for<'de> serde::Deserialize<'de>,
+    {
+        let after: Option<(K, u64)> = match &options.cursor {
+            Some(cursor) => Some(decode_cursor(cursor)?),
+            None => None,
+        };
+
+        let mutex = self.db.lock().unwrap();
+        let candidates: Box<dyn Iterator<Item = &T>> = match &options.index_range {
+            Some(index_range) => match index_range.bounds() {
+                Some(bounds) => Box::new(
+                    mutex
+                        .index(index_range.name)
+                        .positions
+                        .range(bounds)
+                        .flat_map(|(_, positions)| positions.iter().map(|it| &mutex.data[*it])),
+                ),
+                None => Box::new(std::iter::empty()),
+            },
+            None => Box::new(mutex.data.iter()),
+        };
+        let mut matches: Vec<(K, u64, &T)> = candidates
+            .filter(|x| filter(x))
+            .map(|x| (sort_key(x), x.id(), x))
+            .collect();
+        let total = matches.len();
+
+        matches.sort_by(|a, b| (&a.0, a.1).cmp(&(&b.0, b.1)));
+        if options.descending {
+            matches.reverse();
+        }
+
+        // the page starts after the last data of the previous page
+        let start = match &after {
+            Some((key, id)) => matches
+                .iter()
+                .position(|x| {
+                    let ordering = (&x.0, x.1).cmp(&(key, *id));
+                    if options.descending {
+                        ordering.is_lt()
+                    } else {
+                        ordering.is_gt()
+                    }
+                })
+                .unwrap_or(matches.len()),
+            None => 0,
+        };
+        let end = match options.limit {
+            Some(limit) => (start + limit).min(matches.len()),
+            None => matches.len(),
+        };
+
+        let next_cursor = if end < matches.len() && end > start {
+            let (key, id, _) = &matches[end - 1];
+            Some(encode_cursor(key, *id)?)
+        } else {
+            None
+        };
+
+        Ok(QueryPage {
+            data: matches[start..end].iter().map(|x| x.2.clone()).collect(),
+            total,
+            next_cursor,
+        })
     }
 
-    pub fn upsert(&self, data: T) -> Result<(), Box<dyn std::error::Error>> {
+    /**
+    Apply many changes under one lock, then sort once and persist all changes at once.
+
+    The changes are persisted only if at least one data has changed. If they can't be persisted, the
+    data is left as it was.
+    */
+    pub fn batch<R>(
+        &self,
+        f: impl FnOnce(&mut Batch<T>) -> R,
+    ) -> Result<R, Box<dyn std::error::Error>> {
         let mut mutex = self.db.lock().unwrap();
-        let index = mutex.data.iter().position(|x| x.id() == data.id());
-        if let Some(index) = index {
-            debug!(
-                "Update {} with id {}", 
-                std::any::type_name::<T>(), 
-                &data.id()
-            );
-            mutex.data[index] = data;
-        } else {
-            debug!(
-                "Insert {} with id {}", 
-                std::any::type_name::<T>(), 
-                &data.id());
-            mutex.data.push(data);
+        let base = &mut *mutex;
+
+        let mut batch = Batch {
+            base,
+            upserted: HashSet::new(),
+            deleted: HashSet::new(),
+            originals: HashMap::new(),
+        };
+        let result = f(&mut batch);
+        let Batch {
+            base,
+            upserted,
+            deleted,
+            originals,
+        } = batch;
+        if upserted.is_empty() && deleted.is_empty() {
+            return Ok(result);
+        }
+
+        Self::sort(&mut base.data);
+        base.rebuild_indexes();
+        let upserted: Vec<&T> = base
+            .data
+            .iter()
+            .filter(|x| upserted.contains(&x.id()))
+            .collect();
+        let deleted: Vec<u64> = deleted.into_iter().collect();
+        if let Err(e) = base.storage.write_changes(&base.data, &upserted, &deleted) {
+            // the data in memory stays as it is stored
+            base.data.retain(|x| !originals.contains_key(&x.id()));
+            base.data.extend(originals.into_values().flatten());
+            Self::sort(&mut base.data);
+            base.rebuild_indexes();
+            return Err(e);
         }
-        self.sort_and_save(&mut mutex)
+
+        Ok(result)
+    }
+
+    pub fn delete_by_id(&self, id: u64) -> Result<(), Box<dyn std::error::Error>> {
+        self.batch(|batch| batch.delete_by_id(id))
+    }
+
+    /// Insert a new data built with the next available id, which is the greatest id + 1.
+    pub fn insert_with_next_id(
+        &self,
+        build: impl FnOnce(u64) -> T,
+    ) -> Result<T, Box<dyn std::error::Error>> {
+        self.batch(|batch| {
+            let data = build(batch.next_id());
+            batch.upsert(data.clone());
+            data
+        })
+    }
+
+    pub fn upsert(&self, data: T) -> Result<UpsertOutcome, Box<dyn std::error::Error>> {
+        self.batch(|batch| batch.upsert(data))
+    }
+
+    /// Upsert all the given data with a single write, returns the outcome of each data.
+    pub fn upsert_many(
+        &self,
+        data: impl IntoIterator<Item = T>,
+    ) -> Result<Vec<UpsertOutcome>, Box<dyn std::error::Error>> {
+        self.batch(|batch| data.into_iter().map(|x| batch.upsert(x)).collect())
     }
 }
 
-struct BaseStructFileDb<T: serde::Serialize + for<'de> serde::Deserialize<'de>> {
-    file_path: String,
-    data: Vec<T>,
+impl<T> Encryptable for StructFileDb<T>
+where
+    T: serde::Serialize + for<'de> serde::Deserialize<'de> + Clone,
+{
+    /// Rewrite all the data with the new cipher.
+    fn set_cipher(&self, cipher: Option<Cipher>) -> Result<(), Box<dyn std::error::Error>> {
+        let mut mutex = self.db.lock().unwrap();
+        let base = &mut *mutex;
+        base.storage.set_cipher(cipher);
+        base.storage.write_all(&base.data)
+    }
 }
 
-impl<T: serde::Serialize + for<'de> serde::Deserialize<'de>> BaseStructFileDb<T> {
-    fn new(file_path: String) -> Result<Self, Box<dyn std::error::Error>> {
-        let mut content = String::new();
+/**
+Changes made to the data of a DB by [`StructFileDb::batch`].
+*/
+pub struct Batch<'a, T> {
+    base: &'a mut BaseStructFileDb<T>,
+    upserted: HashSet<u64>,
+    deleted: HashSet<u64>,
+    /// Data before the batch changed it, None if it did not exist, to undo a failed write.
+    originals: HashMap<u64, Option<T>>,
+}
 
-        if !fs::exists(&file_path)? {
-            // split and get folder, create folder if necessary
-            let folder_path = file_path.split("/").collect::<Vec<&str>>()
-                [..(file_path.split("/").count() - 1)]
-                .join("/");
-            if !folder_path.is_empty() && !fs::exists(&folder_path)? {
-                fs::create_dir_all(&folder_path)?;
-                info!("Created folder: {}", folder_path);
-            }
+impl<T> Batch<'_, T>
+where
+    T: Clone + HasId + PartialEq,
+{
+    pub fn find_by_id(&self, id: u64) -> Option<&T> {
+        self.base.ids.get(&id).map(|index| &self.base.data[*index])
+    }
 
-            File::create(&file_path)?;
-            info!("Created file: {}", file_path);
-        } else {
-            let mut file = File::open(&file_path)?;
-            file.read_to_string(&mut content)?;
-        } // file closed
+    /// The greatest id + 1.
+    pub fn next_id(&self) -> u64 {
+        self.base.ids.keys().max().unwrap_or(&0) + 1
+    }
 
-        let data: Vec<T> = if content.is_empty() {
-            Vec::new()
+    pub fn upsert(&mut self, data: T) -> UpsertOutcome {
+        let id = data.id();
+        let outcome = if let Some(index) = self.base.ids.get(&id).copied() {
+            if self.base.data[index] == data {
+                return UpsertOutcome::Unchanged;
+            }
+            self.keep_original(id);
+            debug!("Update {} with id {}", std::any::type_name::<T>(), id);
+            self.base.data[index] = data;
+            UpsertOutcome::Updated
         } else {
-            serde_json::from_str(&content)?
+            self.keep_original(id);
+            debug!("Insert {} with id {}", std::any::type_name::<T>(), id);
+            self.base.ids.insert(id, self.base.data.len());
+            self.base.data.push(data);
+            UpsertOutcome::Inserted
         };
+        self.deleted.remove(&id);
+        self.upserted.insert(id);
+        outcome
+    }
 
-        Ok(BaseStructFileDb::<T> { file_path, data })
+    pub fn delete_by_id(&mut self, id: u64) {
+        if self.base.ids.contains_key(&id) {
+            self.keep_original(id);
+            self.base.data.retain(|x| x.id() != id);
+            self.base.rebuild_ids();
+            self.upserted.remove(&id);
+            self.deleted.insert(id);
+        }
     }
 
-    fn save(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let content = serde_json::to_string_pretty(&self.data)?;
+    /// Delete the data for which `keep` returns false.
+    pub fn retain(&mut self, mut keep: impl FnMut(&T) -> bool) {
+        let deleted: HashSet<u64> = self
+            .base
+            .data
+            .iter()
+            .filter(|x| !keep(x))
+            .map(|x| x.id())
+            .collect();
+        if deleted.is_empty() {
+            return;
+        }
+        for id in deleted.iter() {
+            self.keep_original(*id);
+            self.upserted.remove(id);
+        }
+        self.base.data.retain(|x| !deleted.contains(&x.id()));
+        self.base.rebuild_ids();
+        self.deleted.extend(deleted);
+    }
+
+    /// Keep the data as it was before the first change of the batch.
+    fn keep_original(&mut self, id: u64) {
+        if !self.originals.contains_key(&id) {
+            let original = self.find_by_id(id).cloned();
+            self.originals.insert(id, original);
+        }
+    }
+}
 
-        let tmp_path = format!("{}.tmp", &self.file_path);
-        let mut file = File::create(&tmp_path)?; // this truncates the exiting file if any
-        file.write_all(content.as_bytes())?;
-        file.sync_all()?;
+/// Positions in the data of each key.
+struct SecondaryIndex<T> {
+    name: &'static str,
+    key: fn(&T) -> String,
+    positions: BTreeMap<String, Vec<usize>>,
+}
 
-        fs::rename(&tmp_path, &self.file_path)?; // this replaces the existing file
+struct BaseStructFileDb<T> {
+    storage: Box<dyn StorageBackend<T>>,
+    data: Vec<T>,
+    /// Position in the data of each id.
+    ids: HashMap<u64, usize>,
+    indexes: Vec<SecondaryIndex<T>>,
+}
 
-        info!("Saved file: {}", self.file_path);
+impl<T> BaseStructFileDb<T> {
+    fn save(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.storage.write_all(&self.data)
+    }
 
+    fn reload(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.data = self.storage.load()?;
         Ok(())
     }
 
-    fn reload(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        if !fs::exists(&self.file_path)? {
-            return if self.data.is_empty() {
-                Ok(())
-            } else {
-                Err(Box::from("File does not exist and data is not empty"))
-            };
-        }
+    fn index(&self, name: &str) -> &SecondaryIndex<T> {
+        self.indexes
+            .iter()
+            .find(|it| it.name == name)
+            .unwrap_or_else(|| panic!("Index {name} not found"))
+    }
+}
 
-        let content = fs::read_to_string(&self.file_path)?;
-        self.data = if content.is_empty() {
-            Vec::new()
-        } else {
-            serde_json::from_str(&content)?
-        };
+impl<T: HasId> BaseStructFileDb<T> {
+    fn rebuild_ids(&mut self) {
+        self.ids = self
+            .data
+            .iter()
+            .enumerate()
+            .map(|(index, x)| (x.id(), index))
+            .collect();
+    }
 
-        Ok(())
+    /// Rebuild all indexes, to be called after the data is changed.
+    fn rebuild_indexes(&mut self) {
+        self.rebuild_ids();
+        for index in self.indexes.iter_mut() {
+            index.positions.clear();
+            for (position, x) in self.data.iter().enumerate() {
+                index
+                    .positions
+                    .entry((index.key)(x))
+                    .or_default()
+                    .push(position);
+            }
+        }
     }
 }
@@ -0,0 +1,338 @@
+//! Encryption at rest of the Struct Databases
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use std::fs;
+use tracing::info;
+
+/// Start of encrypted content, followed by the nonce and the ciphertext.
+const MAGIC: &[u8] = b"PMFC-AES256GCM-1";
+const NONCE_SIZE: usize = 12;
+
+/**
+AES-256-GCM cipher of the data stored on disk.
+
+The key comes from `DB_ENCRYPTION_KEY`, or from the file `DB_ENCRYPTION_KEY_FILE`, as base64 of 32
+bytes, ex: generated by `openssl rand -base64 32`. A key file can have the previous keys on the
+next lines, or they can be in `DB_ENCRYPTION_PREVIOUS_KEYS` separated by commas, they are only used
+to decrypt.
+
+Each content is bound to a name, ex: the table name, so it can't be swapped with another one.
+*/
+#[derive(Clone)]
+pub struct Cipher {
+    /// The first key encrypts, all keys are tried to decrypt.
+    keys: Vec<Aes256Gcm>,
+}
+
+impl Cipher {
+    /// Read the keys from the env vars, None if encryption is not enabled.
+    pub fn from_env() -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        let keys = match (
+            dotenv::var("DB_ENCRYPTION_KEY")
+                .ok()
+                .filter(|it| !it.is_empty()),
+            key_file_path(),
+        ) {
+            (Some(_), Some(_)) => {
+                return Err("Set only one of DB_ENCRYPTION_KEY and DB_ENCRYPTION_KEY_FILE".into());
+            }
+            (Some(key), None) => {
+                let mut keys = vec![key];
+                keys.extend(previous_keys_from_env());
+                keys
+            }
+            (None, Some(key_file_path)) => read_key_file(&key_file_path)?,
+            (None, None) => return Ok(None),
+        };
+        if keys.is_empty() {
+            return Err("The encryption key file is empty".into());
+        }
+        Ok(Some(Cipher::new(&keys)?))
+    }
+
+    /// Create a cipher from base64 keys, the first one encrypts.
+    pub fn new(keys: &[String]) -> Result<Self, Box<dyn std::error::Error>> {
+        let keys = keys
+            .iter()
+            .map(|key| {
+                let bytes = STANDARD.decode(key.trim())?;
+                if bytes.len() != 32 {
+                    return Err("An encryption key must be 32 bytes".into());
+                }
+                Ok(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&bytes)))
+            })
+            .collect::<Result<Vec<_>, Box<dyn std::error::Error>>>()?;
+        Ok(Cipher { keys })
+    }
+
+    /// A new random key, as base64.
+    pub fn generate_key() -> String {
+        STANDARD.encode(Aes256Gcm::generate_key(OsRng))
+    }
+
+    pub fn encrypt(
+        &self,
+        content: &[u8],
+        name: &str,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self.keys[0]
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: content,
+                    aad: name.as_bytes(),
+                },
+            )
+            .map_err(|_| format!("Error encrypting {name}"))?;
+
+        let mut encrypted = Vec::with_capacity(MAGIC.len() + NONCE_SIZE + ciphertext.len());
+        encrypted.extend_from_slice(MAGIC);
+        encrypted.extend_from_slice(&nonce);
+        encrypted.extend_from_slice(&ciphertext);
+        Ok(encrypted)
+    }
+
+    pub fn decrypt(
+        &self,
+        content: &[u8],
+        name: &str,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        Ok(self.decrypt_with_key_index(content, name)?.0)
+    }
+
+    /// Decrypted content, with the index of the key which decrypted it, 0 for the current key.
+    fn decrypt_with_key_index(
+        &self,
+        content: &[u8],
+        name: &str,
+    ) -> Result<(Vec<u8>, usize), Box<dyn std::error::Error>> {
+        let content = content
+            .strip_prefix(MAGIC)
+            .filter(|it| it.len() >= NONCE_SIZE)
+            .ok_or(format!("{name} is not encrypted"))?;
+        let (nonce, ciphertext) = content.split_at(NONCE_SIZE);
+        self.keys
+            .iter()
+            .enumerate()
+            .find_map(|(index, key)| {
+                key.decrypt(
+                    Nonce::from_slice(nonce),
+                    Payload {
+                        msg: ciphertext,
+                        aad: name.as_bytes(),
+                    },
+                )
+                .ok()
+                .map(|content| (content, index))
+            })
+            .ok_or_else(|| format!("Error decrypting {name}, wrong key or corrupted data").into())
+    }
+}
+
+pub fn is_encrypted(content: &[u8]) -> bool {
+    content.starts_with(MAGIC)
+}
+
+/// Keys of `DB_ENCRYPTION_PREVIOUS_KEYS`, separated by commas.
+fn previous_keys_from_env() -> Vec<String> {
+    dotenv::var("DB_ENCRYPTION_PREVIOUS_KEYS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|it| it.trim().to_string())
+        .filter(|it| !it.is_empty())
+        .collect()
+}
+
+fn key_file_path() -> Option<String> {
+    dotenv::var("DB_ENCRYPTION_KEY_FILE")
+        .ok()
+        .filter(|it| !it.is_empty())
+}
+
+/// Encrypt the content if there is a cipher.
+pub fn encrypt_if_enabled(
+    cipher: Option<&Cipher>,
+    content: Vec<u8>,
+    name: &str,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    match cipher {
+        Some(cipher) => cipher.encrypt(&content, name),
+        None => Ok(content),
+    }
+}
+
+/// Decrypt the content if it is encrypted, plain content is read as is so encryption can be
+/// enabled on existing data.
+pub fn decrypt_if_encrypted(
+    cipher: Option<&Cipher>,
+    content: Vec<u8>,
+    name: &str,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if !is_encrypted(&content) {
+        return Ok(content);
+    }
+    match cipher {
+        Some(cipher) => cipher.decrypt(&content, name),
+        None => Err(format!(
+            "{name} is encrypted, set DB_ENCRYPTION_KEY or DB_ENCRYPTION_KEY_FILE"
+        )
+        .into()),
+    }
+}
+
+/**
+Decrypt the content if it is encrypted, like [`decrypt_if_encrypted`], with whether it must be
+rewritten: plain content while encryption is enabled, or content encrypted with a previous key.
+*/
+pub fn decrypt_and_check_key(
+    cipher: Option<&Cipher>,
+    content: Vec<u8>,
+    name: &str,
+) -> Result<(Vec<u8>, bool), Box<dyn std::error::Error>> {
+    match cipher {
+        Some(cipher) if is_encrypted(&content) => {
+            let (content, key_index) = cipher.decrypt_with_key_index(&content, name)?;
+            Ok((content, key_index > 0))
+        }
+        Some(_) => Ok((content, true)),
+        None => Ok((decrypt_if_encrypted(None, content, name)?, false)),
+    }
+}
+
+/// A DB which data can be rewritten with another cipher.
+pub trait Encryptable {
+    fn set_cipher(&self, cipher: Option<Cipher>) -> Result<(), Box<dyn std::error::Error>>;
+}
+
+/**
+Re-encrypt the DBs with a new random key, returns the new key.
+
+The new key is given to `on_new_key` before any DB is rewritten, so it can't be lost. With
+`DB_ENCRYPTION_KEY_FILE`, it is written first in the key file, before the previous keys which are
+kept to read the older backups. With `DB_ENCRYPTION_KEY`, the env var must be updated with the new
+key, and the previous key added to `DB_ENCRYPTION_PREVIOUS_KEYS`. Without a key, the DBs are
+encrypted for the first time.
+
+The previous keys stay usable until the rewrite is done: if it is interrupted, the DBs which are
+still encrypted with a previous key are rewritten when they are loaded.
+*/
+pub fn rotate_encryption_key(
+    dbs: &[&dyn Encryptable],
+    on_new_key: impl FnOnce(&str, &[String]),
+) -> Result<String, Box<dyn std::error::Error>> {
+    let previous_keys: Vec<String> = match key_file_path() {
+        Some(key_file_path) if fs::exists(&key_file_path)? => read_key_file(&key_file_path)?,
+        Some(_) => Vec::new(),
+        None => dotenv::var("DB_ENCRYPTION_KEY")
+            .ok()
+            .filter(|it| !it.is_empty())
+            .into_iter()
+            .chain(previous_keys_from_env())
+            .collect(),
+    };
+
+    let new_key = Cipher::generate_key();
+    let mut keys = vec![new_key.clone()];
+    keys.extend(previous_keys);
+    let cipher = Cipher::new(&keys)?;
+
+    if let Some(key_file_path) = key_file_path() {
+        write_key_file(&key_file_path, &keys)?;
+        info!("New encryption key written to {}.", key_file_path);
+    }
+    on_new_key(&new_key, &keys[1..]);
+
+    for db in dbs {
+        db.set_cipher(Some(cipher.clone()))?;
+    }
+    info!("Encryption key rotated.");
+
+    Ok(new_key)
+}
+
+/// Whether the encryption key is read from a file, which the key rotation can update.
+pub fn is_key_file_used() -> bool {
+    key_file_path().is_some()
+}
+
+/// Keys of a key file, one per line.
+fn read_key_file(key_file_path: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    Ok(fs::read_to_string(key_file_path)
+        .map_err(|e| format!("Error reading {}: {}", key_file_path, e))?
+        .lines()
+        .map(|it| it.trim().to_string())
+        .filter(|it| !it.is_empty())
+        .collect())
+}
+
+fn write_key_file(key_file_path: &str, keys: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_path = format!("{}.tmp", key_file_path);
+    fs::write(&tmp_path, keys.join("\n") + "\n")?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&tmp_path, fs::Permissions::from_mode(0o600))?;
+    }
+    fs::rename(&tmp_path, key_file_path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_is_bound_to_its_name() {
+        let cipher = Cipher::new(&[Cipher::generate_key()]).unwrap();
+        let encrypted = cipher.encrypt(b"data", "accounts").unwrap();
+        assert!(is_encrypted(&encrypted));
+        assert_eq!(cipher.decrypt(&encrypted, "accounts").unwrap(), b"data");
+        assert!(cipher.decrypt(&encrypted, "transactions").is_err());
+    }
+
+    #[test]
+    fn previous_keys_only_decrypt() {
+        let previous_key = Cipher::generate_key();
+        let previous = Cipher::new(std::slice::from_ref(&previous_key)).unwrap();
+        let rotated = Cipher::new(&[Cipher::generate_key(), previous_key]).unwrap();
+
+        let old_content = previous.encrypt(b"old", "accounts").unwrap();
+        assert_eq!(rotated.decrypt(&old_content, "accounts").unwrap(), b"old");
+        // new content can't be read with the previous key alone
+        let new_content = rotated.encrypt(b"new", "accounts").unwrap();
+        assert!(previous.decrypt(&new_content, "accounts").is_err());
+    }
+
+    #[test]
+    fn content_not_encrypted_with_current_key_must_be_rewritten() {
+        let previous_key = Cipher::generate_key();
+        let previous = Cipher::new(std::slice::from_ref(&previous_key)).unwrap();
+        let cipher = Cipher::new(&[Cipher::generate_key(), previous_key]).unwrap();
+
+        let current = cipher.encrypt(b"data", "accounts").unwrap();
+        let old = previous.encrypt(b"data", "accounts").unwrap();
+        for (content, must_be_rewritten) in
+            [(current, false), (old, true), (b"data".to_vec(), true)]
+        {
+            let (decrypted, rewrite) =
+                decrypt_and_check_key(Some(&cipher), content, "accounts").unwrap();
+            assert_eq!(decrypted, b"data");
+            assert_eq!(rewrite, must_be_rewritten);
+        }
+    }
+
+    #[test]
+    fn plain_content_is_read_without_cipher() {
+        let (content, rewrite) = decrypt_and_check_key(None, b"data".to_vec(), "accounts").unwrap();
+        assert_eq!(content, b"data");
+        assert!(!rewrite);
+
+        let cipher = Cipher::new(&[Cipher::generate_key()]).unwrap();
+        let encrypted = cipher.encrypt(b"data", "accounts").unwrap();
+        assert!(decrypt_and_check_key(None, encrypted, "accounts").is_err());
+    }
+}
@@ -0,0 +1,947 @@
+//! Storage backends of the Struct Databases
+
+use super::db_encryption::{Cipher, decrypt_and_check_key, encrypt_if_enabled, is_encrypted};
+use crate::config::env_or_default;
+use crate::powens::HasId;
+use chrono::Utc;
+use rusqlite::types::{Value, ValueRef};
+use rusqlite::{Connection, OptionalExtension, params};
+use std::fs;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+/// SQLite file used when `DB_BACKEND` is `sqlite`.
+const DEFAULT_SQLITE_PATH: &str = "db/connector.sqlite";
+/// Datetime in the names of the backups made before a migration, to keep the previous ones.
+const MIGRATION_BACKUP_DATETIME_FORMAT: &str = "%Y%m%d-%H%M%S";
+
+/**
+Version of the schema of stored data, with the migrations to upgrade data stored by older versions.
+
+Files written before versioning are version 0. When a struct changes in a way that old data can't
+be deserialized anymore, increase `SCHEMA_VERSION` and handle the previous version in `migrate`.
+*/
+pub trait SchemaVersioned {
+    const SCHEMA_VERSION: u32 = 1;
+
+    /// Upgrade a record stored with `from_version` to `from_version + 1`.
+    fn migrate(
+        _from_version: u32,
+        _record: &mut serde_json::Value,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+}
+
+/// Content of a JSON file: the data with the schema version.
+#[derive(serde::Serialize)]
+struct Envelope<'a, T> {
+    schema_version: u32,
+    data: &'a [T],
+}
+
+/// Upgrade records stored with `version` to the current version, then deserialize them.
+fn migrate_records<T>(
+    version: u32,
+    records: Vec<serde_json::Value>,
+) -> Result<Vec<T>, Box<dyn std::error::Error>>
+where
+    T: for<'de> serde::Deserialize<'de> + SchemaVersioned,
+{
+    if version > T::SCHEMA_VERSION {
+        return Err(format!(
+            "Data has schema version {}, newer than the supported version {}",
+            version,
+            T::SCHEMA_VERSION
+        )
+        .into());
+    }
+
+    let mut data = Vec::with_capacity(records.len());
+    for mut record in records {
+        for from_version in version..T::SCHEMA_VERSION {
+            T::migrate(from_version, &mut record)?;
+        }
+        data.push(serde_json::from_value(record)?);
+    }
+    Ok(data)
+}
+
+/// Content of a JSON file, the data in an envelope with the current schema version.
+pub fn serialize_json_file<T>(data: &[T]) -> Result<String, Box<dyn std::error::Error>>
+where
+    T: serde::Serialize + SchemaVersioned,
+{
+    Ok(serde_json::to_string_pretty(&Envelope {
+        schema_version: T::SCHEMA_VERSION,
+        data,
+    })?)
+}
+
+/// Read the content of a JSON file, returns the schema version it was written with and the
+/// migrated data.
+pub fn deserialize_json_file<T>(content: &str) -> Result<(u32, Vec<T>), Box<dyn std::error::Error>>
+where
+    T: for<'de> serde::Deserialize<'de> + SchemaVersioned,
+{
+    // files written before versioning are bare arrays
+    let (version, records): (u32, Vec<serde_json::Value>) =
+        match serde_json::from_str::<serde_json::Value>(content)? {
+            serde_json::Value::Array(records) => (0, records),
+            serde_json::Value::Object(mut envelope) => {
+                let version = envelope
+                    .get("schema_version")
+                    .and_then(|it| it.as_u64())
+                    .ok_or("Missing schema_version")?;
+                let records =
+                    serde_json::from_value(envelope.remove("data").ok_or("Missing data")?)?;
+                (version as u32, records)
+            }
+            _ => return Err("Unsupported file content".into()),
+        };
+
+    Ok((version, migrate_records::<T>(version, records)?))
+}
+
+/**
+Where the data of a Struct Database is persisted.
+
+The data is kept in memory by the DB, the backend only loads it at start and persists the changes.
+*/
+pub trait StorageBackend<T>: Send {
+    fn load(&mut self) -> Result<Vec<T>, Box<dyn std::error::Error>>;
+
+    /// Replace all the stored data.
+    fn write_all(&mut self, data: &[T]) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// Persist the upserted and deleted data.
+    /// `data` is all the data after the changes, for backends which can only write everything.
+    fn write_changes(
+        &mut self,
+        data: &[T],
+        upserted: &[&T],
+        deleted: &[u64],
+    ) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// Change the cipher used to write, the data is not rewritten.
+    fn set_cipher(&mut self, cipher: Option<Cipher>);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::EnumString, strum::Display)]
+#[strum(serialize_all = "snake_case", ascii_case_insensitive)]
+pub enum StorageBackendKind {
+    /// One pretty-printed JSON file per DB, rewritten on each change.
+    Json,
+    /// One table per DB in a SQLite file, only changed rows are written.
+    Sqlite,
+}
+
+impl StorageBackendKind {
+    /// Read `DB_BACKEND`, JSON files by default.
+    pub fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
+        let kind: String = env_or_default("DB_BACKEND", "json".to_string())?;
+        StorageBackendKind::from_str(&kind)
+            .map_err(|_| format!("Unsupported DB_BACKEND: {kind}").into())
+    }
+}
+
+/**
+Open the storage of the DB which JSON file is `file_path`, ex: `db/accounts.json`.
+
+With SQLite, the file name without extension is used as table name.
+*/
+pub fn open_storage<T>(
+    kind: StorageBackendKind,
+    file_path: &str,
+) -> Result<Box<dyn StorageBackend<T>>, Box<dyn std::error::Error>>
+where
+    T: serde::Serialize + for<'de> serde::Deserialize<'de> + HasId + SchemaVersioned + 'static,
+{
+    let cipher = Cipher::from_env()?;
+    Ok(match kind {
+        StorageBackendKind::Json => Box::new(JsonFileStorage::new(file_path.to_string(), cipher)?),
+        StorageBackendKind::Sqlite => {
+            let table = table_name(file_path);
+            let storage = SqliteStorage::open(&sqlite_path()?, &table, cipher)?;
+            if storage.is_empty()? && fs::metadata(file_path).is_ok_and(|it| it.len() > 0) {
+                warn!(
+                    "SQLite table {} is empty but {} exists, run `migrate-json-to-sqlite` to import it.",
+                    table, file_path
+                );
+            }
+            Box::new(storage)
+        }
+    })
+}
+
+/// Path of the SQLite file, from `DB_SQLITE_PATH`.
+pub fn sqlite_path() -> Result<String, Box<dyn std::error::Error>> {
+    env_or_default("DB_SQLITE_PATH", DEFAULT_SQLITE_PATH.to_string())
+}
+
+/// Name of the SQLite table of a DB, the JSON file name without extension.
+pub(super) fn table_name(file_path: &str) -> String {
+    Path::new(file_path)
+        .file_stem()
+        .and_then(|it| it.to_str())
+        .unwrap_or(file_path)
+        .to_string()
+}
+
+/// Create the parent folder of a file if necessary.
+fn create_parent_folder(file_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(folder_path) = Path::new(file_path).parent()
+        && !folder_path.as_os_str().is_empty()
+        && !fs::exists(folder_path)?
+    {
+        fs::create_dir_all(folder_path)?;
+        info!("Created folder: {}", folder_path.display());
+    }
+    Ok(())
+}
+
+pub struct JsonFileStorage {
+    file_path: String,
+    /// The whole file is encrypted when set.
+    cipher: Option<Cipher>,
+}
+
+impl JsonFileStorage {
+    /// Create the file if it does not exist.
+    pub fn new(
+        file_path: String,
+        cipher: Option<Cipher>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        if !fs::exists(&file_path)? {
+            create_parent_folder(&file_path)?;
+            File::create(&file_path)?;
+            info!("Created file: {}", file_path);
+        }
+        Ok(JsonFileStorage { file_path, cipher })
+    }
+
+    /// Write a file through a temporary file, encrypted if enabled and bound to the DB name so a
+    /// backup can be restored by renaming it.
+    fn write_encrypted(
+        &self,
+        path: &str,
+        content: Vec<u8>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let content =
+            encrypt_if_enabled(self.cipher.as_ref(), content, &table_name(&self.file_path))?;
+
+        let tmp_path = format!("{}.tmp", path);
+        let mut file = File::create(&tmp_path)?; // this truncates the exiting file if any
+        file.write_all(&content)?;
+        file.sync_all()?;
+
+        fs::rename(&tmp_path, path)?; // this replaces the existing file
+        Ok(())
+    }
+
+    /// Encrypt the migration backups of the file written before encryption was enabled.
+    fn encrypt_migration_backups(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.cipher.is_none() {
+            return Ok(());
+        }
+        let path = Path::new(&self.file_path);
+        let folder_path = match path.parent() {
+            Some(folder_path) if !folder_path.as_os_str().is_empty() => folder_path,
+            _ => Path::new("."),
+        };
+        let backup_prefix = format!(
+            "{}.v",
+            path.file_name()
+                .and_then(|it| it.to_str())
+                .unwrap_or_default()
+        );
+        for entry in fs::read_dir(folder_path)? {
+            let entry = entry?;
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            if !file_name.starts_with(&backup_prefix) || !file_name.ends_with(".bak") {
+                continue;
+            }
+            let backup_path = entry.path().to_string_lossy().to_string();
+            let content = fs::read(&backup_path)?;
+            if !is_encrypted(&content) {
+                self.write_encrypted(&backup_path, content)?;
+                info!("Encrypted migration backup {}.", backup_path);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T> StorageBackend<T> for JsonFileStorage
+where
+    T: serde::Serialize + for<'de> serde::Deserialize<'de> + SchemaVersioned,
+{
+    /**
+    Files of an older schema version are backed up, migrated and rewritten.
+
+    When encryption is enabled, a file which is not encrypted with the current key is rewritten,
+    with its migration backups.
+    */
+    fn load(&mut self) -> Result<Vec<T>, Box<dyn std::error::Error>> {
+        if !fs::exists(&self.file_path)? {
+            return Ok(Vec::new());
+        }
+
+        let mut content = Vec::new();
+        let mut file = File::open(&self.file_path)?;
+        file.read_to_end(&mut content)?;
+        if content.is_empty() {
+            return Ok(Vec::new());
+        }
+        let (content, outdated_encryption) =
+            decrypt_and_check_key(self.cipher.as_ref(), content, &table_name(&self.file_path))?;
+        let content = String::from_utf8(content)?;
+
+        let (version, data) = deserialize_json_file::<T>(&content)
+            .map_err(|e| format!("Error loading {}: {}", self.file_path, e))?;
+        if version < T::SCHEMA_VERSION {
+            let backup_path = format!(
+                "{}.v{}.{}.bak",
+                self.file_path,
+                version,
+                Utc::now().format(MIGRATION_BACKUP_DATETIME_FORMAT)
+            );
+            self.write_encrypted(&backup_path, content.into_bytes())?;
+            info!(
+                "Migrating {} from schema version {} to {}, original backed up to {}.",
+                self.file_path,
+                version,
+                T::SCHEMA_VERSION,
+                backup_path
+            );
+            self.write_all(&data)?;
+        } else if outdated_encryption {
+            info!(
+                "Rewriting {}, it is not encrypted with the current key.",
+                self.file_path
+            );
+            self.write_all(&data)?;
+        }
+        if outdated_encryption {
+            self.encrypt_migration_backups()?;
+        }
+
+        Ok(data)
+    }
+
+    fn write_all(&mut self, data: &[T]) -> Result<(), Box<dyn std::error::Error>> {
+        let file_path = self.file_path.clone();
+        self.write_encrypted(&file_path, serialize_json_file(data)?.into_bytes())?;
+
+        info!("Saved file: {}", self.file_path);
+
+        Ok(())
+    }
+
+    fn write_changes(
+        &mut self,
+        data: &[T],
+        _upserted: &[&T],
+        _deleted: &[u64],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // a JSON file can only be rewritten entirely
+        self.write_all(data)
+    }
+
+    fn set_cipher(&mut self, cipher: Option<Cipher>) {
+        self.cipher = cipher;
+    }
+}
+
+/// Id and decrypted JSON of a row of a SQLite table.
+type Row = (u64, Vec<u8>);
+
+/**
+One table of a SQLite file, with the id and the JSON of each data.
+*/
+pub struct SqliteStorage {
+    connection: Connection,
+    table: String,
+    /// Each row is encrypted when set, as a blob instead of text.
+    cipher: Option<Cipher>,
+}
+
+impl SqliteStorage {
+    /// Open the SQLite file and create the table if necessary.
+    pub fn open(
+        sqlite_path: &str,
+        table: &str,
+        cipher: Option<Cipher>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        create_parent_folder(sqlite_path)?;
+        let connection = Connection::open(sqlite_path)?;
+        // each DB has its own connection to the file
+        connection.busy_timeout(Duration::from_secs(5))?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.execute(
+            &format!(
+                "CREATE TABLE IF NOT EXISTS \"{table}\" (id INTEGER PRIMARY KEY, data TEXT NOT NULL)"
+            ),
+            [],
+        )?;
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS schema_versions (name TEXT PRIMARY KEY, version INTEGER NOT NULL)",
+            [],
+        )?;
+
+        Ok(SqliteStorage {
+            connection,
+            table: table.to_string(),
+            cipher,
+        })
+    }
+
+    pub fn is_empty(&self) -> Result<bool, Box<dyn std::error::Error>> {
+        let count: i64 = self.connection.query_row(
+            &format!("SELECT COUNT(*) FROM \"{}\"", self.table),
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(count == 0)
+    }
+
+    /// Schema version of the table, 0 if written before versioning.
+    fn schema_version(&self) -> Result<u32, Box<dyn std::error::Error>> {
+        let version: Option<u32> = self
+            .connection
+            .query_row(
+                "SELECT version FROM schema_versions WHERE name = ?1",
+                params![self.table],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(version.unwrap_or(0))
+    }
+
+    fn set_schema_version(
+        transaction: &rusqlite::Transaction,
+        table: &str,
+        version: u32,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        transaction.execute(
+            "INSERT OR REPLACE INTO schema_versions (name, version) VALUES (?1, ?2)",
+            params![table, version],
+        )?;
+        Ok(())
+    }
+
+    fn upsert_rows<T: serde::Serialize + HasId>(
+        transaction: &rusqlite::Transaction,
+        table: &str,
+        cipher: Option<&Cipher>,
+        data: &[&T],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut statement = transaction.prepare_cached(&format!(
+            "INSERT OR REPLACE INTO \"{table}\" (id, data) VALUES (?1, ?2)"
+        ))?;
+        for it in data {
+            let json = serde_json::to_string(it)?;
+            statement.execute(params![
+                it.id() as i64,
+                Self::row_value(cipher, table, it.id(), json.into_bytes())?
+            ])?;
+        }
+        Ok(())
+    }
+
+    /// Name a row is encrypted with, so it can't be swapped with another row or table.
+    fn row_name(table: &str, id: u64) -> String {
+        format!("{table}/{id}")
+    }
+
+    /// Content of the data column of a row, a blob when encrypted, text otherwise.
+    fn row_value(
+        cipher: Option<&Cipher>,
+        table: &str,
+        id: u64,
+        json: Vec<u8>,
+    ) -> Result<Value, Box<dyn std::error::Error>> {
+        Ok(match cipher {
+            Some(cipher) => Value::Blob(cipher.encrypt(&json, &Self::row_name(table, id))?),
+            None => Value::Text(String::from_utf8(json)?),
+        })
+    }
+
+    /// Rows of a table, decrypted, with whether the table must be rewritten because a row is not
+    /// encrypted with the current key.
+    fn read_rows(
+        &self,
+        table: &str,
+    ) -> Result<(Vec<Row>, bool), Box<dyn std::error::Error>> {
+        let mut statement = self
+            .connection
+            .prepare(&format!("SELECT id, data FROM \"{table}\""))?;
+        // encrypted rows are blobs
+        let rows = statement.query_map([], |row| {
+            let content = match row.get_ref(1)? {
+                ValueRef::Blob(bytes) | ValueRef::Text(bytes) => bytes.to_vec(),
+                _ => Vec::new(),
+            };
+            Ok((row.get::<_, i64>(0)? as u64, content))
+        })?;
+
+        let mut decrypted = Vec::new();
+        let mut outdated_encryption = false;
+        for row in rows {
+            let (id, content) = row?;
+            let (content, outdated) =
+                decrypt_and_check_key(self.cipher.as_ref(), content, &Self::row_name(table, id))?;
+            outdated_encryption |= outdated;
+            decrypted.push((id, content));
+        }
+        Ok((decrypted, outdated_encryption))
+    }
+
+    /// Copy the rows to a new table, encrypted if enabled.
+    fn write_backup_table(
+        &mut self,
+        backup_table: &str,
+        rows: &[Row],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let transaction = self.connection.transaction()?;
+        transaction.execute_batch(&format!(
+            "DROP TABLE IF EXISTS \"{backup_table}\"; \
+            CREATE TABLE \"{backup_table}\" (id INTEGER PRIMARY KEY, data TEXT NOT NULL);"
+        ))?;
+        {
+            let mut statement = transaction.prepare(&format!(
+                "INSERT INTO \"{backup_table}\" (id, data) VALUES (?1, ?2)"
+            ))?;
+            for (id, content) in rows {
+                let value =
+                    Self::row_value(self.cipher.as_ref(), backup_table, *id, content.clone())?;
+                statement.execute(params![*id as i64, value])?;
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Encrypt the migration backup tables of the table written before encryption was enabled.
+    fn encrypt_migration_backups(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.cipher.is_none() {
+            return Ok(());
+        }
+        let backup_tables: Vec<String> = self
+            .connection
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'table' AND name GLOB ?1")?
+            .query_map(params![format!("{}_v*_bak*", self.table)], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        for backup_table in backup_tables {
+            let plain_rows: i64 = self.connection.query_row(
+                &format!("SELECT COUNT(*) FROM \"{backup_table}\" WHERE typeof(data) = 'text'"),
+                [],
+                |row| row.get(0),
+            )?;
+            if plain_rows == 0 {
+                continue;
+            }
+            let (rows, _) = self.read_rows(&backup_table)?;
+            self.write_backup_table(&backup_table, &rows)?;
+            info!("Encrypted migration backup table {}.", backup_table);
+        }
+        Ok(())
+    }
+}
+
+impl<T> StorageBackend<T> for SqliteStorage
+where
+    T: serde::Serialize + for<'de> serde::Deserialize<'de> + HasId + SchemaVersioned,
+{
+    /**
+    Tables of an older schema version are backed up, migrated and rewritten.
+
+    When encryption is enabled, a table which rows are not all encrypted with the current key is
+    rewritten, with its migration backups.
+    */
+    fn load(&mut self) -> Result<Vec<T>, Box<dyn std::error::Error>> {
+        let (rows, outdated_encryption) = self.read_rows(&self.table)?;
+        let records = rows
+            .iter()
+            .map(|(_, content)| serde_json::from_slice(content))
+            .collect::<Result<Vec<serde_json::Value>, _>>()?;
+
+        let version = self.schema_version()?;
+        let data = migrate_records::<T>(version, records)
+            .map_err(|e| format!("Error loading SQLite table {}: {}", self.table, e))?;
+        if version < T::SCHEMA_VERSION {
+            let backup_table = format!(
+                "{}_v{}_bak_{}",
+                self.table,
+                version,
+                Utc::now().format(MIGRATION_BACKUP_DATETIME_FORMAT)
+            );
+            self.write_backup_table(&backup_table, &rows)?;
+            info!(
+                "Migrating SQLite table {} from schema version {} to {}, original backed up to {}.",
+                self.table,
+                version,
+                T::SCHEMA_VERSION,
+                backup_table
+            );
+            self.write_all(&data)?;
+        } else if outdated_encryption {
+            info!(
+                "Rewriting SQLite table {}, it is not encrypted with the current key.",
+                self.table
+            );
+            self.write_all(&data)?;
+        }
+        if outdated_encryption {
+            self.encrypt_migration_backups()?;
+        }
+
+        Ok(data)
+    }
+
+    fn write_all(&mut self, data: &[T]) -> Result<(), Box<dyn std::error::Error>> {
+        let transaction = self.connection.transaction()?;
+        transaction.execute(&format!("DELETE FROM \"{}\"", self.table), [])?;
+        Self::upsert_rows(
+            &transaction,
+            &self.table,
+            self.cipher.as_ref(),
+            &data.iter().collect::<Vec<&T>>(),
+        )?;
+        Self::set_schema_version(&transaction, &self.table, T::SCHEMA_VERSION)?;
+        transaction.commit()?;
+
+        info!("Saved SQLite table: {}", self.table);
+
+        Ok(())
+    }
+
+    fn write_changes(
+        &mut self,
+        _data: &[T],
+        upserted: &[&T],
+        deleted: &[u64],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let transaction = self.connection.transaction()?;
+        Self::upsert_rows(&transaction, &self.table, self.cipher.as_ref(), upserted)?;
+        {
+            let mut statement = transaction
+                .prepare_cached(&format!("DELETE FROM \"{}\" WHERE id = ?1", self.table))?;
+            for id in deleted {
+                statement.execute(params![*id as i64])?;
+            }
+        }
+        transaction.commit()?;
+
+        debug!(
+            "Saved SQLite table {}: {} upserted, {} deleted.",
+            self.table,
+            upserted.len(),
+            deleted.len()
+        );
+
+        Ok(())
+    }
+
+    fn set_cipher(&mut self, cipher: Option<Cipher>) {
+        self.cipher = cipher;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+
+    /// Version 1 had `label` instead of `name`.
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Record {
+        id: u64,
+        name: String,
+    }
+
+    impl HasId for Record {
+        fn id(&self) -> u64 {
+            self.id
+        }
+    }
+
+    impl SchemaVersioned for Record {
+        const SCHEMA_VERSION: u32 = 2;
+
+        fn migrate(
+            from_version: u32,
+            record: &mut serde_json::Value,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            if from_version == 1 {
+                let record = record.as_object_mut().ok_or("Not an object")?;
+                let label = record.remove("label").ok_or("Missing label")?;
+                record.insert("name".to_string(), label);
+            }
+            Ok(())
+        }
+    }
+
+    fn record(id: u64, name: &str) -> Record {
+        Record {
+            id,
+            name: name.to_string(),
+        }
+    }
+
+    /// Empty folder for the files of a test.
+    fn test_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "powens-maybe-finance-connector-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn backups_in(dir: &Path, file_name: &str) -> Vec<String> {
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|it| it.unwrap().file_name().to_string_lossy().to_string())
+            .filter(|it| it.starts_with(file_name) && it.ends_with(".bak"))
+            .collect()
+    }
+
+    #[test]
+    fn bare_array_is_migrated_from_version_0() {
+        let (version, data) =
+            deserialize_json_file::<Record>(r#"[{"id": 1, "label": "a"}]"#).unwrap();
+        assert_eq!(version, 0);
+        assert_eq!(data, vec![record(1, "a")]);
+    }
+
+    #[test]
+    fn envelope_is_migrated_to_current_version() {
+        let (version, data) = deserialize_json_file::<Record>(
+            r#"{"schema_version": 1, "data": [{"id": 1, "label": "a"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(version, 1);
+        assert_eq!(data, vec![record(1, "a")]);
+    }
+
+    #[test]
+    fn serialized_file_is_an_envelope_with_current_version() {
+        let content = serialize_json_file(&[record(1, "a")]).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&content).unwrap();
+        assert_eq!(value["schema_version"], 2);
+        assert_eq!(
+            deserialize_json_file::<Record>(&content).unwrap(),
+            (2, vec![record(1, "a")])
+        );
+    }
+
+    #[test]
+    fn newer_version_is_rejected() {
+        let result = deserialize_json_file::<Record>(r#"{"schema_version": 3, "data": []}"#);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn json_file_is_backed_up_and_rewritten_when_migrated() {
+        let dir = test_dir("json-migration");
+        let file_path = dir.join("records.json").to_string_lossy().to_string();
+        let original = r#"{"schema_version": 1, "data": [{"id": 1, "label": "a"}]}"#;
+        fs::write(&file_path, original).unwrap();
+
+        let mut storage = JsonFileStorage::new(file_path.clone(), None).unwrap();
+        let data: Vec<Record> = storage.load().unwrap();
+        assert_eq!(data, vec![record(1, "a")]);
+
+        let backups = backups_in(&dir, "records.json.v1.");
+        assert_eq!(backups.len(), 1);
+        assert_eq!(fs::read_to_string(dir.join(&backups[0])).unwrap(), original);
+        let rewritten = fs::read_to_string(&file_path).unwrap();
+        assert_eq!(
+            deserialize_json_file::<Record>(&rewritten).unwrap(),
+            (2, vec![record(1, "a")])
+        );
+
+        // current version, no new backup
+        let _: Vec<Record> = storage.load().unwrap();
+        assert_eq!(backups_in(&dir, "records.json.v").len(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn json_file_of_newer_version_is_not_loaded() {
+        let dir = test_dir("json-newer");
+        let file_path = dir.join("records.json").to_string_lossy().to_string();
+        let original = r#"{"schema_version": 3, "data": [{"id": 1, "title": "a"}]}"#;
+        fs::write(&file_path, original).unwrap();
+
+        let mut storage = JsonFileStorage::new(file_path.clone(), None).unwrap();
+        let result: Result<Vec<Record>, _> = storage.load();
+        assert!(result.is_err());
+        assert_eq!(fs::read_to_string(&file_path).unwrap(), original);
+        assert!(backups_in(&dir, "records.json.v").is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sqlite_table_is_backed_up_and_rewritten_when_migrated() {
+        let dir = test_dir("sqlite-migration");
+        let sqlite_path = dir.join("db.sqlite").to_string_lossy().to_string();
+        let mut storage = SqliteStorage::open(&sqlite_path, "records", None).unwrap();
+        {
+            let transaction = storage.connection.transaction().unwrap();
+            transaction
+                .execute(
+                    "INSERT INTO records (id, data) VALUES (1, ?1)",
+                    params![r#"{"id": 1, "label": "a"}"#],
+                )
+                .unwrap();
+            SqliteStorage::set_schema_version(&transaction, "records", 1).unwrap();
+            transaction.commit().unwrap();
+        }
+
+        let data: Vec<Record> = storage.load().unwrap();
+        assert_eq!(data, vec![record(1, "a")]);
+        assert_eq!(storage.schema_version().unwrap(), 2);
+
+        let backup_tables: Vec<String> = storage
+            .connection
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'table' AND name LIKE 'records_v1_bak_%'")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(backup_tables.len(), 1);
+        let backed_up: String = storage
+            .connection
+            .query_row(
+                &format!("SELECT data FROM \"{}\"", backup_tables[0]),
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(backed_up, r#"{"id": 1, "label": "a"}"#);
+
+        drop(storage);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn cipher() -> Cipher {
+        Cipher::new(&[Cipher::generate_key()]).unwrap()
+    }
+
+    #[test]
+    fn plain_json_file_and_its_backups_are_encrypted_when_loaded_with_a_cipher() {
+        let dir = test_dir("json-encryption");
+        let file_path = dir.join("records.json").to_string_lossy().to_string();
+        let backup_path = dir.join("records.json.v1.20250101-000000.bak");
+        fs::write(&file_path, serialize_json_file(&[record(1, "a")]).unwrap()).unwrap();
+        fs::write(&backup_path, r#"{"schema_version": 1, "data": []}"#).unwrap();
+
+        let cipher = cipher();
+        let mut storage = JsonFileStorage::new(file_path.clone(), Some(cipher.clone())).unwrap();
+        let data: Vec<Record> = storage.load().unwrap();
+        assert_eq!(data, vec![record(1, "a")]);
+
+        let content = fs::read(&file_path).unwrap();
+        assert!(is_encrypted(&content));
+        let backup = fs::read(&backup_path).unwrap();
+        assert!(is_encrypted(&backup));
+        assert_eq!(
+            cipher.decrypt(&backup, "records").unwrap(),
+            br#"{"schema_version": 1, "data": []}"#
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn json_file_encrypted_with_a_previous_key_is_rewritten_with_the_current_key() {
+        let dir = test_dir("json-rotation");
+        let file_path = dir.join("records.json").to_string_lossy().to_string();
+        let previous_key = Cipher::generate_key();
+        let previous = Cipher::new(std::slice::from_ref(&previous_key)).unwrap();
+        let mut storage = JsonFileStorage::new(file_path.clone(), Some(previous)).unwrap();
+        storage.write_all(&[record(1, "a")]).unwrap();
+
+        let current_key = Cipher::generate_key();
+        let rotated = Cipher::new(&[current_key.clone(), previous_key]).unwrap();
+        let mut storage = JsonFileStorage::new(file_path.clone(), Some(rotated)).unwrap();
+        let data: Vec<Record> = storage.load().unwrap();
+        assert_eq!(data, vec![record(1, "a")]);
+
+        // readable with the current key alone
+        let current = Cipher::new(&[current_key]).unwrap();
+        let mut storage = JsonFileStorage::new(file_path, Some(current)).unwrap();
+        let data: Vec<Record> = storage.load().unwrap();
+        assert_eq!(data, vec![record(1, "a")]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn plain_sqlite_table_and_its_backups_are_encrypted_when_loaded_with_a_cipher() {
+        let dir = test_dir("sqlite-encryption");
+        let sqlite_path = dir.join("db.sqlite").to_string_lossy().to_string();
+        let mut storage = SqliteStorage::open(&sqlite_path, "records", None).unwrap();
+        storage.write_all(&[record(1, "a")]).unwrap();
+        storage
+            .write_backup_table("records_v1_bak_20250101-000000", &[(1, b"{}".to_vec())])
+            .unwrap();
+        drop(storage);
+
+        let mut storage = SqliteStorage::open(&sqlite_path, "records", Some(cipher())).unwrap();
+        let data: Vec<Record> = storage.load().unwrap();
+        assert_eq!(data, vec![record(1, "a")]);
+
+        for table in ["records", "records_v1_bak_20250101-000000"] {
+            let plain_rows: i64 = storage
+                .connection
+                .query_row(
+                    &format!("SELECT COUNT(*) FROM \"{table}\" WHERE typeof(data) = 'text'"),
+                    [],
+                    |row| row.get(0),
+                )
+                .unwrap();
+            assert_eq!(plain_rows, 0, "{table}");
+        }
+        let (rows, outdated) = storage.read_rows("records_v1_bak_20250101-000000").unwrap();
+        assert_eq!(rows, vec![(1, b"{}".to_vec())]);
+        assert!(!outdated);
+
+        drop(storage);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn encrypted_sqlite_rows_are_bound_to_their_id() {
+        let dir = test_dir("sqlite-row-binding");
+        let sqlite_path = dir.join("db.sqlite").to_string_lossy().to_string();
+        let mut storage = SqliteStorage::open(&sqlite_path, "records", Some(cipher())).unwrap();
+        storage
+            .write_all(&[record(1, "a"), record(2, "b")])
+            .unwrap();
+
+        // move the content of row 2 to row 1
+        storage
+            .connection
+            .execute_batch(
+                "DELETE FROM records WHERE id = 1; UPDATE records SET id = 1 WHERE id = 2;",
+            )
+            .unwrap();
+        let result: Result<Vec<Record>, _> = storage.load();
+        assert!(result.is_err());
+
+        drop(storage);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
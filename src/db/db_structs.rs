@@ -1,26 +1,102 @@
-use super::db_base::StructFileDb;
-use crate::powens::{Account, HasId, Sortable, Transaction};
+use super::db_base::{IndexRange, StructFileDb, UpsertOutcome};
+use super::db_encryption::Cipher;
+use super::db_storage::{
+    JsonFileStorage, SchemaVersioned, SqliteStorage, StorageBackend, table_name,
+};
+use crate::config::env_or_default;
+use crate::events::{Event, EventBus};
+use crate::powens::{
+    Account, HasId, Investment, MarketOrder, Sortable, Transaction, POWENS_DATE_FORMAT,
+    POWENS_DATETIME_FORMAT,
+};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, Utc};
 use serde::{Deserialize, Serialize};
-use tracing::info;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+use tracing::{error, info};
+
+impl SchemaVersioned for Account {}
+impl SchemaVersioned for Investment {}
+impl SchemaVersioned for MarketOrder {}
+impl SchemaVersioned for Transaction {}
+
+pub const ACCOUNTS_DB_FILE: &str = "db/accounts.json";
 
 pub type AccountsDb = StructFileDb<Account>;
 
 impl AccountsDb {
     pub fn new_account_db() -> Result<Self, Box<dyn std::error::Error>> {
-        let res = StructFileDb::<Account>::new("db/accounts.json".to_string());
+        let res = StructFileDb::<Account>::new(ACCOUNTS_DB_FILE.to_string());
         info!("Accounts DB initialized.");
         res
     }
 }
 
+pub const INVESTMENTS_DB_FILE: &str = "db/investments.json";
+
+pub type InvestmentsDb = StructFileDb<Investment>;
+
+impl InvestmentsDb {
+    pub fn new_investment_db() -> Result<Self, Box<dyn std::error::Error>> {
+        let res = StructFileDb::<Investment>::new(INVESTMENTS_DB_FILE.to_string());
+        info!("Investments DB initialized.");
+        res
+    }
+}
+
+pub const MARKET_ORDERS_DB_FILE: &str = "db/market_orders.json";
+
+pub type MarketOrdersDb = StructFileDb<MarketOrder>;
+
+impl MarketOrdersDb {
+    pub fn new_market_order_db() -> Result<Self, Box<dyn std::error::Error>> {
+        let res = StructFileDb::<MarketOrder>::new(MARKET_ORDERS_DB_FILE.to_string());
+        info!("Market Orders DB initialized.");
+        res
+    }
+}
+
+pub const TRANSACTION_DB_FILE: &str = "db/transaction.json";
+
 pub type TransactionsDb = StructFileDb<Transaction>;
 
+const INDEX_ID_ACCOUNT: &str = "id_account";
+const INDEX_DATE: &str = "date";
+
 impl TransactionsDb {
     pub fn new_transaction_db() -> Result<Self, Box<dyn std::error::Error>> {
-        let res = StructFileDb::<Transaction>::new("db/transaction.json".to_string());
+        let res = StructFileDb::<Transaction>::new(TRANSACTION_DB_FILE.to_string()).map(|db| {
+            db.with_index(INDEX_ID_ACCOUNT, |it| it.id_account.to_string())
+                .with_index(INDEX_DATE, |it| it.date.clone())
+        });
         info!("Transactions DB initialized.");
         res
     }
+
+    /// Transactions of an account, sorted by date.
+    pub fn find_by_account(&self, id_account: u64) -> Vec<Transaction> {
+        self.find_by_index(INDEX_ID_ACCOUNT, &id_account.to_string())
+    }
+
+    /// Index range of the transactions of an account, for [`StructFileDb::query`].
+    pub fn account_index_range(id_account: u64) -> IndexRange {
+        IndexRange {
+            name: INDEX_ID_ACCOUNT,
+            min: Some(id_account.to_string()),
+            max: Some(id_account.to_string()),
+        }
+    }
+
+    /// Index range of the transactions which date is between min and max included, for
+    /// [`StructFileDb::query`].
+    pub fn date_index_range(min: Option<NaiveDate>, max: Option<NaiveDate>) -> IndexRange {
+        let format = |it: NaiveDate| it.format(POWENS_DATE_FORMAT).to_string();
+        IndexRange {
+            name: INDEX_DATE,
+            min: min.map(format),
+            max: max.map(format),
+        }
+    }
 }
 
 /**
@@ -34,6 +110,8 @@ pub struct TransactionExtras {
     pub tags: Vec<String>,
 }
 
+impl SchemaVersioned for TransactionExtras {}
+
 impl HasId for TransactionExtras {
     fn id(&self) -> u64 {
         self.id
@@ -46,12 +124,1241 @@ impl Sortable for TransactionExtras {
     }
 }
 
+pub const TRANSACTION_EXTRAS_DB_FILE: &str = "db/transaction_extras.json";
+
 pub type TransactionExtrasDb = StructFileDb<TransactionExtras>;
 
 impl TransactionExtrasDb {
     pub fn new_transaction_extras_db() -> Result<Self, Box<dyn std::error::Error>> {
-        let res = StructFileDb::<TransactionExtras>::new("db/transaction_extras.json".to_string());
+        let res = StructFileDb::<TransactionExtras>::new(TRANSACTION_EXTRAS_DB_FILE.to_string());
         info!("Transaction Extras DB initialized.");
         res
     }
 }
+
+/**
+Balance of an account on a day.
+
+`AccountsDb` only keeps the latest balance of accounts, snapshots keep their history.
+*/
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BalanceSnapshot {
+    /// Made of the account id and the date, so that there is only one snapshot per account per day.
+    pub id: u64,
+    pub id_account: u64,
+    /// Format: %Y-%m-%d
+    pub date: String,
+    pub balance: f64,
+    pub coming_balance: f64,
+    pub currency: String,
+}
+
+impl BalanceSnapshot {
+    /**
+    Snapshot of the account balance, dated by the last update of the account, or today if unknown.
+
+    Fails if the account id is too big to make the snapshot id.
+    */
+    pub fn from_account(account: &Account) -> Result<Self, String> {
+        let date: NaiveDate =
+            NaiveDateTime::parse_from_str(&account.last_update, POWENS_DATETIME_FORMAT)
+                .map(|it| it.date())
+                .unwrap_or_else(|_| Utc::now().date_naive());
+
+        Ok(BalanceSnapshot {
+            id: Self::snapshot_id(account.id, date).ok_or(format!(
+                "Account id {} is too big for a balance snapshot id",
+                account.id
+            ))?,
+            id_account: account.id,
+            date: date.format(POWENS_DATE_FORMAT).to_string(),
+            balance: account.balance,
+            coming_balance: account.coming_balance,
+            currency: account.currency.id.clone(),
+        })
+    }
+
+    /// id_account * 10^8 + yyyymmdd, None if it overflows.
+    fn snapshot_id(id_account: u64, date: NaiveDate) -> Option<u64> {
+        let day = date.year() as u64 * 10_000 + date.month() as u64 * 100 + date.day() as u64;
+        id_account.checked_mul(100_000_000)?.checked_add(day)
+    }
+}
+
+impl SchemaVersioned for BalanceSnapshot {}
+
+impl HasId for BalanceSnapshot {
+    fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Sortable for BalanceSnapshot {
+    fn sortable_value(&self) -> impl Ord {
+        (self.id_account, self.date.clone())
+    }
+}
+
+pub const BALANCE_SNAPSHOTS_DB_FILE: &str = "db/balance_snapshots.json";
+
+pub type BalanceSnapshotsDb = StructFileDb<BalanceSnapshot>;
+
+impl BalanceSnapshotsDb {
+    pub fn new_balance_snapshot_db() -> Result<Self, Box<dyn std::error::Error>> {
+        let res = StructFileDb::<BalanceSnapshot>::new(BALANCE_SNAPSHOTS_DB_FILE.to_string())
+            .map(|db| db.with_index(INDEX_ID_ACCOUNT, |it| it.id_account.to_string()));
+        info!("Balance Snapshots DB initialized.");
+        res
+    }
+
+    /// Record the current balance of the accounts, replacing the snapshot of the same day if any.
+    pub fn record_accounts(&self, accounts: &[Account]) -> Result<(), Box<dyn std::error::Error>> {
+        let snapshots = accounts
+            .iter()
+            .map(BalanceSnapshot::from_account)
+            .collect::<Result<Vec<_>, _>>()?;
+        self.upsert_many(snapshots)?;
+        Ok(())
+    }
+
+    /// Balance history of an account, sorted by date.
+    pub fn find_by_account(&self, id_account: u64) -> Vec<BalanceSnapshot> {
+        self.find_by_index(INDEX_ID_ACCOUNT, &id_account.to_string())
+    }
+}
+
+/**
+A coming transaction which has been replaced by a posted transaction with another id.
+*/
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Reconciliation {
+    /// ID of the posted transaction.
+    pub id: u64,
+    /// ID of the removed coming transaction.
+    pub coming_id: u64,
+    pub id_account: u64,
+    pub value: f64,
+    pub coming_date: String,
+    pub posted_date: String,
+    pub coming_wording: String,
+    pub posted_wording: String,
+    pub wording_similarity: f64,
+    /// Whether categories and tags of the coming transaction were carried over to the posted transaction.
+    pub carried_over_extras: bool,
+    /// RFC 3339 datetime.
+    pub reconciled_at: String,
+}
+
+impl SchemaVersioned for Reconciliation {}
+
+impl HasId for Reconciliation {
+    fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Sortable for Reconciliation {
+    fn sortable_value(&self) -> impl Ord {
+        self.reconciled_at.clone()
+    }
+}
+
+pub const RECONCILIATIONS_DB_FILE: &str = "db/reconciliations.json";
+
+pub type ReconciliationsDb = StructFileDb<Reconciliation>;
+
+impl ReconciliationsDb {
+    pub fn new_reconciliation_db() -> Result<Self, Box<dyn std::error::Error>> {
+        let res = StructFileDb::<Reconciliation>::new(RECONCILIATIONS_DB_FILE.to_string());
+        info!("Reconciliations DB initialized.");
+        res
+    }
+}
+
+/**
+Incremental sync state of the transactions of an account.
+*/
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SyncState {
+    /// ID of the account.
+    pub id: u64,
+    /// Greatest `last_update` of the transactions of the account synced so far, format: %Y-%m-%d %H:%M:%S
+    pub cursor: Option<String>,
+    /// `last_update` filter sent to Powens on the last sync, the cursor minus the overlap window.
+    /// None if the last sync fetched all transactions.
+    pub last_fetched_since: Option<String>,
+    /// Number of transactions fetched on the last sync.
+    pub last_fetched_count: usize,
+    /// RFC 3339 datetime of the last sync.
+    pub last_synced_at: String,
+}
+
+impl SchemaVersioned for SyncState {}
+
+impl HasId for SyncState {
+    fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Sortable for SyncState {
+    fn sortable_value(&self) -> impl Ord {
+        self.id
+    }
+}
+
+pub const SYNC_STATES_DB_FILE: &str = "db/sync_states.json";
+
+pub type SyncStatesDb = StructFileDb<SyncState>;
+
+impl SyncStatesDb {
+    pub fn new_sync_state_db() -> Result<Self, Box<dyn std::error::Error>> {
+        let res = StructFileDb::<SyncState>::new(SYNC_STATES_DB_FILE.to_string());
+        info!("Sync States DB initialized.");
+        res
+    }
+}
+
+/**
+Fetch state of the investments and market orders of an investment account.
+*/
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InvestmentSyncState {
+    /// ID of the account.
+    pub id: u64,
+    /// RFC 3339 datetime of the last successful fetch of the investments.
+    pub investments_fetched_at: Option<String>,
+    /// RFC 3339 datetime of the last successful fetch of the market orders.
+    pub market_orders_fetched_at: Option<String>,
+}
+
+impl SchemaVersioned for InvestmentSyncState {}
+
+impl HasId for InvestmentSyncState {
+    fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Sortable for InvestmentSyncState {
+    fn sortable_value(&self) -> impl Ord {
+        self.id
+    }
+}
+
+pub const INVESTMENT_SYNC_STATES_DB_FILE: &str = "db/investment_sync_states.json";
+
+pub type InvestmentSyncStatesDb = StructFileDb<InvestmentSyncState>;
+
+impl InvestmentSyncStatesDb {
+    pub fn new_investment_sync_state_db() -> Result<Self, Box<dyn std::error::Error>> {
+        let res = StructFileDb::<InvestmentSyncState>::new(
+            INVESTMENT_SYNC_STATES_DB_FILE.to_string(),
+        );
+        info!("Investment Sync States DB initialized.");
+        res
+    }
+
+    /// Whether both the investments and the market orders of the account have been fetched once.
+    pub fn has_fetched(&self, id_account: u64) -> bool {
+        self.find_by_id(id_account).is_some_and(|it| {
+            it.investments_fetched_at.is_some() && it.market_orders_fetched_at.is_some()
+        })
+    }
+
+    /// Whether the market orders of the account have been fetched at least once.
+    pub fn has_fetched_market_orders(&self, id_account: u64) -> bool {
+        self.find_by_id(id_account)
+            .is_some_and(|it| it.market_orders_fetched_at.is_some())
+    }
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, strum::Display)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncRunKind {
+    /// Fetch or receive data from Powens, then AI guessing.
+    #[default]
+    Fetch,
+    /// AI guessing only.
+    Classify,
+    /// Fetch transactions of a date range.
+    Backfill,
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, strum::Display)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncTrigger {
+    #[default]
+    Startup,
+    Scheduler,
+    Http,
+    Webhook,
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncRunStatus {
+    #[default]
+    Running,
+    Succeeded,
+    /// Finished with at least one error.
+    Failed,
+    /// Stopped before the end by a cancellation request.
+    Cancelled,
+}
+
+/**
+A run of a fetch or classify job, to keep track of what jobs did.
+*/
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SyncRun {
+    pub id: u64,
+    pub kind: SyncRunKind,
+    pub trigger: SyncTrigger,
+    pub status: SyncRunStatus,
+    /// RFC 3339 datetime.
+    pub started_at: String,
+    /// RFC 3339 datetime, None while running.
+    pub ended_at: Option<String>,
+    /// Number of transactions fetched from Powens.
+    pub fetched: usize,
+    /// Number of fetched transactions which were not in DB.
+    pub inserted: usize,
+    /// Number of fetched transactions which were in DB and have changed.
+    pub updated: usize,
+    /// Number of AI calls made to guess categories.
+    pub ai_calls: usize,
+    pub errors: Vec<String>,
+}
+
+impl SyncRun {
+    /// Whether the run is saved in DB, an unrecorded run has the ID 0.
+    pub fn is_recorded(&self) -> bool {
+        self.id != 0
+    }
+
+    /// Log, record and publish an error, the run continues.
+    pub fn add_error(&mut self, events: &EventBus, context: &str, e: impl Display) {
+        error!("{}: {}", context, e);
+        let message = format!("{context}: {e}");
+        events.publish(Event::SyncError {
+            id_sync_run: self.id,
+            message: message.clone(),
+        });
+        self.errors.push(message);
+    }
+
+    /// Count the outcome of an upsert of a fetched transaction.
+    pub fn count_upsert(&mut self, outcome: UpsertOutcome) {
+        match outcome {
+            UpsertOutcome::Inserted => self.inserted += 1,
+            UpsertOutcome::Updated => self.updated += 1,
+            UpsertOutcome::Unchanged => {}
+        }
+    }
+}
+
+impl SchemaVersioned for SyncRun {}
+
+impl HasId for SyncRun {
+    fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Sortable for SyncRun {
+    fn sortable_value(&self) -> impl Ord {
+        self.id
+    }
+}
+
+pub const SYNC_RUNS_DB_FILE: &str = "db/sync_runs.json";
+
+pub type SyncRunsDb = StructFileDb<SyncRun>;
+
+impl SyncRunsDb {
+    pub fn new_sync_run_db() -> Result<Self, Box<dyn std::error::Error>> {
+        let res = StructFileDb::<SyncRun>::new(SYNC_RUNS_DB_FILE.to_string());
+        info!("Sync Runs DB initialized.");
+        res
+    }
+
+    /// Record the start of a run.
+    pub fn start(
+        &self,
+        kind: SyncRunKind,
+        trigger: SyncTrigger,
+    ) -> Result<SyncRun, Box<dyn std::error::Error>> {
+        let run = self.insert_with_next_id(|id| SyncRun {
+            id,
+            kind,
+            trigger,
+            started_at: Utc::now().to_rfc3339(),
+            ..Default::default()
+        })?;
+        info!(
+            "Sync run {} started: {} triggered by {}.",
+            run.id, kind, trigger
+        );
+        Ok(run)
+    }
+
+    /**
+    Record the start of a run, or log the error and return an unrecorded run, so the job can go on
+    even if the DB can't be written.
+    */
+    pub fn start_or_unrecorded(&self, kind: SyncRunKind, trigger: SyncTrigger) -> SyncRun {
+        self.start(kind, trigger).unwrap_or_else(|e| {
+            error!(
+                "Error recording sync run, continuing without recording it: {:#?}",
+                e
+            );
+            SyncRun {
+                kind,
+                trigger,
+                started_at: Utc::now().to_rfc3339(),
+                ..Default::default()
+            }
+        })
+    }
+
+    /// Record the end of a run, the status is kept if it has been set by the job.
+    pub fn finish(&self, mut run: SyncRun) {
+        run.ended_at = Some(Utc::now().to_rfc3339());
+        if run.status == SyncRunStatus::Running {
+            run.status = if run.errors.is_empty() {
+                SyncRunStatus::Succeeded
+            } else {
+                SyncRunStatus::Failed
+            };
+        }
+
+        info!(
+            "Sync run {} finished: {:?}, fetched {}, inserted {}, updated {}, AI calls {}, errors {}.",
+            run.id,
+            run.status,
+            run.fetched,
+            run.inserted,
+            run.updated,
+            run.ai_calls,
+            run.errors.len()
+        );
+        if !run.is_recorded() {
+            return;
+        }
+        if let Err(e) = self.upsert(run) {
+            error!("Error saving sync run: {:#?}", e);
+        }
+    }
+}
+
+/// Kind of data which changes are recorded.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, strum::Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum ChangeRecord {
+    #[default]
+    Transaction,
+    TransactionExtras,
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    #[default]
+    Inserted,
+    Updated,
+    Deleted,
+}
+
+/// What made a change.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, strum::Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum ChangeSource {
+    /// Fetch, webhook or backfill of Powens data, with the reconciliation of coming transactions.
+    #[default]
+    PowensSync,
+    /// AI guessing of categories.
+    Ai,
+    /// Edit or revert through the API.
+    Manual,
+    /// Restore of a backup.
+    Restore,
+}
+
+/// Old and new value of a field, null when the record did not exist or does not exist anymore.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FieldChange {
+    pub old: serde_json::Value,
+    pub new: serde_json::Value,
+}
+
+/**
+A change of a transaction or of transaction extras, the change log is append-only.
+*/
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Change {
+    pub id: u64,
+    pub record: ChangeRecord,
+    /// ID of the changed transaction or transaction extras.
+    pub id_record: u64,
+    pub kind: ChangeKind,
+    pub source: ChangeSource,
+    /// RFC 3339 datetime.
+    pub changed_at: String,
+    /// Changed fields only.
+    pub fields: BTreeMap<String, FieldChange>,
+    /// The whole data right after the change, null when deleted.
+    pub snapshot: serde_json::Value,
+}
+
+impl Change {
+    /// The data as it was right after the change, None if it has been deleted.
+    pub fn version_after<T>(&self) -> Result<Option<T>, Box<dyn std::error::Error>>
+    where
+        T: for<'de> Deserialize<'de> + Recorded,
+    {
+        if self.kind == ChangeKind::Deleted {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_value(self.snapshot.clone())?))
+    }
+}
+
+impl SchemaVersioned for Change {}
+
+impl HasId for Change {
+    fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Sortable for Change {
+    fn sortable_value(&self) -> impl Ord {
+        self.id
+    }
+}
+
+/// Data which changes are recorded in the change log.
+pub trait Recorded: Serialize + HasId {
+    const RECORD: ChangeRecord;
+}
+
+impl Recorded for Transaction {
+    const RECORD: ChangeRecord = ChangeRecord::Transaction;
+}
+
+impl Recorded for TransactionExtras {
+    const RECORD: ChangeRecord = ChangeRecord::TransactionExtras;
+}
+
+pub const CHANGES_DB_FILE: &str = "db/changes.json";
+/// Default number of days the changes are kept.
+const DEFAULT_CHANGES_RETENTION_DAYS: i64 = 365;
+
+const INDEX_RECORD: &str = "record";
+
+pub type ChangesDb = StructFileDb<Change>;
+
+impl ChangesDb {
+    pub fn new_change_db() -> Result<Self, Box<dyn std::error::Error>> {
+        let res = StructFileDb::<Change>::new(CHANGES_DB_FILE.to_string())
+            .map(|db| db.with_index(INDEX_RECORD, |it| record_key(it.record, it.id_record)));
+        info!("Changes DB initialized.");
+        res
+    }
+
+    /**
+    Upsert the data into `db`, and record the changes of the inserted and updated data.
+
+    If the changes can't be recorded, the data is restored as it was, so the data and the change
+    log stay consistent.
+    */
+    pub fn upsert_recorded<T>(
+        &self,
+        db: &StructFileDb<T>,
+        data: impl IntoIterator<Item = T>,
+        source: ChangeSource,
+    ) -> Result<Vec<UpsertOutcome>, Box<dyn std::error::Error>>
+    where
+        T: for<'de> Deserialize<'de> + Clone + Sortable + SchemaVersioned + PartialEq + Recorded,
+    {
+        let changed_at = Utc::now().to_rfc3339();
+        let mut changes = Vec::new();
+        let mut previous: Vec<(u64, Option<T>)> = Vec::new();
+        let outcomes = db.batch(|batch| {
+            data.into_iter()
+                .map(|it| {
+                    let id = it.id();
+                    let old_data = batch.find_by_id(id).cloned();
+                    let old = old_data.as_ref().map(to_json_value);
+                    let new = to_json_value(&it);
+                    let outcome = batch.upsert(it);
+                    let kind = match outcome {
+                        UpsertOutcome::Inserted => ChangeKind::Inserted,
+                        UpsertOutcome::Updated => ChangeKind::Updated,
+                        UpsertOutcome::Unchanged => return outcome,
+                    };
+                    previous.push((id, old_data));
+                    changes.push(Change {
+                        record: T::RECORD,
+                        id_record: id,
+                        kind,
+                        source,
+                        changed_at: changed_at.clone(),
+                        fields: diff_fields(old.as_ref(), Some(&new)),
+                        snapshot: new,
+                        ..Default::default()
+                    });
+                    outcome
+                })
+                .collect()
+        })?;
+        self.append_or_restore(changes, db, previous)?;
+        Ok(outcomes)
+    }
+
+    /**
+    Delete the data from `db`, and record the deletion if it existed.
+
+    If the deletion can't be recorded, the data is restored.
+    */
+    pub fn delete_recorded<T>(
+        &self,
+        db: &StructFileDb<T>,
+        id: u64,
+        source: ChangeSource,
+    ) -> Result<(), Box<dyn std::error::Error>>
+    where
+        T: for<'de> Deserialize<'de> + Clone + Sortable + SchemaVersioned + PartialEq + Recorded,
+    {
+        let old = db.batch(|batch| {
+            let old = batch.find_by_id(id).cloned();
+            batch.delete_by_id(id);
+            old
+        })?;
+        if let Some(old) = old {
+            let change = Change {
+                record: T::RECORD,
+                id_record: id,
+                kind: ChangeKind::Deleted,
+                source,
+                changed_at: Utc::now().to_rfc3339(),
+                fields: diff_fields(Some(&to_json_value(&old)), None),
+                ..Default::default()
+            };
+            self.append_or_restore(vec![change], db, vec![(id, Some(old))])?;
+        }
+        Ok(())
+    }
+
+    /**
+    Record the changes between the previous data of `db` and its current data, ex: after a restore
+    replaced all the data. Returns the number of recorded changes.
+    */
+    pub fn record_replaced<T>(
+        &self,
+        db: &StructFileDb<T>,
+        previous: Vec<T>,
+        source: ChangeSource,
+    ) -> Result<usize, Box<dyn std::error::Error>>
+    where
+        T: for<'de> Deserialize<'de> + Clone + Sortable + SchemaVersioned + PartialEq + Recorded,
+    {
+        let changed_at = Utc::now().to_rfc3339();
+        let mut previous: HashMap<u64, T> = previous.into_iter().map(|it| (it.id(), it)).collect();
+        let mut changes = Vec::new();
+        for current in db.data() {
+            let old = previous.remove(&current.id());
+            if old.as_ref() == Some(&current) {
+                continue;
+            }
+            let new = to_json_value(&current);
+            changes.push(Change {
+                record: T::RECORD,
+                id_record: current.id(),
+                kind: match old {
+                    Some(_) => ChangeKind::Updated,
+                    None => ChangeKind::Inserted,
+                },
+                source,
+                changed_at: changed_at.clone(),
+                fields: diff_fields(old.map(|it| to_json_value(&it)).as_ref(), Some(&new)),
+                snapshot: new,
+                ..Default::default()
+            });
+        }
+        let mut deleted: Vec<T> = previous.into_values().collect();
+        deleted.sort_by_key(|it| it.id());
+        for old in deleted {
+            changes.push(Change {
+                record: T::RECORD,
+                id_record: old.id(),
+                kind: ChangeKind::Deleted,
+                source,
+                changed_at: changed_at.clone(),
+                fields: diff_fields(Some(&to_json_value(&old)), None),
+                ..Default::default()
+            });
+        }
+
+        let count = changes.len();
+        self.append(changes)?;
+        Ok(count)
+    }
+
+    /// Append the changes, or put back the previous data of `db` if they can't be saved.
+    fn append_or_restore<T>(
+        &self,
+        changes: Vec<Change>,
+        db: &StructFileDb<T>,
+        previous: Vec<(u64, Option<T>)>,
+    ) -> Result<(), Box<dyn std::error::Error>>
+    where
+        T: for<'de> Deserialize<'de> + Clone + Sortable + SchemaVersioned + PartialEq + Recorded,
+    {
+        let Err(e) = self.append(changes) else {
+            return Ok(());
+        };
+        let restored = db.batch(|batch| {
+            for (id, old) in previous {
+                match old {
+                    Some(old) => {
+                        batch.upsert(old);
+                    }
+                    None => batch.delete_by_id(id),
+                }
+            }
+        });
+        if let Err(restore_error) = restored {
+            error!(
+                "Error restoring {} data after the changes could not be recorded: {:#?}",
+                T::RECORD,
+                restore_error
+            );
+        }
+        Err(e)
+    }
+
+    /// Save the changes with new ids, in a single write.
+    fn append(&self, changes: Vec<Change>) -> Result<(), Box<dyn std::error::Error>> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+        self.batch(|batch| {
+            for (id, mut change) in (batch.next_id()..).zip(changes) {
+                change.id = id;
+                batch.upsert(change);
+            }
+        })
+    }
+
+    /// Changes of a record, oldest first.
+    pub fn find_by_record(&self, record: ChangeRecord, id_record: u64) -> Vec<Change> {
+        self.find_by_index(INDEX_RECORD, &record_key(record, id_record))
+    }
+
+    /// Delete the changes older than `CHANGES_RETENTION_DAYS` days, returns the number of deleted
+    /// changes. Changes are kept forever with 0.
+    pub fn prune(&self) -> Result<usize, Box<dyn std::error::Error>> {
+        let retention_days: i64 =
+            env_or_default("CHANGES_RETENTION_DAYS", DEFAULT_CHANGES_RETENTION_DAYS)?;
+        if retention_days <= 0 {
+            return Ok(0);
+        }
+        self.prune_older_than(Utc::now() - Duration::days(retention_days))
+    }
+
+    /// Delete the changes made before `cutoff`, except the latest change, so that ids are not
+    /// given again to new changes.
+    fn prune_older_than(&self, cutoff: DateTime<Utc>) -> Result<usize, Box<dyn std::error::Error>> {
+        let pruned = self.batch(|batch| {
+            let latest_id = batch.next_id() - 1;
+            let mut pruned = 0;
+            batch.retain(|it| {
+                let expired = it.id != latest_id
+                    && DateTime::parse_from_rfc3339(&it.changed_at).is_ok_and(|it| it < cutoff);
+                pruned += expired as usize;
+                !expired
+            });
+            pruned
+        })?;
+        if pruned > 0 {
+            info!("Deleted {} changes made before {}.", pruned, cutoff);
+        }
+        Ok(pruned)
+    }
+}
+
+fn record_key(record: ChangeRecord, id_record: u64) -> String {
+    format!("{record}:{id_record}")
+}
+
+fn to_json_value<T: Serialize>(data: &T) -> serde_json::Value {
+    serde_json::to_value(data).unwrap_or_default()
+}
+
+/// Old and new value of the fields which differ, a missing data has null fields.
+fn diff_fields(
+    old: Option<&serde_json::Value>,
+    new: Option<&serde_json::Value>,
+) -> BTreeMap<String, FieldChange> {
+    let empty = serde_json::Map::new();
+    let old = old.and_then(|it| it.as_object()).unwrap_or(&empty);
+    let new = new.and_then(|it| it.as_object()).unwrap_or(&empty);
+
+    old.keys()
+        .chain(new.keys())
+        .filter_map(|name| {
+            let old = old.get(name).cloned().unwrap_or_default();
+            let new = new.get(name).cloned().unwrap_or_default();
+            (old != new).then(|| (name.clone(), FieldChange { old, new }))
+        })
+        .collect()
+}
+
+/// A transaction as it was exported, to detect its changes since the export.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExportedTransaction {
+    pub id: u64,
+    /// Powens datetime.
+    pub last_update: String,
+    pub categories: Vec<String>,
+    pub tags: Vec<String>,
+}
+
+impl ExportedTransaction {
+    pub fn new(transaction: &Transaction, extras: Option<&TransactionExtras>) -> Self {
+        ExportedTransaction {
+            id: transaction.id,
+            last_update: transaction.last_update.clone(),
+            categories: extras.map(|it| it.categories.clone()).unwrap_or_default(),
+            tags: extras.map(|it| it.tags.clone()).unwrap_or_default(),
+        }
+    }
+}
+
+/**
+A CSV export of transactions, so that the next export only has the transactions not exported yet.
+*/
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExportBatch {
+    pub id: u64,
+    /// RFC 3339 datetime.
+    pub exported_at: String,
+    /// Amounts were converted to BASE_CURRENCY.
+    pub converted: bool,
+    pub transactions: Vec<ExportedTransaction>,
+    /// RFC 3339 datetime, the transactions of a rolled back batch count as not exported.
+    pub rolled_back_at: Option<String>,
+    /// The exported CSV, to download it again as it was.
+    pub csv: String,
+}
+
+impl SchemaVersioned for ExportBatch {}
+
+impl HasId for ExportBatch {
+    fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Sortable for ExportBatch {
+    fn sortable_value(&self) -> impl Ord {
+        self.id
+    }
+}
+
+pub const EXPORT_BATCHES_DB_FILE: &str = "db/export_batches.json";
+
+pub type ExportBatchesDb = StructFileDb<ExportBatch>;
+
+impl ExportBatchesDb {
+    pub fn new_export_batch_db() -> Result<Self, Box<dyn std::error::Error>> {
+        let res = StructFileDb::<ExportBatch>::new(EXPORT_BATCHES_DB_FILE.to_string());
+        info!("Export Batches DB initialized.");
+        res
+    }
+
+    /// Record an export of transactions, with the exported CSV.
+    pub fn record(
+        &self,
+        transactions: Vec<ExportedTransaction>,
+        converted: bool,
+        csv: String,
+    ) -> Result<ExportBatch, Box<dyn std::error::Error>> {
+        let batch = self.insert_with_next_id(|id| ExportBatch {
+            id,
+            exported_at: Utc::now().to_rfc3339(),
+            converted,
+            transactions,
+            rolled_back_at: None,
+            csv,
+        })?;
+        info!(
+            "Export batch {} recorded with {} transactions.",
+            batch.id,
+            batch.transactions.len()
+        );
+        Ok(batch)
+    }
+
+    /// The latest export of each exported transaction, with the id of its batch.
+    /// Rolled back batches are ignored.
+    pub fn latest_exports(&self) -> HashMap<u64, (u64, ExportedTransaction)> {
+        let mut exports = HashMap::new();
+        // batches are sorted by id, later exports replace the earlier ones
+        for batch in self.data() {
+            if batch.rolled_back_at.is_some() {
+                continue;
+            }
+            for transaction in batch.transactions {
+                exports.insert(transaction.id, (batch.id, transaction));
+            }
+        }
+        exports
+    }
+}
+
+/**
+Import the JSON files of all DBs into the SQLite file, as a one-shot migration to the SQLite backend.
+
+Tables which already have data are skipped, so that the migration can't overwrite newer data.
+*/
+pub fn migrate_json_to_sqlite(sqlite_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    migrate_json_file_to_sqlite::<Account>(ACCOUNTS_DB_FILE, sqlite_path)?;
+    migrate_json_file_to_sqlite::<Investment>(INVESTMENTS_DB_FILE, sqlite_path)?;
+    migrate_json_file_to_sqlite::<MarketOrder>(MARKET_ORDERS_DB_FILE, sqlite_path)?;
+    migrate_json_file_to_sqlite::<Transaction>(TRANSACTION_DB_FILE, sqlite_path)?;
+    migrate_json_file_to_sqlite::<TransactionExtras>(TRANSACTION_EXTRAS_DB_FILE, sqlite_path)?;
+    migrate_json_file_to_sqlite::<BalanceSnapshot>(BALANCE_SNAPSHOTS_DB_FILE, sqlite_path)?;
+    migrate_json_file_to_sqlite::<Reconciliation>(RECONCILIATIONS_DB_FILE, sqlite_path)?;
+    migrate_json_file_to_sqlite::<SyncState>(SYNC_STATES_DB_FILE, sqlite_path)?;
+    migrate_json_file_to_sqlite::<InvestmentSyncState>(
+        INVESTMENT_SYNC_STATES_DB_FILE,
+        sqlite_path,
+    )?;
+    migrate_json_file_to_sqlite::<SyncRun>(SYNC_RUNS_DB_FILE, sqlite_path)?;
+    migrate_json_file_to_sqlite::<Change>(CHANGES_DB_FILE, sqlite_path)?;
+    migrate_json_file_to_sqlite::<ExportBatch>(EXPORT_BATCHES_DB_FILE, sqlite_path)?;
+    info!("Migration to SQLite {} finished.", sqlite_path);
+    Ok(())
+}
+
+fn migrate_json_file_to_sqlite<T>(
+    file_path: &str,
+    sqlite_path: &str,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    T: Serialize + for<'de> Deserialize<'de> + HasId + SchemaVersioned,
+{
+    let table = table_name(file_path);
+    let cipher = Cipher::from_env()?;
+    let mut sqlite = SqliteStorage::open(sqlite_path, &table, cipher.clone())?;
+    if !sqlite.is_empty()? {
+        info!(
+            "SQLite table {} already has data, {} skipped.",
+            table, file_path
+        );
+        return Ok(());
+    }
+    if !std::fs::exists(file_path)? {
+        info!("{} does not exist, skipped.", file_path);
+        return Ok(());
+    }
+
+    let data: Vec<T> = JsonFileStorage::new(file_path.to_string(), cipher)?.load()?;
+    sqlite.write_all(&data)?;
+    info!(
+        "Imported {} rows from {} into SQLite table {}.",
+        data.len(),
+        file_path,
+        table
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// Storage keeping nothing, which writes fail when `failing` is set.
+    struct MemoryStorage {
+        failing: Arc<AtomicBool>,
+    }
+
+    impl<T> StorageBackend<T> for MemoryStorage {
+        fn load(&mut self) -> Result<Vec<T>, Box<dyn std::error::Error>> {
+            Ok(Vec::new())
+        }
+
+        fn write_all(&mut self, _data: &[T]) -> Result<(), Box<dyn std::error::Error>> {
+            if self.failing.load(Ordering::Relaxed) {
+                return Err("Write failed".into());
+            }
+            Ok(())
+        }
+
+        fn write_changes(
+            &mut self,
+            data: &[T],
+            _upserted: &[&T],
+            _deleted: &[u64],
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            self.write_all(data)
+        }
+
+        fn set_cipher(&mut self, _cipher: Option<Cipher>) {}
+    }
+
+    fn memory_db<T>(failing: &Arc<AtomicBool>) -> StructFileDb<T>
+    where
+        T: Serialize + for<'de> Deserialize<'de> + Clone + HasId + Sortable + SchemaVersioned,
+        T: PartialEq,
+    {
+        StructFileDb::with_storage(Box::new(MemoryStorage {
+            failing: failing.clone(),
+        }))
+        .unwrap()
+    }
+
+    fn change_db(failing: &Arc<AtomicBool>) -> ChangesDb {
+        memory_db::<Change>(failing)
+            .with_index(INDEX_RECORD, |it| record_key(it.record, it.id_record))
+    }
+
+    fn extras(id: u64, categories: &[&str]) -> TransactionExtras {
+        TransactionExtras {
+            id,
+            categories: categories.iter().map(|it| it.to_string()).collect(),
+            tags: Vec::new(),
+        }
+    }
+
+    fn changes_of(change_db: &ChangesDb, id: u64) -> Vec<Change> {
+        change_db.find_by_record(ChangeRecord::TransactionExtras, id)
+    }
+
+    #[test]
+    fn diff_fields_has_the_changed_fields_only() {
+        let old = serde_json::json!({"id": 1, "categories": ["food"], "tags": []});
+        let new = serde_json::json!({"id": 1, "categories": ["travel"], "tags": []});
+        let fields = diff_fields(Some(&old), Some(&new));
+        assert_eq!(
+            fields,
+            BTreeMap::from([(
+                "categories".to_string(),
+                FieldChange {
+                    old: serde_json::json!(["food"]),
+                    new: serde_json::json!(["travel"]),
+                }
+            )])
+        );
+    }
+
+    #[test]
+    fn diff_fields_of_missing_data_are_null() {
+        let data = serde_json::json!({"id": 1, "tags": ["a"]});
+
+        let inserted = diff_fields(None, Some(&data));
+        assert_eq!(inserted.len(), 2);
+        assert_eq!(inserted["tags"].old, serde_json::Value::Null);
+        assert_eq!(inserted["tags"].new, serde_json::json!(["a"]));
+
+        let deleted = diff_fields(Some(&data), None);
+        assert_eq!(deleted.len(), 2);
+        assert_eq!(deleted["id"].old, serde_json::json!(1));
+        assert_eq!(deleted["id"].new, serde_json::Value::Null);
+
+        assert!(diff_fields(Some(&data), Some(&data)).is_empty());
+    }
+
+    #[test]
+    fn insert_update_then_revert_to_the_insert() {
+        let failing = Arc::new(AtomicBool::new(false));
+        let change_db = change_db(&failing);
+        let extras_db = memory_db::<TransactionExtras>(&failing);
+
+        change_db
+            .upsert_recorded(&extras_db, [extras(1, &["food"])], ChangeSource::Ai)
+            .unwrap();
+        change_db
+            .upsert_recorded(&extras_db, [extras(1, &["travel"])], ChangeSource::Manual)
+            .unwrap();
+        let changes = changes_of(&change_db, 1);
+        assert_eq!(
+            changes.iter().map(|it| it.kind).collect::<Vec<_>>(),
+            [ChangeKind::Inserted, ChangeKind::Updated]
+        );
+
+        let version: Option<TransactionExtras> = changes[0].version_after().unwrap();
+        assert_eq!(version, Some(extras(1, &["food"])));
+
+        change_db
+            .upsert_recorded(&extras_db, version, ChangeSource::Manual)
+            .unwrap();
+        assert_eq!(extras_db.find_by_id(1), Some(extras(1, &["food"])));
+        let changes = changes_of(&change_db, 1);
+        assert_eq!(changes.len(), 3);
+        assert_eq!(changes[2].source, ChangeSource::Manual);
+        assert_eq!(
+            changes[2].fields["categories"].old,
+            serde_json::json!(["travel"])
+        );
+        assert_eq!(
+            changes[2].fields["categories"].new,
+            serde_json::json!(["food"])
+        );
+    }
+
+    #[test]
+    fn delete_reinsert_then_revert_to_the_delete() {
+        let failing = Arc::new(AtomicBool::new(false));
+        let change_db = change_db(&failing);
+        let extras_db = memory_db::<TransactionExtras>(&failing);
+
+        change_db
+            .upsert_recorded(&extras_db, [extras(1, &["food"])], ChangeSource::Ai)
+            .unwrap();
+        change_db
+            .delete_recorded(&extras_db, 1, ChangeSource::Manual)
+            .unwrap();
+        change_db
+            .upsert_recorded(&extras_db, [extras(1, &["travel"])], ChangeSource::Ai)
+            .unwrap();
+        let changes = changes_of(&change_db, 1);
+        assert_eq!(
+            changes.iter().map(|it| it.kind).collect::<Vec<_>>(),
+            [
+                ChangeKind::Inserted,
+                ChangeKind::Deleted,
+                ChangeKind::Inserted
+            ]
+        );
+
+        let after_delete: Option<TransactionExtras> = changes[1].version_after().unwrap();
+        assert_eq!(after_delete, None);
+        let after_insert: Option<TransactionExtras> = changes[0].version_after().unwrap();
+        assert_eq!(after_insert, Some(extras(1, &["food"])));
+
+        change_db
+            .delete_recorded(&extras_db, 1, ChangeSource::Manual)
+            .unwrap();
+        assert_eq!(extras_db.find_by_id(1), None);
+        assert_eq!(changes_of(&change_db, 1).len(), 4);
+    }
+
+    #[test]
+    fn data_is_restored_when_the_changes_can_not_be_recorded() {
+        let change_db_failing = Arc::new(AtomicBool::new(false));
+        let change_db = change_db(&change_db_failing);
+        let extras_db = memory_db::<TransactionExtras>(&Arc::new(AtomicBool::new(false)));
+        change_db
+            .upsert_recorded(&extras_db, [extras(1, &["food"])], ChangeSource::Ai)
+            .unwrap();
+
+        change_db_failing.store(true, Ordering::Relaxed);
+        let result = change_db.upsert_recorded(
+            &extras_db,
+            [extras(1, &["travel"]), extras(2, &["rent"])],
+            ChangeSource::Ai,
+        );
+        assert!(result.is_err());
+        assert_eq!(extras_db.data(), vec![extras(1, &["food"])]);
+
+        assert!(
+            change_db
+                .delete_recorded(&extras_db, 1, ChangeSource::Manual)
+                .is_err()
+        );
+        assert_eq!(extras_db.data(), vec![extras(1, &["food"])]);
+        assert_eq!(changes_of(&change_db, 1).len(), 1);
+    }
+
+    #[test]
+    fn replaced_data_is_recorded() {
+        let failing = Arc::new(AtomicBool::new(false));
+        let change_db = change_db(&failing);
+        let extras_db = memory_db::<TransactionExtras>(&failing);
+        let previous = vec![extras(1, &["food"]), extras(2, &["rent"]), extras(3, &[])];
+        extras_db
+            .save(vec![
+                extras(2, &["rent"]),
+                extras(3, &["travel"]),
+                extras(4, &[]),
+            ])
+            .unwrap();
+
+        let count = change_db
+            .record_replaced(&extras_db, previous, ChangeSource::Restore)
+            .unwrap();
+        assert_eq!(count, 3);
+        let kinds: Vec<(u64, ChangeKind)> = change_db
+            .data()
+            .iter()
+            .map(|it| (it.id_record, it.kind))
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                (3, ChangeKind::Updated),
+                (4, ChangeKind::Inserted),
+                (1, ChangeKind::Deleted)
+            ]
+        );
+        assert!(
+            change_db
+                .data()
+                .iter()
+                .all(|it| it.source == ChangeSource::Restore)
+        );
+    }
+
+    #[test]
+    fn balance_snapshot_id_is_made_of_the_account_id_and_the_date() {
+        let date = NaiveDate::from_ymd_opt(2026, 10, 17).unwrap();
+        assert_eq!(
+            BalanceSnapshot::snapshot_id(42, date),
+            Some(4_200_000_000 + 20_261_017)
+        );
+        assert_eq!(
+            BalanceSnapshot::snapshot_id(u64::MAX / 10_000_000, date),
+            None
+        );
+    }
+
+    #[test]
+    fn changes_older_than_the_cutoff_are_pruned_except_the_latest() {
+        let failing = Arc::new(AtomicBool::new(false));
+        let change_db = change_db(&failing);
+        let extras_db = memory_db::<TransactionExtras>(&failing);
+        for (id, category) in [(1, "food"), (2, "travel"), (3, "rent")] {
+            change_db
+                .upsert_recorded(&extras_db, [extras(id, &[category])], ChangeSource::Ai)
+                .unwrap();
+        }
+        // changes 1 and 3 are old
+        let old = change_db
+            .data()
+            .into_iter()
+            .filter(|it| it.id != 2)
+            .map(|it| Change {
+                changed_at: "2020-01-01T00:00:00+00:00".to_string(),
+                ..it
+            });
+        change_db.upsert_many(old).unwrap();
+
+        let pruned = change_db
+            .prune_older_than(Utc::now() - Duration::days(1))
+            .unwrap();
+        assert_eq!(pruned, 1);
+        assert_eq!(
+            change_db.data().iter().map(|it| it.id).collect::<Vec<_>>(),
+            [2, 3]
+        );
+        assert!(changes_of(&change_db, 1).is_empty());
+    }
+}
@@ -0,0 +1,87 @@
+use crate::handlers::AccountsRefreshReport;
+use crate::jobs::Job;
+use crate::powens::Transaction;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// Number of events kept for slow subscribers, older events are dropped for them.
+const EVENT_BUS_CAPACITY: usize = 1024;
+
+/**
+Events published while the connector works, streamed by `GET /events`.
+*/
+#[derive(Debug, Clone, Serialize, strum::IntoStaticStr)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum Event {
+    JobStarted {
+        job: Job,
+    },
+    JobProgress {
+        job: Job,
+    },
+    JobFinished {
+        job: Job,
+    },
+    /// A transaction not yet in DB has been fetched or received from Powens.
+    NewTransaction {
+        transaction: Box<Transaction>,
+    },
+    /// AI guessing result of a transaction.
+    TransactionClassified {
+        id: u64,
+        wording: String,
+        categories: Vec<String>,
+    },
+    /// Accounts have been refreshed from Powens, with the accounts added, deleted or disabled.
+    AccountsRefreshed {
+        report: AccountsRefreshReport,
+    },
+    /// An error recorded in a sync run, the run continues.
+    SyncError {
+        id_sync_run: u64,
+        message: String,
+    },
+    /// The server is shutting down, event streams end after this event.
+    Shutdown,
+}
+
+impl Event {
+    /// Name of the event, used as SSE event type.
+    pub fn name(&self) -> &'static str {
+        self.into()
+    }
+}
+
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BUS_CAPACITY);
+        Self { sender }
+    }
+
+    /// Publish an event to the current subscribers, if any.
+    pub fn publish(&self, event: Event) {
+        // an error only means that nobody is listening
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    /// End the event streams, so that graceful shutdown doesn't wait for them.
+    pub fn close(&self) {
+        self.publish(Event::Shutdown);
+    }
+}
@@ -9,7 +9,9 @@ use std::fs;
 use tracing::{info};
 use tracing::log::debug;
 use crate::app_state::AppState;
-use crate::db::TransactionExtras;
+use crate::db::{ChangeSource, SyncRun, SyncTrigger, TransactionExtras};
+use crate::events::Event;
+use crate::jobs::{JobHandle, JobKind};
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct SimplifiedTransaction {
@@ -30,6 +32,14 @@ impl From<&Transaction> for SimplifiedTransaction {
     }
 }
 
+pub const INCOME_PROMPT_FILE: &str = "ai-prompts/income.json";
+pub const EXPENSES_PROMPT_FILE: &str = "ai-prompts/expenses.json";
+
+/// Read an AI prompt file, or its `.example` file if it does not exist.
+pub fn read_ai_prompt_file(file_path: &str) -> std::io::Result<String> {
+    fs::read_to_string(file_path).or_else(|_| fs::read_to_string(format!("{file_path}.example")))
+}
+
 const PROMPT: &str = r#"
 You are an expert transaction classifier designed to categorize financial transactions into predefined categories and subcategories.
 
@@ -83,11 +93,9 @@ pub async fn ai_guess_transaction_categories(
     }
 
     // load income.json & expenses.json
-    let income_json = fs::read_to_string("ai-prompts/income.json")
-        .unwrap_or_else(|_| fs::read_to_string("ai-prompts/income.json.example").unwrap());
+    let income_json = read_ai_prompt_file(INCOME_PROMPT_FILE).unwrap();
 
-    let expenses_json = fs::read_to_string("ai-prompts/expenses.json")
-        .unwrap_or_else(|_| fs::read_to_string("ai-prompts/expenses.json.example").unwrap());
+    let expenses_json = read_ai_prompt_file(EXPENSES_PROMPT_FILE).unwrap();
 
     // final prompt
     let system_prompt = PROMPT
@@ -115,10 +123,10 @@ pub async fn ai_guess_transaction_categories(
             .collect();
 
         // if the first string is "Expenses" or "Income", remove it
-        if let Some(category) = categories.get(0) {
-            if category == "Expenses" || category == "Income" {
-                categories.remove(0);
-            }
+        if let Some(category) = categories.first()
+            && (category == "Expenses" || category == "Income")
+        {
+            categories.remove(0);
         }
 
         debug!("Gemini return category: {:?}", categories);
@@ -128,11 +136,60 @@ pub async fn ai_guess_transaction_categories(
     Err("Failed to parse JSON".into())
 }
 
+/// Number of AI guessing results saved at once, so that a long run keeps its results on error.
+const AI_GUESS_SAVE_BATCH_SIZE: usize = 50;
+
+/**
+Queue a job running AI guessing on the given transactions, or on all transactions if None.
+
+The given sync run is finished with the job, returns the job ID.
+*/
+pub fn run_ai_guess_job(
+    app_state: AppState,
+    trigger: SyncTrigger,
+    transactions: Option<Vec<Transaction>>,
+    mut run: SyncRun,
+) -> u64 {
+    let mut job = app_state.job_manager.enqueue(JobKind::Classify, trigger);
+    if run.is_recorded() {
+        job.set_sync_run(run.id);
+    }
+    let id = job.id();
+
+    tokio::spawn(async move {
+        if job.wait_turn().await {
+            let transactions = transactions.unwrap_or_else(|| app_state.transaction_db.data());
+            let (ai_calls, error) =
+                run_ai_guess_on_transactions(app_state.clone(), transactions, &job).await;
+            run.ai_calls += ai_calls;
+            if let Some(e) = error {
+                run.add_error(&app_state.event_bus, "Error running AI guessing", e);
+            }
+        }
+        job.finish_with_sync_run(&app_state.sync_run_db, run);
+    });
+
+    id
+}
+
+/// Run AI guessing on all transactions without categories, returns the number of AI calls made,
+/// with the error which stopped it, if any.
 pub async fn run_ai_guess_on_all_transactions(
     app_state: AppState,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut transactions = app_state.transaction_db.data(); // this is a clone of Vec<Transaction> at this moment
+    job: &JobHandle,
+) -> (usize, Option<Box<dyn std::error::Error>>) {
+    let transactions = app_state.transaction_db.data(); // this is a clone of Vec<Transaction> at this moment
+    run_ai_guess_on_transactions(app_state, transactions, job).await
+}
 
+/// Run AI guessing on the given transactions, skipping those already having categories.
+/// Stops when the job is cancelled or on the first error, returns the number of AI calls made,
+/// also on error as they are billed, with the error if any.
+pub async fn run_ai_guess_on_transactions(
+    app_state: AppState,
+    mut transactions: Vec<Transaction>,
+    job: &JobHandle,
+) -> (usize, Option<Box<dyn std::error::Error>>) {
     // skip if transaction_extras exist & has categories
     transactions.retain(|t| {
         let extras = app_state.transaction_extras_db.find_by_id(t.id);
@@ -144,23 +201,68 @@ pub async fn run_ai_guess_on_all_transactions(
         transactions.len()
     );
 
+    let total = transactions.len();
+    let mut ai_calls: usize = 0;
+    let mut pending_extras: Vec<TransactionExtras> = Vec::new();
+    let mut error: Option<String> = None;
     for transaction in transactions {
+        if job.is_cancelled() {
+            info!(
+                "AI guessing cancelled after {} of {} transactions.",
+                ai_calls, total
+            );
+            break;
+        }
+        job.set_progress("classified", ai_calls, total);
+
         // do ai guessing
-        let categories = ai_guess_transaction_categories(&transaction).await?;
+        ai_calls += 1;
+        let categories = match ai_guess_transaction_categories(&transaction).await {
+            Ok(categories) => categories,
+            Err(e) => {
+                error = Some(e.to_string());
+                break;
+            }
+        };
 
-        // create new transaction_extras and save
-        let transaction_extras: TransactionExtras = TransactionExtras {
+        // create new transaction_extras, saved by batch
+        pending_extras.push(TransactionExtras {
             id: transaction.id,
-            categories,
+            categories: categories.clone(),
             tags: vec![],
-        };
-
-        app_state.transaction_extras_db.upsert(transaction_extras)?;
+        });
+        app_state.event_bus.publish(Event::TransactionClassified {
+            id: transaction.id,
+            wording: transaction.wording.clone(),
+            categories,
+        });
+        if pending_extras.len() >= AI_GUESS_SAVE_BATCH_SIZE
+            && let Err(e) = app_state.change_db.upsert_recorded(
+                &app_state.transaction_extras_db,
+                pending_extras.drain(..),
+                ChangeSource::Ai,
+            )
+        {
+            return (ai_calls, Some(e));
+        }
 
         // avoid rate limit if free tier
         // tokio::time::sleep(std::time::Duration::from_secs(4)).await;
     }
 
+    // save the remaining results, also when stopped by an error or a cancellation
+    if let Err(e) = app_state.change_db.upsert_recorded(
+        &app_state.transaction_extras_db,
+        pending_extras,
+        ChangeSource::Ai,
+    ) {
+        return (ai_calls, Some(e));
+    }
+    if let Some(error) = error {
+        return (ai_calls, Some(error.into()));
+    }
+
+    job.set_progress("classified", ai_calls, total);
     info!("AI guessing finished.");
-    Ok(())
+    (ai_calls, None)
 }
@@ -1,5 +1,49 @@
 mod transactions_handlers;
 mod accounts_handlers;
+mod webhooks_handlers;
+mod connections_handlers;
+mod investments_handlers;
+mod diagnostics_handlers;
+mod sync_runs_handlers;
+mod jobs_handlers;
+mod events_handlers;
+mod admin_handlers;
+mod changes_handlers;
+mod exports_handlers;
+
+use axum::body::Body;
+use axum::http::{Response, header};
+use serde::Serialize;
 
 pub use transactions_handlers::*;
 pub use accounts_handlers::*;
+pub use webhooks_handlers::*;
+pub use connections_handlers::*;
+pub use investments_handlers::*;
+pub use diagnostics_handlers::*;
+pub use sync_runs_handlers::*;
+pub use jobs_handlers::*;
+pub use events_handlers::*;
+pub use admin_handlers::*;
+pub use changes_handlers::*;
+pub use exports_handlers::*;
+
+fn json_response(status: u16, data: &impl Serialize) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_string_pretty(data).unwrap()))
+        .unwrap()
+}
+
+fn text_response(status: u16, body: impl Into<Body>) -> Response<Body> {
+    Response::builder().status(status).body(body.into()).unwrap()
+}
+
+/// ex: `not_found("Transaction", 1)` for `Transaction 1 not found`.
+fn not_found(what: &str, id: u64) -> Response<Body> {
+    Response::builder()
+        .status(404)
+        .body(Body::from(format!("{what} {id} not found")))
+        .unwrap()
+}
@@ -1,16 +1,249 @@
-use axum::extract::State;
+use axum::body::Body;
+use axum::extract::{Path, Query, State};
+use axum::http::{header, Response};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tracing::{error, info, warn};
+use super::{json_response, not_found};
 use crate::app_state::AppState;
-use crate::csv::{AccountCsv, VecToCsv};
+use crate::csv::{AccountCsv, BalanceCsv, VecToCsv};
+use crate::currency::CurrencyConverter;
+use crate::events::Event;
+use crate::powens::{Account, POWENS_DATETIME_FORMAT};
+
+/**
+Changes made by a refresh of the accounts.
+*/
+#[derive(Default, Debug, Clone, Serialize)]
+pub struct AccountsRefreshReport {
+    /// IDs of accounts which were not known before.
+    pub added: Vec<u64>,
+    /// IDs of accounts which are not found on the bank anymore, or not returned by Powens anymore.
+    pub deleted: Vec<u64>,
+    /// IDs of accounts which have been disabled by the user since the last refresh.
+    pub disabled: Vec<u64>,
+    /// Number of accounts which were already known and are updated.
+    pub updated: usize,
+}
 
 pub async fn list_accounts_handler(State(app_state): State<AppState>) -> String {
     serde_json::to_string_pretty(&app_state.account_db.data()).unwrap()
 }
 
-pub async fn accounts_to_csv_handler(State(app_state): State<AppState>) -> String {
-    let accounts_csv: Vec<AccountCsv> = (&app_state.account_db)
+#[derive(Deserialize)]
+pub struct AccountsToCsvParams {
+    /// Convert balances to BASE_CURRENCY.
+    convert: Option<bool>,
+}
+
+/**
+Total balance of the active accounts.
+*/
+#[derive(Default, Debug, Clone, Serialize)]
+pub struct AccountsTotals {
+    pub by_currency: BTreeMap<String, f64>,
+    /// Set if BASE_CURRENCY is configured.
+    pub base_currency: Option<String>,
+    /// Total converted to the base currency, excluding currencies without rate.
+    pub total: Option<f64>,
+    /// Currencies excluded from the total because they have no rate.
+    pub unconverted_currencies: Vec<String>,
+}
+
+pub async fn accounts_to_csv_handler(
+    Query(params): Query<AccountsToCsvParams>,
+    State(app_state): State<AppState>,
+) -> Response<Body> {
+    let converter = if params.convert.unwrap_or(false) {
+        match CurrencyConverter::required_from_env() {
+            Ok(converter) => Some(converter),
+            Err(e) => {
+                error!("Error loading currency rates: {}", e);
+                return Response::builder()
+                    .status(400)
+                    .body(Body::from(format!("Error loading currency rates: {e}")))
+                    .unwrap();
+            }
+        }
+    } else {
+        None
+    };
+
+    let accounts_csv: Vec<AccountCsv> = app_state
+        .account_db
         .data()
         .iter()
-        .map(|it| it.into())
+        .map(|it| {
+            let mut account_csv: AccountCsv = it.into();
+            if let Some(converter) = &converter
+                && !account_csv.convert(converter)
+            {
+                warn!(
+                    "No rate for currency {} of account {}, balance not converted.",
+                    account_csv.currency, it.id
+                );
+            }
+            account_csv
+        })
         .collect();
-    accounts_csv.to_csv()
+
+    Response::builder()
+        .status(200)
+        .body(Body::from(accounts_csv.to_csv()))
+        .unwrap()
+}
+
+pub async fn accounts_totals_handler(State(app_state): State<AppState>) -> Response<Body> {
+    let converter = match CurrencyConverter::from_env() {
+        Ok(converter) => converter,
+        Err(e) => {
+            error!("Error loading currency rates: {}", e);
+            return Response::builder()
+                .status(500)
+                .body(Body::from(format!("Error loading currency rates: {e}")))
+                .unwrap();
+        }
+    };
+
+    let mut totals = AccountsTotals::default();
+    for account in app_state.account_db.data() {
+        if account.deleted.is_some() || account.disabled.is_some() {
+            continue;
+        }
+        *totals
+            .by_currency
+            .entry(account.currency.id.clone())
+            .or_default() += account.balance;
+    }
+
+    if let Some(converter) = converter {
+        let mut total = 0.0;
+        for (currency, balance) in totals.by_currency.iter() {
+            match converter.convert(*balance, currency) {
+                Some(converted) => total += converted,
+                None => totals.unconverted_currencies.push(currency.clone()),
+            }
+        }
+        totals.total = Some(total);
+        totals.base_currency = Some(converter.base);
+    }
+
+    json_response(200, &totals)
+}
+
+pub async fn list_account_balances_handler(
+    Path(id): Path<u64>,
+    State(app_state): State<AppState>,
+) -> String {
+    serde_json::to_string_pretty(&app_state.balance_snapshot_db.find_by_account(id)).unwrap()
+}
+
+pub async fn account_balances_to_csv_handler(
+    Path(id): Path<u64>,
+    State(app_state): State<AppState>,
+) -> Response<Body> {
+    let Some(account) = app_state.account_db.find_by_id(id) else {
+        return not_found("Account", id);
+    };
+
+    let balances_csv: Vec<BalanceCsv> = app_state
+        .balance_snapshot_db
+        .find_by_account(id)
+        .iter()
+        .map(|it| {
+            let mut balance_csv: BalanceCsv = it.into();
+            balance_csv.set_account(&account);
+            balance_csv
+        })
+        .collect();
+
+    Response::builder()
+        .status(200)
+        .header(header::CONTENT_TYPE, "text/csv")
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"balances {}.csv\"", account.name),
+        )
+        .body(Body::from(balances_csv.to_csv()))
+        .unwrap()
+}
+
+/// Refresh accounts from Powens, after the running job is finished.
+pub async fn fetch_accounts_from_powens_handler(State(app_state): State<AppState>) -> Response<Body> {
+    let _write_guard = app_state.job_manager.lock_writes().await;
+    match refresh_accounts_from_powens(&app_state).await {
+        Ok(report) => json_response(200, &report),
+        Err(e) => {
+            error!("Error refreshing accounts: {:#?}", e);
+            Response::builder()
+                .status(502)
+                .body(Body::from("Error refreshing accounts"))
+                .unwrap()
+        }
+    }
+}
+
+/**
+Fetch accounts from Powens and merge them into the accounts DB, and record their balances.
+
+Accounts which are not returned by Powens anymore are kept, marked as deleted. The report is also
+published as an `AccountsRefreshed` event, for the refreshes made by background syncs.
+The caller must hold the write lock, ex: be a running job, as the accounts are read then replaced.
+*/
+pub async fn refresh_accounts_from_powens(
+    app_state: &AppState,
+) -> Result<AccountsRefreshReport, Box<dyn std::error::Error>> {
+    info!("Refreshing accounts from Powens.");
+
+    let fetched = app_state.powens_api.get_accounts().await?;
+    let existing = app_state.account_db.data();
+    let mut report = AccountsRefreshReport::default();
+
+    for account in fetched.iter() {
+        match existing.iter().find(|it| it.id == account.id) {
+            None => report.added.push(account.id),
+            Some(previous) => {
+                if account.deleted.is_some() && previous.deleted.is_none() {
+                    report.deleted.push(account.id);
+                } else if account.disabled.is_some() && previous.disabled.is_none() {
+                    report.disabled.push(account.id);
+                } else {
+                    report.updated += 1;
+                }
+            }
+        }
+    }
+
+    // keep the accounts which disappeared from Powens, flagged as deleted
+    let now = Utc::now().format(POWENS_DATETIME_FORMAT).to_string();
+    let disappeared: Vec<Account> = existing
+        .into_iter()
+        .filter(|it| !fetched.iter().any(|fetched| fetched.id == it.id))
+        .map(|mut it| {
+            if it.deleted.is_none() {
+                report.deleted.push(it.id);
+                it.deleted = Some(now.clone());
+            }
+            it
+        })
+        .collect();
+
+    let active: Vec<Account> = fetched
+        .iter()
+        .filter(|it| it.deleted.is_none() && it.disabled.is_none())
+        .cloned()
+        .collect();
+    app_state.balance_snapshot_db.record_accounts(&active)?;
+
+    let mut accounts = fetched;
+    accounts.extend(disappeared);
+    accounts.sort_by_key(|it| it.id);
+    app_state.account_db.save(accounts)?;
+
+    info!("Accounts refreshed: {:?}", report);
+    app_state.event_bus.publish(Event::AccountsRefreshed {
+        report: report.clone(),
+    });
+    Ok(report)
 }
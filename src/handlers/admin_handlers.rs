@@ -0,0 +1,193 @@
+use crate::app_state::AppState;
+use crate::backup;
+use crate::db::{Encryptable, is_key_file_used, rotate_encryption_key};
+use axum::body::{Body, Bytes};
+use axum::extract::{Request, State};
+use axum::http::{Response, header};
+use axum::middleware::Next;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tracing::{error, warn};
+
+/**
+Reject the requests to the admin routes which don't have `Authorization: Bearer <ADMIN_TOKEN>`.
+
+The admin routes are disabled while `ADMIN_TOKEN` is not configured.
+*/
+pub async fn require_admin_token(request: Request, next: Next) -> Response<Body> {
+    let token = match dotenv::var("ADMIN_TOKEN") {
+        Ok(token) if !token.is_empty() => token,
+        _ => {
+            warn!(
+                "Rejected {} {}, ADMIN_TOKEN is not configured.",
+                request.method(),
+                request.uri().path()
+            );
+            return Response::builder()
+                .status(403)
+                .body(Body::from(
+                    "Admin routes are disabled, ADMIN_TOKEN is not configured",
+                ))
+                .unwrap();
+        }
+    };
+    let given = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|it| it.to_str().ok())
+        .and_then(|it| it.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    if !is_admin_token_valid(&token, given) {
+        warn!(
+            "Rejected {} {} with invalid admin token.",
+            request.method(),
+            request.uri().path()
+        );
+        return Response::builder()
+            .status(401)
+            .header(header::WWW_AUTHENTICATE, "Bearer")
+            .body(Body::from("Invalid admin token"))
+            .unwrap();
+    }
+
+    next.run(request).await
+}
+
+/// Compare the tokens in constant time, through their HMAC, so the token can't be guessed by timing.
+fn is_admin_token_valid(token: &str, given: &str) -> bool {
+    let hmac = |value: &str| {
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"admin-token").unwrap();
+        mac.update(value.as_bytes());
+        mac
+    };
+    !token.is_empty()
+        && hmac(given)
+            .verify_slice(&hmac(token).finalize().into_bytes())
+            .is_ok()
+}
+
+/// Download a tar.gz archive of all DBs and of the active AI prompts, encrypted if the DBs are.
+pub async fn backup_handler(State(app_state): State<AppState>) -> Response<Body> {
+    match backup::create_backup(&app_state) {
+        Ok(archive) => Response::builder()
+            .status(200)
+            .header(
+                header::CONTENT_TYPE,
+                if archive.encrypted {
+                    "application/octet-stream"
+                } else {
+                    "application/gzip"
+                },
+            )
+            .header(
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", archive.file_name),
+            )
+            .body(Body::from(archive.content))
+            .unwrap(),
+        Err(e) => {
+            error!("Error creating backup: {:#?}", e);
+            Response::builder()
+                .status(500)
+                .body(Body::from(format!("Error creating backup: {e}")))
+                .unwrap()
+        }
+    }
+}
+
+/**
+Restore an archive downloaded from `/admin/backup`, sent as request body.
+
+The archive is validated before anything is replaced, an invalid archive returns 400.
+*/
+pub async fn restore_handler(State(app_state): State<AppState>, archive: Bytes) -> Response<Body> {
+    let backup = match backup::read_backup(&archive) {
+        Ok(backup) => backup,
+        Err(e) => {
+            return Response::builder()
+                .status(400)
+                .body(Body::from(format!("Invalid backup: {e}")))
+                .unwrap();
+        }
+    };
+
+    match backup::restore_backup(&app_state, backup).await {
+        Ok(()) => Response::builder()
+            .status(200)
+            .body(Body::from("Backup restored"))
+            .unwrap(),
+        Err(e) => Response::builder()
+            .status(500)
+            .body(Body::from(format!("Error restoring backup: {e}")))
+            .unwrap(),
+    }
+}
+
+/**
+Re-encrypt the DBs with a new random key, written to `DB_ENCRYPTION_KEY_FILE`.
+
+Only available with a key file, as the new key must survive a restart. Waits for the running job to
+finish and keeps the others waiting during the rotation.
+*/
+pub async fn rotate_encryption_key_handler(State(app_state): State<AppState>) -> Response<Body> {
+    if !is_key_file_used() {
+        return Response::builder()
+            .status(409)
+            .body(Body::from(
+                "Key rotation requires DB_ENCRYPTION_KEY_FILE, use the rotate-encryption-key command instead",
+            ))
+            .unwrap();
+    }
+
+    let _write_guard = app_state.job_manager.lock_writes().await;
+    let dbs: [&dyn Encryptable; 12] = [
+        &app_state.account_db,
+        &app_state.balance_snapshot_db,
+        &app_state.change_db,
+        &app_state.export_batch_db,
+        &app_state.investment_db,
+        &app_state.investment_sync_state_db,
+        &app_state.market_order_db,
+        &app_state.reconciliation_db,
+        &app_state.sync_run_db,
+        &app_state.sync_state_db,
+        &app_state.transaction_db,
+        &app_state.transaction_extras_db,
+    ];
+    // the new key is written to the key file
+    match rotate_encryption_key(&dbs, |_, _| {}) {
+        Ok(_) => Response::builder()
+            .status(200)
+            .body(Body::from("Encryption key rotated"))
+            .unwrap(),
+        Err(e) => {
+            error!("Error rotating encryption key: {:#?}", e);
+            Response::builder()
+                .status(500)
+                .body(Body::from(format!("Error rotating encryption key: {e}")))
+                .unwrap()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_token_is_valid() {
+        assert!(is_admin_token_valid("secret", "secret"));
+    }
+
+    #[test]
+    fn other_token_is_invalid() {
+        assert!(!is_admin_token_valid("secret", "secreT"));
+        assert!(!is_admin_token_valid("secret", "secret "));
+        assert!(!is_admin_token_valid("secret", ""));
+    }
+
+    #[test]
+    fn empty_token_is_never_valid() {
+        assert!(!is_admin_token_valid("", ""));
+    }
+}
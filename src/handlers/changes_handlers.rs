@@ -0,0 +1,138 @@
+use super::{json_response, not_found};
+use crate::app_state::AppState;
+use crate::db::{Change, ChangeRecord, ChangeSource, TransactionExtras};
+use axum::body::Body;
+use axum::extract::{Json, Path, Query, State};
+use axum::http::Response;
+use serde::Deserialize;
+use tracing::error;
+
+#[derive(Deserialize)]
+pub struct ExtrasParams {
+    categories: Vec<String>,
+    tags: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct RevertExtrasParams {
+    /// ID of the change which the extras are reverted to, as they were right after it.
+    change: u64,
+}
+
+/**
+List the changes of a transaction and of its extras, oldest first.
+
+Changes are recorded from their source: Powens sync, AI guessing or manual edits.
+*/
+pub async fn transaction_history_handler(
+    Path(id): Path<u64>,
+    State(app_state): State<AppState>,
+) -> Response<Body> {
+    let mut changes = app_state
+        .change_db
+        .find_by_record(ChangeRecord::Transaction, id);
+    changes.extend(
+        app_state
+            .change_db
+            .find_by_record(ChangeRecord::TransactionExtras, id),
+    );
+    if changes.is_empty() && app_state.transaction_db.find_by_id(id).is_none() {
+        return not_found("Transaction", id);
+    }
+
+    changes.sort_by_key(|it| it.id);
+    json_response(200, &changes)
+}
+
+/**
+Replace the categories and tags of a transaction, recorded as a manual edit.
+
+Waits for the running job to finish, so the edit is not overwritten by AI guessing.
+*/
+pub async fn update_transaction_extras_handler(
+    Path(id): Path<u64>,
+    State(app_state): State<AppState>,
+    Json(params): Json<ExtrasParams>,
+) -> Response<Body> {
+    let _write_guard = app_state.job_manager.lock_writes().await;
+    if app_state.transaction_db.find_by_id(id).is_none() {
+        return not_found("Transaction", id);
+    }
+
+    let extras = TransactionExtras {
+        id,
+        categories: params.categories,
+        tags: params.tags,
+    };
+    if let Err(e) = app_state.change_db.upsert_recorded(
+        &app_state.transaction_extras_db,
+        [extras.clone()],
+        ChangeSource::Manual,
+    ) {
+        return server_error("Error saving transaction extras", e);
+    }
+    json_response(200, &extras)
+}
+
+/**
+Revert the extras of a transaction to how they were right after a change of the history.
+
+The revert is recorded as a manual edit, the extras are deleted if they did not exist at that time.
+Returns the reverted extras, null if deleted.
+*/
+pub async fn revert_transaction_extras_handler(
+    Path(id): Path<u64>,
+    Query(params): Query<RevertExtrasParams>,
+    State(app_state): State<AppState>,
+) -> Response<Body> {
+    let _write_guard = app_state.job_manager.lock_writes().await;
+    let change: Change = match app_state.change_db.find_by_id(params.change) {
+        Some(change)
+            if change.record == ChangeRecord::TransactionExtras && change.id_record == id =>
+        {
+            change
+        }
+        _ => {
+            return Response::builder()
+                .status(404)
+                .body(Body::from(format!(
+                    "Change {} of transaction {id} extras not found",
+                    params.change
+                )))
+                .unwrap();
+        }
+    };
+
+    let version: Option<TransactionExtras> = match change.version_after() {
+        Ok(version) => version,
+        Err(e) => return server_error("Error reading transaction extras of the change", e),
+    };
+
+    let result = match &version {
+        Some(extras) => app_state
+            .change_db
+            .upsert_recorded(
+                &app_state.transaction_extras_db,
+                [extras.clone()],
+                ChangeSource::Manual,
+            )
+            .map(|_| ()),
+        None => app_state.change_db.delete_recorded(
+            &app_state.transaction_extras_db,
+            id,
+            ChangeSource::Manual,
+        ),
+    };
+    if let Err(e) = result {
+        return server_error("Error saving transaction extras", e);
+    }
+    json_response(200, &version)
+}
+
+fn server_error(message: &str, e: Box<dyn std::error::Error>) -> Response<Body> {
+    error!("{}: {:#?}", message, e);
+    Response::builder()
+        .status(500)
+        .body(Body::from(message.to_string()))
+        .unwrap()
+}
@@ -0,0 +1,142 @@
+use crate::app_state::AppState;
+use crate::config::env_or_default;
+use crate::powens::{Account, Connection, POWENS_DATETIME_FORMAT};
+use axum::body::Body;
+use axum::extract::{Path, State};
+use axum::http::{Response, header};
+use chrono::{Duration, NaiveDateTime, Utc};
+use serde::Serialize;
+use tracing::{error, info, warn};
+
+/// Default age after which an account without successful update is flagged as stale.
+const DEFAULT_ACCOUNT_STALE_AFTER_HOURS: i64 = 48;
+
+/**
+A Powens connection with its health status and the health of its accounts.
+*/
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionHealth {
+    #[serde(flatten)]
+    pub connection: Connection,
+    /// True if the connection or one of its accounts needs a user action or is not synced anymore.
+    pub needs_attention: bool,
+    pub accounts: Vec<AccountHealth>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AccountHealth {
+    pub id: u64,
+    pub name: String,
+    pub last_update: String,
+    pub error: Option<String>,
+    /// True if the last successful update is older than `ACCOUNT_STALE_AFTER_HOURS`.
+    pub stale: bool,
+    pub needs_attention: bool,
+}
+
+impl AccountHealth {
+    fn new(account: &Account, stale_after: Duration) -> Self {
+        let stale =
+            match NaiveDateTime::parse_from_str(&account.last_update, POWENS_DATETIME_FORMAT) {
+                Ok(last_update) => Utc::now() - last_update.and_utc() > stale_after,
+                // never successfully updated
+                Err(_) => true,
+            };
+
+        AccountHealth {
+            id: account.id,
+            name: account.name.clone(),
+            last_update: account.last_update.clone(),
+            error: account.error.clone(),
+            stale,
+            needs_attention: stale || account.error.is_some(),
+        }
+    }
+}
+
+pub async fn list_connections_handler(State(app_state): State<AppState>) -> Response<Body> {
+    let connections = match app_state.powens_api.get_connections().await {
+        Ok(connections) => connections,
+        Err(e) => {
+            error!("Error getting connections from Powens: {:#?}", e);
+            return Response::builder()
+                .status(502)
+                .body(Body::from("Error getting connections from Powens"))
+                .unwrap();
+        }
+    };
+
+    let stale_after = Duration::hours(
+        env_or_default(
+            "ACCOUNT_STALE_AFTER_HOURS",
+            DEFAULT_ACCOUNT_STALE_AFTER_HOURS,
+        )
+        .unwrap_or(DEFAULT_ACCOUNT_STALE_AFTER_HOURS),
+    );
+    let accounts = app_state.account_db.data();
+
+    let connections_health: Vec<ConnectionHealth> = connections
+        .into_iter()
+        .map(|connection| {
+            let accounts_health: Vec<AccountHealth> = accounts
+                .iter()
+                // deleted or disabled accounts are not synced anymore, they are expected to be outdated
+                .filter(|it| {
+                    it.id_connection == connection.id
+                        && it.deleted.is_none()
+                        && it.disabled.is_none()
+                })
+                .map(|it| AccountHealth::new(it, stale_after))
+                .collect();
+
+            let needs_attention = !connection.active
+                || connection.state.is_some()
+                || connection.error.is_some()
+                || accounts_health.iter().any(|it| it.needs_attention);
+            if needs_attention {
+                warn!(
+                    "Connection {} needs attention, state: {:?}, error message: {:?}",
+                    connection.id, connection.state, connection.error_message
+                );
+            }
+
+            ConnectionHealth {
+                connection,
+                needs_attention,
+                accounts: accounts_health,
+            }
+        })
+        .collect();
+
+    Response::builder()
+        .status(200)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(
+            serde_json::to_string_pretty(&connections_health).unwrap(),
+        ))
+        .unwrap()
+}
+
+pub async fn sync_connection_handler(
+    Path(id): Path<u64>,
+    State(app_state): State<AppState>,
+) -> Response<Body> {
+    info!("Requesting Powens to sync connection {}.", id);
+
+    match app_state.powens_api.sync_connection(id).await {
+        Ok(connection) => Response::builder()
+            .status(200)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                serde_json::to_string_pretty(&connection).unwrap(),
+            ))
+            .unwrap(),
+        Err(e) => {
+            error!("Error syncing connection {}: {:#?}", id, e);
+            Response::builder()
+                .status(502)
+                .body(Body::from(format!("Error syncing connection {id}")))
+                .unwrap()
+        }
+    }
+}
@@ -0,0 +1,29 @@
+use crate::app_state::AppState;
+use crate::powens::{SchemaDrift, SchemaDriftReport};
+use axum::extract::State;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SchemaDiagnostics {
+    /// Unmodelled fields returned by Powens since the start of the app.
+    pub observed: SchemaDriftReport,
+    /// Unmodelled fields of the accounts and transactions saved in DB.
+    pub stored: SchemaDriftReport,
+}
+
+pub async fn schema_diagnostics_handler(State(app_state): State<AppState>) -> String {
+    let accounts = app_state.account_db.data();
+    let transactions = app_state.transaction_db.data();
+
+    let mut stored = SchemaDrift::report_of("Account", accounts.iter().map(|it| &it.extra));
+    stored.extend(SchemaDrift::report_of(
+        "Transaction",
+        transactions.iter().map(|it| &it.extra),
+    ));
+
+    let diagnostics = SchemaDiagnostics {
+        observed: app_state.powens_api.schema_drift().report(),
+        stored,
+    };
+    serde_json::to_string_pretty(&diagnostics).unwrap()
+}
@@ -0,0 +1,38 @@
+use crate::app_state::AppState;
+use crate::events::Event;
+use axum::extract::State;
+use axum::response::sse::{Event as SseEvent, KeepAlive, Sse};
+use std::convert::Infallible;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::{Stream, StreamExt};
+use tracing::{error, info};
+
+/**
+Stream the events of the connector with Server-Sent Events: jobs start, progress and end, new
+transactions, AI guessing results and sync errors.
+
+Each SSE event has the event type as name, and the JSON of the event as data. A `lagged` event is
+sent when the client is too slow and some events have been dropped.
+*/
+pub async fn events_handler(
+    State(app_state): State<AppState>,
+) -> Sse<impl Stream<Item = Result<SseEvent, Infallible>>> {
+    info!("New events subscriber.");
+    let stream = BroadcastStream::new(app_state.event_bus.subscribe())
+        .take_while(|it| !matches!(it, Ok(Event::Shutdown)))
+        .filter_map(|it| match it {
+            Ok(event) => match SseEvent::default().event(event.name()).json_data(&event) {
+                Ok(sse_event) => Some(Ok(sse_event)),
+                Err(e) => {
+                    error!("Error serializing {} event, skipped: {}", event.name(), e);
+                    None
+                }
+            },
+            Err(BroadcastStreamRecvError::Lagged(count)) => Some(Ok(SseEvent::default()
+                .event("lagged")
+                .data(count.to_string()))),
+        });
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
@@ -0,0 +1,142 @@
+use super::{json_response, not_found};
+use crate::app_state::AppState;
+use crate::db::{ExportBatch, ExportedTransaction};
+use axum::body::Body;
+use axum::extract::{Path, State};
+use axum::http::{Response, header};
+use chrono::Utc;
+use serde::Serialize;
+use tracing::error;
+
+#[derive(Serialize)]
+pub struct ExportBatchSummary {
+    id: u64,
+    exported_at: String,
+    converted: bool,
+    transactions: usize,
+    rolled_back_at: Option<String>,
+}
+
+impl From<&ExportBatch> for ExportBatchSummary {
+    fn from(batch: &ExportBatch) -> Self {
+        ExportBatchSummary {
+            id: batch.id,
+            exported_at: batch.exported_at.clone(),
+            converted: batch.converted,
+            transactions: batch.transactions.len(),
+            rolled_back_at: batch.rolled_back_at.clone(),
+        }
+    }
+}
+
+/// A transaction which changed after its latest export.
+#[derive(Serialize)]
+pub struct ChangedSinceExport {
+    id_export_batch: u64,
+    exported: ExportedTransaction,
+    /// None if the transaction has been deleted, ex: a coming transaction reconciled.
+    current: Option<ExportedTransaction>,
+}
+
+/// List the export batches, newest first, without their transactions.
+pub async fn list_export_batches_handler(State(app_state): State<AppState>) -> String {
+    let summaries: Vec<ExportBatchSummary> = app_state
+        .export_batch_db
+        .data()
+        .iter()
+        .rev()
+        .map(ExportBatchSummary::from)
+        .collect();
+    serde_json::to_string_pretty(&summaries).unwrap()
+}
+
+pub async fn get_export_batch_handler(
+    Path(id): Path<u64>,
+    State(app_state): State<AppState>,
+) -> Response<Body> {
+    match app_state.export_batch_db.find_by_id(id) {
+        Some(batch) => json_response(200, &batch),
+        None => not_found("Export batch", id),
+    }
+}
+
+/**
+Download again the CSV of an export batch, as it was exported.
+*/
+pub async fn export_batch_to_csv_handler(
+    Path(id): Path<u64>,
+    State(app_state): State<AppState>,
+) -> Response<Body> {
+    let Some(batch) = app_state.export_batch_db.find_by_id(id) else {
+        return not_found("Export batch", id);
+    };
+    Response::builder()
+        .status(200)
+        .header(header::CONTENT_TYPE, "text/csv")
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!(
+                "attachment; filename=\"transactions export {}.csv\"",
+                batch.id
+            ),
+        )
+        .header("X-Export-Batch", batch.id)
+        .body(Body::from(batch.csv))
+        .unwrap()
+}
+
+/**
+Roll back an export batch, its transactions are exported again by `since=last-export`.
+
+Returns 409 if the batch is already rolled back.
+*/
+pub async fn roll_back_export_batch_handler(
+    Path(id): Path<u64>,
+    State(app_state): State<AppState>,
+) -> Response<Body> {
+    let _write_guard = app_state.job_manager.lock_writes().await;
+    let Some(mut batch) = app_state.export_batch_db.find_by_id(id) else {
+        return not_found("Export batch", id);
+    };
+    if batch.rolled_back_at.is_some() {
+        return json_response(409, &ExportBatchSummary::from(&batch));
+    }
+
+    batch.rolled_back_at = Some(Utc::now().to_rfc3339());
+    if let Err(e) = app_state.export_batch_db.upsert(batch.clone()) {
+        error!("Error rolling back export batch {}: {:#?}", id, e);
+        return Response::builder()
+            .status(500)
+            .body(Body::from("Error rolling back export batch"))
+            .unwrap();
+    }
+    json_response(200, &ExportBatchSummary::from(&batch))
+}
+
+/**
+List the transactions which changed after their latest export: updated by Powens, or with other
+categories or tags.
+*/
+pub async fn changed_since_export_handler(State(app_state): State<AppState>) -> String {
+    let mut changed: Vec<ChangedSinceExport> = app_state
+        .export_batch_db
+        .latest_exports()
+        .into_values()
+        .filter_map(|(id_export_batch, exported)| {
+            let current = app_state
+                .transaction_db
+                .find_by_id(exported.id)
+                .map(|transaction| {
+                    let extras = app_state.transaction_extras_db.find_by_id(transaction.id);
+                    ExportedTransaction::new(&transaction, extras.as_ref())
+                });
+            (current.as_ref() != Some(&exported)).then_some(ChangedSinceExport {
+                id_export_batch,
+                exported,
+                current,
+            })
+        })
+        .collect();
+    changed.sort_by_key(|it| (it.id_export_batch, it.exported.id));
+    serde_json::to_string_pretty(&changed).unwrap()
+}
@@ -0,0 +1,204 @@
+use super::json_response;
+use crate::app_state::AppState;
+use crate::csv::{HoldingCsv, TradeCsv, VecToCsv};
+use crate::db::{InvestmentSyncState, SyncRunKind, SyncTrigger};
+use crate::jobs::JobKind;
+use crate::powens::{Account, Investment, MarketOrder, PowensError};
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::Response;
+use chrono::Utc;
+use tracing::info;
+
+pub async fn list_investments_handler(State(app_state): State<AppState>) -> String {
+    serde_json::to_string_pretty(&app_state.investment_db.data()).unwrap()
+}
+
+pub async fn investments_to_csv_handler(State(app_state): State<AppState>) -> String {
+    let account_db = &app_state.account_db;
+    let holdings_csv: Vec<HoldingCsv> = app_state
+        .investment_db
+        .data()
+        .iter()
+        .map(|it| {
+            let mut holding_csv: HoldingCsv = it.into();
+            if let Some(account) = account_db.find_by_id(it.id_account) {
+                holding_csv.set_account(&account);
+            }
+            holding_csv
+        })
+        .collect();
+    holdings_csv.to_csv()
+}
+
+pub async fn list_market_orders_handler(State(app_state): State<AppState>) -> String {
+    serde_json::to_string_pretty(&app_state.market_order_db.data()).unwrap()
+}
+
+/**
+Export executed market orders as Maybe trades.
+
+Ticker and name missing on an order are taken from the investment of the same security.
+*/
+pub async fn trades_to_csv_handler(State(app_state): State<AppState>) -> String {
+    let account_db = &app_state.account_db;
+    let investments = app_state.investment_db.data();
+    let trades_csv: Vec<TradeCsv> = app_state
+        .market_order_db
+        .data()
+        .iter()
+        .filter(|it| it.is_executed())
+        .map(|it| {
+            let mut trade_csv: TradeCsv = it.into();
+            if let Some(account) = account_db.find_by_id(it.id_invest_account) {
+                trade_csv.set_account(&account);
+            }
+            if let Some(investment) = investments
+                .iter()
+                .find(|inv| it.id_security.is_some() && inv.id_security == it.id_security)
+            {
+                trade_csv.set_investment(investment);
+            }
+            trade_csv
+        })
+        .collect();
+    trades_csv.to_csv()
+}
+
+/// Start an investments fetch job, answer 409 with the queued or running one if any.
+pub async fn fetch_investments_from_powens_handler(
+    State(app_state): State<AppState>,
+) -> Response<Body> {
+    let mut job = match app_state
+        .job_manager
+        .try_start(JobKind::FetchInvestments, SyncTrigger::Http)
+    {
+        Ok(job) => job,
+        Err(job) => return json_response(409, &job),
+    };
+    let id = job.id();
+
+    tokio::spawn(async move {
+        if !job.wait_turn().await {
+            return;
+        }
+
+        let mut run = app_state
+            .sync_run_db
+            .start_or_unrecorded(SyncRunKind::Fetch, SyncTrigger::Http);
+        if run.is_recorded() {
+            job.set_sync_run(run.id);
+        }
+
+        match fetch_investments_from_powens(&app_state).await {
+            Ok(errors) => {
+                for (context, e) in errors {
+                    run.add_error(&app_state.event_bus, &context, e);
+                }
+            }
+            Err(e) => run.add_error(&app_state.event_bus, "Error fetching investments", e),
+        }
+
+        job.finish_with_sync_run(&app_state.sync_run_db, run);
+    });
+
+    Response::builder()
+        .status(200)
+        .body(Body::from(format!("Job {id} started")))
+        .unwrap()
+}
+
+/**
+Fetch investments and market orders of all investment accounts (market, PEA, life insurance...)
+and replace the saved ones.
+
+If an account's investments or market orders can not be fetched, the previously saved ones are kept,
+and the error is returned with its context. The fetch time of each account is saved in its
+`InvestmentSyncState`, only for what has been fetched.
+The caller must hold the write lock, ex: be a running job, as the saved ones are read then replaced.
+*/
+pub async fn fetch_investments_from_powens(
+    app_state: &AppState,
+) -> Result<Vec<(String, PowensError)>, Box<dyn std::error::Error>> {
+    let accounts = active_investment_accounts(app_state);
+
+    info!("Fetching investments of {} accounts from Powens.", accounts.len());
+
+    let mut investments: Vec<Investment> = app_state.investment_db.data();
+    let mut market_orders: Vec<MarketOrder> = app_state.market_order_db.data();
+    let mut sync_states: Vec<InvestmentSyncState> = Vec::new();
+    let mut errors: Vec<(String, PowensError)> = Vec::new();
+    for account in accounts {
+        let mut sync_state = app_state
+            .investment_sync_state_db
+            .find_by_id(account.id)
+            .unwrap_or(InvestmentSyncState {
+                id: account.id,
+                ..Default::default()
+            });
+
+        match app_state.powens_api.get_investments(account.id).await {
+            Ok(account_investments) => {
+                info!(
+                    "Fetched {} investments of account {}.",
+                    account_investments.len(),
+                    account.id
+                );
+                investments.retain(|it| it.id_account != account.id);
+                investments.extend(account_investments);
+                sync_state.investments_fetched_at = Some(Utc::now().to_rfc3339());
+            }
+            Err(e) => errors.push((
+                format!(
+                    "Error fetching investments of account {}, keeping saved ones",
+                    account.id
+                ),
+                e,
+            )),
+        }
+
+        match app_state.powens_api.get_market_orders(account.id).await {
+            Ok(account_market_orders) => {
+                info!(
+                    "Fetched {} market orders of account {}.",
+                    account_market_orders.len(),
+                    account.id
+                );
+                market_orders.retain(|it| it.id_invest_account != account.id);
+                market_orders.extend(account_market_orders);
+                sync_state.market_orders_fetched_at = Some(Utc::now().to_rfc3339());
+            }
+            Err(e) => errors.push((
+                format!(
+                    "Error fetching market orders of account {}, keeping saved ones",
+                    account.id
+                ),
+                e,
+            )),
+        }
+        sync_states.push(sync_state);
+    }
+
+    investments.sort_by_key(|it| (it.id_account, it.id));
+    app_state.investment_db.save(investments)?;
+    info!("Investments saved.");
+
+    market_orders.sort_by(|a, b| (&a.date, a.id).cmp(&(&b.date, b.id)));
+    app_state.market_order_db.save(market_orders)?;
+    info!("Market orders saved.");
+
+    // saved last, so that a fetch is only recorded once its data is saved
+    app_state.investment_sync_state_db.upsert_many(sync_states)?;
+
+    Ok(errors)
+}
+
+/// Investment accounts (market, PEA, life insurance...) which are neither deleted nor disabled.
+pub fn active_investment_accounts(app_state: &AppState) -> Vec<Account> {
+    app_state
+        .account_db
+        .data()
+        .into_iter()
+        .filter(|it| it.type_field.is_investment() && it.deleted.is_none() && it.disabled.is_none())
+        .collect()
+}
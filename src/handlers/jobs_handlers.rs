@@ -0,0 +1,32 @@
+use super::{json_response, not_found};
+use crate::app_state::AppState;
+use axum::body::Body;
+use axum::extract::{Path, State};
+use axum::http::Response;
+
+/// List the jobs, newest first.
+pub async fn list_jobs_handler(State(app_state): State<AppState>) -> String {
+    serde_json::to_string_pretty(&app_state.job_manager.list()).unwrap()
+}
+
+pub async fn get_job_handler(
+    Path(id): Path<u64>,
+    State(app_state): State<AppState>,
+) -> Response<Body> {
+    match app_state.job_manager.find_by_id(id) {
+        Some(job) => json_response(200, &job),
+        None => not_found("Job", id),
+    }
+}
+
+/// Ask a queued or running job to stop, it stops at its next checkpoint.
+pub async fn cancel_job_handler(
+    Path(id): Path<u64>,
+    State(app_state): State<AppState>,
+) -> Response<Body> {
+    match app_state.job_manager.cancel(id) {
+        Some(Ok(job)) => json_response(202, &job),
+        Some(Err(job)) => json_response(409, &job),
+        None => not_found("Job", id),
+    }
+}
@@ -0,0 +1,22 @@
+use super::{json_response, not_found};
+use crate::app_state::AppState;
+use axum::body::Body;
+use axum::extract::{Path, State};
+use axum::http::Response;
+
+/// List the sync runs, newest first.
+pub async fn list_sync_runs_handler(State(app_state): State<AppState>) -> String {
+    let mut runs = app_state.sync_run_db.data();
+    runs.reverse();
+    serde_json::to_string_pretty(&runs).unwrap()
+}
+
+pub async fn get_sync_run_handler(
+    Path(id): Path<u64>,
+    State(app_state): State<AppState>,
+) -> Response<Body> {
+    match app_state.sync_run_db.find_by_id(id) {
+        Some(run) => json_response(200, &run),
+        None => not_found("Sync run", id),
+    }
+}
@@ -1,124 +1,423 @@
+use super::json_response;
 use crate::app_state::AppState;
 use crate::csv::{TransactionCsv, VecToCsv};
-use crate::genai::run_ai_guess_on_all_transactions;
-use crate::powens::POWENS_DATETIME_FORMAT;
+use crate::currency::CurrencyConverter;
+use crate::config::env_or_default;
+use crate::db::{
+    ChangeSource, ExportedTransaction, QueryOptions, SyncRun, SyncRunKind, SyncState, SyncTrigger,
+    TransactionsDb, UpsertOutcome,
+};
+use crate::events::Event;
+use crate::jobs::{Job, JobHandle, JobKind};
+use crate::genai::{run_ai_guess_job, run_ai_guess_on_all_transactions};
+use crate::handlers::{fetch_investments_from_powens, refresh_accounts_from_powens};
+use crate::powens::{
+    parse_powens_datetime, Transaction, TransactionType, TransactionsQuery, POWENS_DATETIME_FORMAT,
+    POWENS_DATE_FORMAT,
+};
+use crate::reconciliation::reconcile_coming_transactions;
 use axum::http::Response;
 use axum::{
     body::Body,
-    extract::{Query, State},
+    extract::{Json, Query, State},
     http::header,
 };
-use chrono::{DateTime, NaiveDateTime, Utc};
-use serde::Deserialize;
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use tracing::error;
 use tracing::info;
+use tracing::warn;
 
 const PARAM_DATETIME_FORMAT: &str = "%Y-%m-%d_%H-%M-%S";
 
+/// Default number of days before an account's sync cursor which are fetched again on each sync.
+const DEFAULT_SYNC_OVERLAP_DAYS: i64 = 7;
+
 #[derive(Deserialize)]
 pub struct TransactionsToCsvParams {
     last_update: Option<String>,
+    /// `last-export` to only export the transactions not exported yet.
+    since: Option<String>,
+    /// Convert amounts to BASE_CURRENCY.
+    convert: Option<bool>,
+}
+
+/// Value of `since` to only export the transactions not exported yet.
+const SINCE_LAST_EXPORT: &str = "last-export";
+
+#[derive(Deserialize)]
+pub struct ListTransactionsParams {
+    min_date: Option<NaiveDate>,
+    max_date: Option<NaiveDate>,
+    /// ID of the account.
+    account: Option<u64>,
+    min_amount: Option<f64>,
+    max_amount: Option<f64>,
+    #[serde(rename = "type")]
+    transaction_type: Option<TransactionType>,
+    /// Category of the transaction extras, case-insensitive.
+    category: Option<String>,
+    /// Tag of the transaction extras, case-insensitive.
+    tag: Option<String>,
+    coming: Option<bool>,
+    /// Text to search in the wordings, case-insensitive.
+    q: Option<String>,
+    sort: Option<TransactionsSort>,
+    order: Option<SortOrder>,
+    /// `X-Next-Cursor` header of the previous page.
+    cursor: Option<String>,
+    /// Size of a page, all transactions if not set.
+    limit: Option<usize>,
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransactionsSort {
+    #[default]
+    Date,
+    Amount,
+    Id,
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
 }
 
-pub async fn fetch_transactions_from_powens_handler(State(app_state): State<AppState>) -> String {
-    run_fetch_transactions_from_powens_job(app_state);
+#[derive(Deserialize)]
+pub struct BackfillParams {
+    min_date: NaiveDate,
+    max_date: NaiveDate,
+    /// IDs of the accounts to backfill, all accounts if not set.
+    accounts: Option<Vec<u64>>,
+}
 
-    "Job started".to_string()
+#[derive(Default, Debug, Clone, Serialize)]
+pub struct BackfillSummary {
+    pub fetched: usize,
+    pub inserted: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    /// Coming transactions removed because replaced by a backfilled posted transaction.
+    pub reconciled: usize,
 }
 
-pub fn run_fetch_transactions_from_powens_job(app_state: AppState) {
+/// Start a fetch job, answer 409 with the queued or running fetch job if any.
+pub async fn fetch_transactions_from_powens_handler(
+    State(app_state): State<AppState>,
+) -> Response<Body> {
+    match run_fetch_transactions_from_powens_job(app_state, SyncTrigger::Http) {
+        Ok(id) => Response::builder()
+            .status(200)
+            .body(Body::from(format!("Job {id} started")))
+            .unwrap(),
+        Err(job) => json_response(409, &job),
+    }
+}
+
+/**
+Start a job to fetch data from Powens then run AI guessing, returns the job ID.
+
+If a fetch job is already queued or running, no job is started and that job is returned as error.
+*/
+pub fn run_fetch_transactions_from_powens_job(
+    app_state: AppState,
+    trigger: SyncTrigger,
+) -> Result<u64, Box<Job>> {
+    let mut job = app_state.job_manager.try_start(JobKind::Fetch, trigger)?;
+    let id = job.id();
+
     tokio::spawn(async move {
+        if !job.wait_turn().await {
+            return;
+        }
+
         info!("Starting job to fetch transactions from Powens.");
+        let mut run = app_state
+            .sync_run_db
+            .start_or_unrecorded(SyncRunKind::Fetch, trigger);
+        if run.is_recorded() {
+            job.set_sync_run(run.id);
+        }
 
-        let mut latest_last_update: Option<DateTime<Utc>> = None;
-
-        let existing_transactions = app_state.transaction_db.data();
-        if !existing_transactions.is_empty() {
-            for transaction in existing_transactions {
-                let last_update =
-                    NaiveDateTime::parse_from_str(&transaction.last_update, POWENS_DATETIME_FORMAT);
-                if let Ok(last_update) = last_update {
-                    let last_update: DateTime<Utc> = last_update.and_utc();
-                    if let Some(latest_last_update_inner) = latest_last_update {
-                        if last_update > latest_last_update_inner {
-                            latest_last_update = Some(last_update);
-                        }
-                    } else {
-                        latest_last_update = Some(last_update);
-                    }
-                }
+        fetch_from_powens(&app_state, &job, &mut run).await;
+
+        job.finish_with_sync_run(&app_state.sync_run_db, run);
+        info!("Job finished.");
+    });
+
+    Ok(id)
+}
+
+/// Steps of the fetch job, the remaining steps are skipped when the job is cancelled.
+async fn fetch_from_powens(app_state: &AppState, job: &JobHandle, run: &mut SyncRun) {
+    // refresh accounts first, so that transactions of new accounts can be linked to them
+    if let Err(e) = refresh_accounts_from_powens(app_state).await {
+        run.add_error(&app_state.event_bus, "Error refreshing accounts", e);
+    }
+    if job.is_cancelled() {
+        return;
+    }
+
+    let (transactions, new_transactions) =
+        fetch_transactions_of_accounts(app_state, job, run).await;
+    info!("Fetched {} transactions from Powens.", transactions.len());
+
+    // remove coming transactions replaced by posted ones, before AI guessing on them
+    let fetched_ids: HashSet<u64> = transactions.iter().map(|it| it.id).collect();
+    if let Err(e) = reconcile_coming_transactions(app_state, &new_transactions, &fetched_ids) {
+        run.add_error(
+            &app_state.event_bus,
+            "Error reconciling coming transactions",
+            e,
+        );
+    }
+    if job.is_cancelled() {
+        return;
+    }
+
+    // refresh investments
+    match fetch_investments_from_powens(app_state).await {
+        Ok(errors) => {
+            for (context, e) in errors {
+                run.add_error(&app_state.event_bus, &context, e);
             }
         }
+        Err(e) => run.add_error(&app_state.event_bus, "Error fetching investments", e),
+    }
+    if job.is_cancelled() {
+        return;
+    }
 
-        if let Some(latest_last_update) = latest_last_update {
-            info!("last_update: {}", latest_last_update);
-        } else {
-            info!("No last_update, fetching last 1000 transactions.")
+    // run ai guessing
+    let (ai_calls, error) = run_ai_guess_on_all_transactions(app_state.clone(), job).await;
+    run.ai_calls = ai_calls;
+    if let Some(e) = error {
+        run.add_error(&app_state.event_bus, "Error running AI guessing", e);
+    }
+}
+
+/**
+Fetch and save the transactions of each active account, updated since the account's sync cursor.
+
+The `SYNC_OVERLAP_DAYS` days before the cursor are fetched again, so that changes made by Powens to
+older transactions are not missed. Returns all fetched transactions and those newly inserted, counts
+and errors are recorded in the given run.
+*/
+async fn fetch_transactions_of_accounts(
+    app_state: &AppState,
+    job: &JobHandle,
+    run: &mut SyncRun,
+) -> (Vec<Transaction>, Vec<Transaction>) {
+    let overlap = Duration::days(
+        env_or_default("SYNC_OVERLAP_DAYS", DEFAULT_SYNC_OVERLAP_DAYS)
+            .unwrap_or(DEFAULT_SYNC_OVERLAP_DAYS),
+    );
+
+    let mut accounts = app_state.account_db.data();
+    accounts.retain(|it| it.deleted.is_none() && it.disabled.is_none());
+
+    let total = accounts.len();
+    let mut all_transactions: Vec<Transaction> = Vec::new();
+    let mut sync_states: Vec<SyncState> = Vec::new();
+    for (index, account) in accounts.into_iter().enumerate() {
+        if job.is_cancelled() {
+            break;
         }
+        job.set_progress("fetched accounts", index, total);
 
-        info!("Starting job to fetch transactions from Powens.");
-        if let Ok(transactions) = app_state
+        // without sync state yet, start from the transactions already in DB
+        let cursor: Option<DateTime<Utc>> = match app_state.sync_state_db.find_by_id(account.id) {
+            Some(sync_state) => sync_state.cursor.as_deref().and_then(parse_powens_datetime),
+            None => app_state
+                .transaction_db
+                .find_by_account(account.id)
+                .iter()
+                .filter_map(|it| parse_powens_datetime(&it.last_update))
+                .max(),
+        };
+        let since = cursor.map(|it| it - overlap);
+
+        info!(
+            "Fetching transactions of account {} updated since {:?}.",
+            account.id, since
+        );
+        let transactions = match app_state
             .powens_api
-            .get_transactions(latest_last_update)
+            .get_transactions(&TransactionsQuery {
+                id_account: Some(account.id),
+                last_update: since,
+                ..Default::default()
+            })
             .await
         {
-            info!("Fetched {} transactions from Powens.", transactions.len());
-            for transaction in transactions {
-                if let Err(e) = app_state.transaction_db.upsert(transaction) {
-                    error!("Error saving transaction: {:#?}", e);
-                }
+            Ok(transactions) => transactions,
+            Err(e) => {
+                run.add_error(
+                    &app_state.event_bus,
+                    &format!(
+                        "Error fetching transactions of account {} from Powens",
+                        account.id
+                    ),
+                    e,
+                );
+                continue;
             }
-            info!("Transactions saved.");
-        }
+        };
+
+        run.fetched += transactions.len();
+
+        // move the cursor to the latest update synced
+        let new_cursor = transactions
+            .iter()
+            .filter_map(|it| parse_powens_datetime(&it.last_update))
+            .chain(cursor)
+            .max();
+        sync_states.push(SyncState {
+            id: account.id,
+            cursor: new_cursor.map(|it| it.format(POWENS_DATETIME_FORMAT).to_string()),
+            last_fetched_since: since.map(|it| it.format(POWENS_DATETIME_FORMAT).to_string()),
+            last_fetched_count: transactions.len(),
+            last_synced_at: Utc::now().to_rfc3339(),
+        });
 
-        // run ai guessing
-        if let Err(e) = run_ai_guess_on_all_transactions(app_state).await {
-            error!("Error running AI guessing: {:#?}", e);
+        all_transactions.extend(transactions);
+    }
+
+    // save all transactions with a single write, then move the cursors
+    let mut new_transactions: Vec<Transaction> = Vec::new();
+    match app_state.change_db.upsert_recorded(
+        &app_state.transaction_db,
+        all_transactions.iter().cloned(),
+        ChangeSource::PowensSync,
+    ) {
+        Ok(outcomes) => {
+            for (transaction, outcome) in all_transactions.iter().zip(outcomes) {
+                run.count_upsert(outcome);
+                if outcome == UpsertOutcome::Inserted {
+                    app_state.event_bus.publish(Event::NewTransaction {
+                        transaction: Box::new(transaction.clone()),
+                    });
+                    new_transactions.push(transaction.clone());
+                }
+            }
+            if let Err(e) = app_state.sync_state_db.upsert_many(sync_states) {
+                run.add_error(&app_state.event_bus, "Error saving sync states", e);
+            }
         }
+        // cursors are not moved, so that the transactions are fetched again on next sync
+        Err(e) => run.add_error(&app_state.event_bus, "Error saving transactions", e),
+    }
 
-        info!("Job finished.");
-    });
+    (all_transactions, new_transactions)
 }
 
+/**
+Export the transactions as CSV for Maybe.
+
+With `since=last-export`, only the transactions not in an export batch are exported. The export is
+not recorded, see `export_transactions_handler`.
+*/
 pub async fn transactions_to_csv_handler(
     Query(params): Query<TransactionsToCsvParams>,
     State(app_state): State<AppState>,
+) -> Response<Body> {
+    transactions_csv_response(&app_state, &params, false).await
+}
+
+/**
+Export the transactions as CSV for Maybe, like `transactions_to_csv_handler`, and record the export
+as an export batch. The id of the recorded batch is in the `X-Export-Batch` header.
+
+ex: `POST /transactions/exports?since=last-export` to export the transactions not exported yet.
+*/
+pub async fn export_transactions_handler(
+    Query(params): Query<TransactionsToCsvParams>,
+    State(app_state): State<AppState>,
+) -> Response<Body> {
+    transactions_csv_response(&app_state, &params, true).await
+}
+
+/// CSV of the transactions, recorded as an export batch if `record`.
+async fn transactions_csv_response(
+    app_state: &AppState,
+    params: &TransactionsToCsvParams,
+    record: bool,
 ) -> Response<Body> {
     // parse param
+    let since_last_export = match params.since.as_deref() {
+        None => false,
+        Some(SINCE_LAST_EXPORT) => true,
+        Some(since) => {
+            return Response::builder()
+                .status(400)
+                .body(Body::from(format!(
+                    "Unsupported since: {since}, only {SINCE_LAST_EXPORT} is supported"
+                )))
+                .unwrap();
+        }
+    };
+
     let mut last_update: Option<DateTime<Utc>> = None;
-    if let Some(last_update_str) = &params.last_update {
-        if let Ok(last_update_native) =
+    if let Some(last_update_str) = &params.last_update
+        && let Ok(last_update_native) =
             NaiveDateTime::parse_from_str(last_update_str, PARAM_DATETIME_FORMAT)
-        {
-            let last_update_utc: DateTime<Utc> = last_update_native.and_utc();
-            last_update = Some(last_update_utc)
-        }
+    {
+        let last_update_utc: DateTime<Utc> = last_update_native.and_utc();
+        last_update = Some(last_update_utc)
     }
 
+    let converter = match csv_converter(params.convert.unwrap_or(false)) {
+        Ok(converter) => converter,
+        Err(response) => return *response,
+    };
+
     if let Some(last_update) = last_update {
         info!("Generate transactions CSV with last update: {:#?}", last_update);
     } else {
         info!("Generate all transactions CSV");
     }
 
+    // a concurrent export must not record the same transactions as not exported yet
+    let write_guard = if record {
+        Some(app_state.job_manager.lock_writes().await)
+    } else {
+        None
+    };
+
     // filter transactions
-    let mut transaction = (&app_state.transaction_db).data();
+    let mut transaction = app_state.transaction_db.data();
     // keep those coming == false
     transaction.retain(|it| !it.coming);
+    // trades are exported by the trades CSV, when the market orders of their account are fetched
+    transaction.retain(|it| {
+        !it.transaction_type.is_trade()
+            || !app_state
+                .investment_sync_state_db
+                .has_fetched_market_orders(it.id_account)
+    });
     // keep if transaction.last_update > param.last_update
     if let Some(last_update_param) = last_update {
+        // without a valid last_update, a transaction can't be known as exported already
         transaction.retain(|it| {
-            let last_update_native =
-                NaiveDateTime::parse_from_str(&it.last_update, POWENS_DATETIME_FORMAT).unwrap();
-            let last_update_utc: DateTime<Utc> = last_update_native.and_utc();
-
-            last_update_utc > last_update_param
+            parse_powens_datetime(&it.last_update).is_none_or(|it| it > last_update_param)
         });
     }
+    // keep those not exported yet
+    if since_last_export {
+        let exports = app_state.export_batch_db.latest_exports();
+        transaction.retain(|it| !exports.contains_key(&it.id));
+    }
 
     // if empty, end
     if transaction.is_empty() {
-        let res_str = if let Some(last_update_str) = &params.last_update {
+        let res_str = if since_last_export {
+            "No transactions found since the last export.".to_string()
+        } else if let Some(last_update_str) = &params.last_update {
             info!("Total {} transactions found for last update {:#?}.", transaction.len(), last_update);
             format!("No transactions found with last_update > {last_update_str} found")
         } else {
@@ -133,51 +432,53 @@ pub async fn transactions_to_csv_handler(
     // export csv
     else {
         // find the biggest last_update in transactions, use it to create a download file name
-        let mut biggest_last_update: Option<DateTime<Utc>> = None;
-        for transaction in transaction.iter() {
-            let last_update_native =
-                NaiveDateTime::parse_from_str(&transaction.last_update, POWENS_DATETIME_FORMAT)
-                    .unwrap();
-            let last_update_utc: DateTime<Utc> = last_update_native.and_utc();
-            if let Some(biggest_last_update_inner) = biggest_last_update {
-                if last_update_utc > biggest_last_update_inner {
-                    biggest_last_update = Some(last_update_utc);
-                }
-            } else {
-                biggest_last_update = Some(last_update_utc);
-            }
-        }
-
-        // convert to Transaction to TransactionCsv
-        let account_db = &app_state.account_db;
-        let transactions_csv: Vec<TransactionCsv> = transaction
+        let biggest_last_update = transaction
             .iter()
-            .map(|it| {
-                let mut transaction_csv: TransactionCsv = it.into();
+            .filter_map(|it| parse_powens_datetime(&it.last_update))
+            .max();
 
-                let account = account_db.find_by_id(it.id_account).unwrap();
-                transaction_csv.set_account(&account);
+        // result csv
+        let result = transactions_to_csv(app_state, &transaction, converter.as_ref());
 
-                if let Some(extras) = app_state.transaction_extras_db.find_by_id(it.id) {
-                    transaction_csv.set_extras(&extras);
+        // remember what was exported
+        let mut response = Response::builder();
+        if record {
+            let exported = transaction
+                .iter()
+                .map(|it| {
+                    let extras = app_state.transaction_extras_db.find_by_id(it.id);
+                    ExportedTransaction::new(it, extras.as_ref())
+                })
+                .collect();
+            let recorded =
+                app_state
+                    .export_batch_db
+                    .record(exported, converter.is_some(), result.clone());
+            drop(write_guard);
+            match recorded {
+                Ok(batch) => response = response.header("X-Export-Batch", batch.id),
+                Err(e) => {
+                    error!("Error recording export batch: {:#?}", e);
+                    return Response::builder()
+                        .status(500)
+                        .body(Body::from("Error recording export batch"))
+                        .unwrap();
                 }
-
-                transaction_csv
-            })
-            .collect();
-
-        // result csv
-        let result = transactions_csv.to_csv();
+            }
+        }
 
         // convert the result into a http body
         let body = Body::from(result);
 
+        // named after the export time when no last_update is valid
         let filename = format!(
             "transactions {}.csv",
-            biggest_last_update.unwrap().format(PARAM_DATETIME_FORMAT)
+            biggest_last_update
+                .unwrap_or_else(Utc::now)
+                .format(PARAM_DATETIME_FORMAT)
         );
 
-        Response::builder()
+        response
             .status(200) // Set status code as needed
             .header(header::CONTENT_TYPE, "text/csv")
             .header(
@@ -189,6 +490,338 @@ pub async fn transactions_to_csv_handler(
     }
 }
 
-pub async fn list_transactions_handler(State(app_state): State<AppState>) -> String {
-    serde_json::to_string_pretty(&app_state.transaction_db.data()).unwrap()
+/// Currency converter of the CSV, or the error response if the rates can't be loaded.
+pub(super) fn csv_converter(
+    convert: bool,
+) -> Result<Option<CurrencyConverter>, Box<Response<Body>>> {
+    if !convert {
+        return Ok(None);
+    }
+    match CurrencyConverter::required_from_env() {
+        Ok(converter) => Ok(Some(converter)),
+        Err(e) => {
+            error!("Error loading currency rates: {}", e);
+            Err(Box::new(
+                Response::builder()
+                    .status(400)
+                    .body(Body::from(format!("Error loading currency rates: {e}")))
+                    .unwrap(),
+            ))
+        }
+    }
+}
+
+/// CSV of the transactions, with their account and extras.
+pub(super) fn transactions_to_csv(
+    app_state: &AppState,
+    transactions: &[Transaction],
+    converter: Option<&CurrencyConverter>,
+) -> String {
+    // convert to Transaction to TransactionCsv
+    let account_db = &app_state.account_db;
+    let transactions_csv: Vec<TransactionCsv> = transactions
+        .iter()
+        .map(|it| {
+            let mut transaction_csv: TransactionCsv = it.into();
+
+            match account_db.find_by_id(it.id_account) {
+                Some(account) => transaction_csv.set_account(&account),
+                None => warn!(
+                    "Account {} of transaction {} not found, refresh accounts to get it.",
+                    it.id_account, it.id
+                ),
+            }
+
+            if let Some(extras) = app_state.transaction_extras_db.find_by_id(it.id) {
+                transaction_csv.set_extras(&extras);
+            }
+
+            if let Some(converter) = converter
+                && !transaction_csv.convert(converter)
+            {
+                warn!(
+                    "No rate for currency {} of transaction {}, amount not converted.",
+                    transaction_csv.currency, it.id
+                );
+            }
+
+            transaction_csv
+        })
+        .collect();
+
+    transactions_csv.to_csv()
+}
+
+/**
+List the transactions matching the query parameters, all transactions sorted by date by default.
+
+When `limit` is set and there are more transactions, the `X-Next-Cursor` header is the `cursor` to
+get the next page. The `X-Total-Count` header is the number of matching transactions.
+*/
+pub async fn list_transactions_handler(
+    Query(params): Query<ListTransactionsParams>,
+    State(app_state): State<AppState>,
+) -> Response<Body> {
+    let min_date = params
+        .min_date
+        .map(|it| it.format(POWENS_DATE_FORMAT).to_string());
+    let max_date = params
+        .max_date
+        .map(|it| it.format(POWENS_DATE_FORMAT).to_string());
+    let search = params.q.as_ref().map(|it| it.to_lowercase());
+    let category = params.category.as_ref().map(|it| it.to_lowercase());
+    let tag = params.tag.as_ref().map(|it| it.to_lowercase());
+
+    // Loaded before the query, to not lock the extras DB while the transactions DB is locked.
+    let ids_with_extras: Option<HashSet<u64>> = (category.is_some() || tag.is_some()).then(|| {
+        let contains =
+            |values: &[String], value: &String| values.iter().any(|it| it.to_lowercase() == *value);
+        app_state
+            .transaction_extras_db
+            .data()
+            .iter()
+            .filter(|extras| {
+                category
+                    .as_ref()
+                    .is_none_or(|category| contains(&extras.categories, category))
+                    && tag.as_ref().is_none_or(|tag| contains(&extras.tags, tag))
+            })
+            .map(|extras| extras.id)
+            .collect()
+    });
+
+    let filter = |it: &Transaction| {
+        if min_date.as_ref().is_some_and(|min| &it.date < min)
+            || max_date.as_ref().is_some_and(|max| &it.date > max)
+            || params
+                .account
+                .is_some_and(|account| it.id_account != account)
+            || params.min_amount.is_some_and(|min| it.value < min)
+            || params.max_amount.is_some_and(|max| it.value > max)
+            || params.coming.is_some_and(|coming| it.coming != coming)
+            || params
+                .transaction_type
+                .as_ref()
+                .is_some_and(|transaction_type| &it.transaction_type != transaction_type)
+        {
+            return false;
+        }
+
+        if let Some(search) = &search
+            && ![
+                &it.original_wording,
+                &it.simplified_wording,
+                &it.stemmed_wording,
+                &it.wording,
+            ]
+            .iter()
+            .any(|wording| wording.to_lowercase().contains(search))
+        {
+            return false;
+        }
+
+        if let Some(ids) = &ids_with_extras
+            && !ids.contains(&it.id)
+        {
+            return false;
+        }
+
+        true
+    };
+
+    let options = QueryOptions {
+        descending: params.order.unwrap_or_default() == SortOrder::Desc,
+        cursor: params.cursor.clone(),
+        limit: params.limit,
+        index_range: match params.account {
+            Some(account) => Some(TransactionsDb::account_index_range(account)),
+            None if params.min_date.is_some() || params.max_date.is_some() => Some(
+                TransactionsDb::date_index_range(params.min_date, params.max_date),
+            ),
+            None => None,
+        },
+    };
+    let db = &app_state.transaction_db;
+    let page = match params.sort.unwrap_or_default() {
+        TransactionsSort::Date => db.query(filter, |it| it.date.clone(), &options),
+        TransactionsSort::Amount => {
+            db.query(filter, |it| (it.value * 100.0).round() as i64, &options)
+        }
+        TransactionsSort::Id => db.query(filter, |it| it.id, &options),
+    };
+    let page = match page {
+        Ok(page) => page,
+        Err(e) => {
+            return Response::builder()
+                .status(400)
+                .body(Body::from(e.to_string()))
+                .unwrap();
+        }
+    };
+
+    let mut response = Response::builder()
+        .status(200)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header("X-Total-Count", page.total);
+    if let Some(next_cursor) = &page.next_cursor {
+        response = response.header("X-Next-Cursor", next_cursor);
+    }
+    response
+        .body(Body::from(
+            serde_json::to_string_pretty(&page.data).unwrap(),
+        ))
+        .unwrap()
+}
+
+pub async fn list_reconciliations_handler(State(app_state): State<AppState>) -> String {
+    serde_json::to_string_pretty(&app_state.reconciliation_db.data()).unwrap()
+}
+
+/**
+Fetch again the transactions of a date range from Powens, and upsert them.
+
+AI guessing is run in background on the inserted transactions.
+*/
+pub async fn backfill_transactions_handler(
+    State(app_state): State<AppState>,
+    Json(params): Json<BackfillParams>,
+) -> Response<Body> {
+    if params.min_date > params.max_date {
+        return Response::builder()
+            .status(400)
+            .body(Body::from("min_date must be before max_date"))
+            .unwrap();
+    }
+
+    info!(
+        "Backfilling transactions from {} to {} of accounts {:?}.",
+        params.min_date, params.max_date, params.accounts
+    );
+    // wait for the running job, so the writes don't interleave with it
+    let write_guard = app_state.job_manager.lock_writes().await;
+    let mut run = match app_state
+        .sync_run_db
+        .start(SyncRunKind::Backfill, SyncTrigger::Http)
+    {
+        Ok(run) => run,
+        Err(e) => {
+            error!("Error recording sync run: {:#?}", e);
+            return Response::builder()
+                .status(500)
+                .body(Body::from("Error recording sync run"))
+                .unwrap();
+        }
+    };
+
+    // one query for all accounts, or one query per account
+    let queries: Vec<TransactionsQuery> = match &params.accounts {
+        Some(accounts) => accounts
+            .iter()
+            .map(|id_account| TransactionsQuery {
+                id_account: Some(*id_account),
+                min_date: Some(params.min_date),
+                max_date: Some(params.max_date),
+                ..Default::default()
+            })
+            .collect(),
+        None => vec![TransactionsQuery {
+            min_date: Some(params.min_date),
+            max_date: Some(params.max_date),
+            ..Default::default()
+        }],
+    };
+
+    let mut transactions: Vec<Transaction> = Vec::new();
+    for query in queries.iter() {
+        match app_state.powens_api.get_transactions(query).await {
+            Ok(fetched) => transactions.extend(fetched),
+            Err(e) => {
+                run.add_error(&app_state.event_bus, "Error backfilling transactions", &e);
+                app_state.sync_run_db.finish(run);
+                return Response::builder()
+                    .status(502)
+                    .body(Body::from(format!("Error fetching transactions from Powens: {e}")))
+                    .unwrap();
+            }
+        }
+    }
+
+    let result = save_backfilled_transactions(&app_state, transactions, run);
+    drop(write_guard);
+
+    match result {
+        Ok(summary) => json_response(200, &summary),
+        Err(e) => {
+            error!("Error saving backfilled transactions: {:#?}", e);
+            Response::builder()
+                .status(500)
+                .body(Body::from("Error saving backfilled transactions"))
+                .unwrap()
+        }
+    }
+}
+
+/// Upsert backfilled transactions, the run is finished by the AI guessing job on inserted ones.
+fn save_backfilled_transactions(
+    app_state: &AppState,
+    transactions: Vec<Transaction>,
+    mut run: SyncRun,
+) -> Result<BackfillSummary, Box<dyn std::error::Error>> {
+    let mut summary = BackfillSummary {
+        fetched: transactions.len(),
+        ..Default::default()
+    };
+    run.fetched = transactions.len();
+
+    let outcomes = match app_state.change_db.upsert_recorded(
+        &app_state.transaction_db,
+        transactions.iter().cloned(),
+        ChangeSource::PowensSync,
+    ) {
+        Ok(outcomes) => outcomes,
+        Err(e) => {
+            run.add_error(&app_state.event_bus, "Error saving transactions", &e);
+            app_state.sync_run_db.finish(run);
+            return Err(e);
+        }
+    };
+
+    let mut inserted: Vec<Transaction> = Vec::new();
+    for (transaction, outcome) in transactions.iter().zip(outcomes) {
+        run.count_upsert(outcome);
+        match outcome {
+            UpsertOutcome::Inserted => {
+                summary.inserted += 1;
+                inserted.push(transaction.clone());
+                app_state.event_bus.publish(Event::NewTransaction {
+                    transaction: Box::new(transaction.clone()),
+                });
+            }
+            UpsertOutcome::Updated => summary.updated += 1,
+            UpsertOutcome::Unchanged => summary.unchanged += 1,
+        }
+    }
+
+    let fetched_ids: HashSet<u64> = transactions.iter().map(|it| it.id).collect();
+    summary.reconciled = match reconcile_coming_transactions(app_state, &inserted, &fetched_ids) {
+        Ok(reconciliations) => reconciliations.len(),
+        Err(e) => {
+            run.add_error(
+                &app_state.event_bus,
+                "Error reconciling coming transactions",
+                &e,
+            );
+            app_state.sync_run_db.finish(run);
+            return Err(e);
+        }
+    };
+    info!("Backfill finished: {:?}", summary);
+
+    if inserted.is_empty() {
+        app_state.sync_run_db.finish(run);
+    } else {
+        run_ai_guess_job(app_state.clone(), SyncTrigger::Http, Some(inserted), run);
+    }
+
+    Ok(summary)
 }
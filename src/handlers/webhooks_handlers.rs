@@ -0,0 +1,188 @@
+use super::text_response;
+use crate::app_state::AppState;
+use crate::config::env_or_default;
+use crate::db::{ChangeSource, SyncRun, SyncRunKind, SyncTrigger, UpsertOutcome};
+use crate::events::Event;
+use crate::genai::run_ai_guess_job;
+use crate::reconciliation::reconcile_coming_transactions;
+use crate::powens::{
+    Account, ConnectionSyncedPayload, DEFAULT_WEBHOOK_TOLERANCE_SECS, Transaction,
+    WEBHOOK_SIGNATURE_DATE_HEADER, WEBHOOK_SIGNATURE_HEADER, WebhookAccount, WebhookEvent,
+    is_signature_date_recent, verify_webhook_signature,
+};
+use axum::body::{Body, Bytes};
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, Response, Uri};
+use chrono::Utc;
+use std::collections::HashSet;
+use std::str::FromStr;
+use tracing::{error, info, warn};
+
+/**
+Receive a Powens webhook.
+
+The event is given by the path, so each webhook has to be configured in Powens with its own URL,
+ex: `/webhooks/powens/connection_synced`, `/webhooks/powens/account_synced`.
+
+Pushed accounts and transactions are saved, then AI guessing is run on the new transactions.
+*/
+pub async fn powens_webhook_handler(
+    Path(event): Path<String>,
+    State(app_state): State<AppState>,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response<Body> {
+    // verify signature
+    let secret = match dotenv::var("POWENS_WEBHOOK_SECRET") {
+        Ok(secret) if !secret.is_empty() => secret,
+        _ => {
+            error!("Received a Powens webhook, but POWENS_WEBHOOK_SECRET is not configured.");
+            return text_response(500, "Webhook secret not configured");
+        }
+    };
+    let header_value = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|it| it.to_str().ok())
+            .unwrap_or_default()
+            .to_string()
+    };
+    let signature = header_value(WEBHOOK_SIGNATURE_HEADER);
+    let signature_date = header_value(WEBHOOK_SIGNATURE_DATE_HEADER);
+    if !verify_webhook_signature(&secret, uri.path(), &signature_date, &body, &signature) {
+        warn!("Rejected Powens webhook {} with invalid signature.", event);
+        return text_response(401, "Invalid signature");
+    }
+    let tolerance_secs = env_or_default(
+        "POWENS_WEBHOOK_TOLERANCE_SECS",
+        DEFAULT_WEBHOOK_TOLERANCE_SECS,
+    )
+    .unwrap_or(DEFAULT_WEBHOOK_TOLERANCE_SECS);
+    if !is_signature_date_recent(&signature_date, Utc::now(), tolerance_secs) {
+        warn!(
+            "Rejected Powens webhook {} with signature date {:?} outside of the tolerance window.",
+            event, signature_date
+        );
+        return text_response(401, "Signature date outside of the tolerance window");
+    }
+
+    // parse payload
+    let Ok(event) = WebhookEvent::from_str(&event) else {
+        warn!("Received unsupported Powens webhook event: {}", event);
+        return text_response(400, "Unsupported webhook event");
+    };
+    info!("Received Powens webhook {}.", event);
+
+    let accounts: Result<Vec<WebhookAccount>, serde_json::Error> = match event {
+        WebhookEvent::ConnectionSynced => serde_json::from_slice::<ConnectionSyncedPayload>(&body)
+            .map(|payload| payload.connection.accounts),
+        WebhookEvent::AccountSynced => {
+            serde_json::from_slice::<WebhookAccount>(&body).map(|account| vec![account])
+        }
+    };
+    let accounts = match accounts {
+        Ok(accounts) => accounts,
+        Err(e) => {
+            error!(
+                "Failed to decode Powens webhook {} payload: {:#?}",
+                event, e
+            );
+            return text_response(400, "Invalid payload");
+        }
+    };
+
+    // save data, after the running job so the writes don't interleave with it
+    let write_guard = app_state.job_manager.lock_writes().await;
+    let mut run = match app_state
+        .sync_run_db
+        .start(SyncRunKind::Fetch, SyncTrigger::Webhook)
+    {
+        Ok(run) => run,
+        Err(e) => {
+            error!("Error recording sync run: {:#?}", e);
+            return text_response(500, "Error saving data");
+        }
+    };
+    let saved = save_webhook_accounts(&app_state, accounts, &mut run);
+    let new_transactions = match saved {
+        Ok(new_transactions) => new_transactions,
+        Err(e) => {
+            run.add_error(
+                &app_state.event_bus,
+                &format!("Error saving Powens webhook {event} data"),
+                e,
+            );
+            app_state.sync_run_db.finish(run);
+            return text_response(500, "Error saving data");
+        }
+    };
+
+    // run ai guessing on new transactions only, in a queued job to answer Powens quickly
+    info!(
+        "Powens webhook {} saved, {} new transactions.",
+        event,
+        new_transactions.len()
+    );
+    if new_transactions.is_empty() {
+        app_state.sync_run_db.finish(run);
+    } else {
+        run_ai_guess_job(app_state, SyncTrigger::Webhook, Some(new_transactions), run);
+    }
+    drop(write_guard);
+
+    text_response(200, "ok")
+}
+
+/**
+Upsert pushed accounts and their transactions, return the transactions which were not in DB.
+
+All accounts are collected first, so each DB is written once, even for a multi-account webhook.
+*/
+fn save_webhook_accounts(
+    app_state: &AppState,
+    webhook_accounts: Vec<WebhookAccount>,
+    run: &mut SyncRun,
+) -> Result<Vec<Transaction>, Box<dyn std::error::Error>> {
+    let schema_drift = app_state.powens_api.schema_drift();
+    let mut accounts: Vec<Account> = Vec::new();
+    let mut transactions: Vec<Transaction> = Vec::new();
+    for WebhookAccount {
+        account,
+        transactions: account_transactions,
+    } in webhook_accounts
+    {
+        schema_drift.record("Account", &account.extra);
+        for transaction in account_transactions.iter() {
+            schema_drift.record("Transaction", &transaction.extra);
+        }
+        accounts.push(account);
+        transactions.extend(account_transactions);
+    }
+
+    app_state.balance_snapshot_db.record_accounts(&accounts)?;
+    app_state.account_db.upsert_many(accounts)?;
+
+    run.fetched += transactions.len();
+    let outcomes = app_state.change_db.upsert_recorded(
+        &app_state.transaction_db,
+        transactions.iter().cloned(),
+        ChangeSource::PowensSync,
+    )?;
+    let fetched_ids: HashSet<u64> = transactions.iter().map(|it| it.id).collect();
+    let mut new_transactions: Vec<Transaction> = Vec::new();
+    for (transaction, outcome) in transactions.into_iter().zip(outcomes) {
+        run.count_upsert(outcome);
+        if outcome == UpsertOutcome::Inserted {
+            app_state.event_bus.publish(Event::NewTransaction {
+                transaction: Box::new(transaction.clone()),
+            });
+            new_transactions.push(transaction);
+        }
+    }
+
+    reconcile_coming_transactions(app_state, &new_transactions, &fetched_ids)?;
+
+    Ok(new_transactions)
+}
+
@@ -0,0 +1,342 @@
+use crate::db::{SyncRun, SyncRunStatus, SyncRunsDb, SyncTrigger};
+use crate::events::{Event, EventBus};
+use chrono::Utc;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::OwnedMutexGuard;
+use tracing::{info, warn};
+
+/// Number of finished jobs kept in memory.
+const FINISHED_JOBS_KEPT: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, strum::Display)]
+#[serde(rename_all = "snake_case")]
+pub enum JobKind {
+    /// Fetch accounts, transactions and investments from Powens, then AI guessing.
+    Fetch,
+    /// AI guessing only.
+    Classify,
+    /// Fetch investments and market orders from Powens only.
+    FetchInvestments,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    /// Waiting for the running job to finish.
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            JobStatus::Succeeded | JobStatus::Failed | JobStatus::Cancelled
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JobProgress {
+    pub done: usize,
+    pub total: usize,
+    /// ex: "classified 120/430"
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Job {
+    pub id: u64,
+    pub kind: JobKind,
+    pub trigger: SyncTrigger,
+    pub status: JobStatus,
+    pub progress: Option<JobProgress>,
+    /// ID of the sync run recording what the job did.
+    pub id_sync_run: Option<u64>,
+    /// RFC 3339 datetime.
+    pub created_at: String,
+    /// RFC 3339 datetime, None while queued.
+    pub started_at: Option<String>,
+    /// RFC 3339 datetime, None until finished.
+    pub ended_at: Option<String>,
+    pub error: Option<String>,
+}
+
+struct JobEntry {
+    job: Job,
+    cancelled: Arc<AtomicBool>,
+}
+
+#[derive(Default)]
+struct JobRegistry {
+    next_id: u64,
+    entries: VecDeque<JobEntry>,
+}
+
+/**
+Keep track of the background jobs.
+
+Jobs writing the DBs run one at a time, the others wait in queue. A job can't be started when a
+job of the same kind is already queued or running, unless it is explicitly queued.
+*/
+#[derive(Clone)]
+pub struct JobManager {
+    registry: Arc<Mutex<JobRegistry>>,
+    run_lock: Arc<tokio::sync::Mutex<()>>,
+    events: EventBus,
+}
+
+impl JobManager {
+    /// Jobs start, progress and end are published to the given event bus.
+    pub fn new(events: EventBus) -> Self {
+        Self {
+            registry: Arc::new(Mutex::new(JobRegistry::default())),
+            run_lock: Arc::new(tokio::sync::Mutex::new(())),
+            events,
+        }
+    }
+
+    /// Register a job, or return the queued or running job of the same kind.
+    pub fn try_start(&self, kind: JobKind, trigger: SyncTrigger) -> Result<JobHandle, Box<Job>> {
+        let mut registry = self.registry.lock().unwrap();
+        if let Some(entry) = registry
+            .entries
+            .iter()
+            .find(|it| it.job.kind == kind && !it.job.status.is_finished())
+        {
+            return Err(Box::new(entry.job.clone()));
+        }
+        Ok(self.register(&mut registry, kind, trigger))
+    }
+
+    /// Register a job, even if a job of the same kind is already queued or running.
+    pub fn enqueue(&self, kind: JobKind, trigger: SyncTrigger) -> JobHandle {
+        let mut registry = self.registry.lock().unwrap();
+        self.register(&mut registry, kind, trigger)
+    }
+
+    fn register(
+        &self,
+        registry: &mut JobRegistry,
+        kind: JobKind,
+        trigger: SyncTrigger,
+    ) -> JobHandle {
+        registry.next_id += 1;
+        let id = registry.next_id;
+        let cancelled = Arc::new(AtomicBool::new(false));
+        registry.entries.push_back(JobEntry {
+            job: Job {
+                id,
+                kind,
+                trigger,
+                status: JobStatus::Queued,
+                progress: None,
+                id_sync_run: None,
+                created_at: Utc::now().to_rfc3339(),
+                started_at: None,
+                ended_at: None,
+                error: None,
+            },
+            cancelled: cancelled.clone(),
+        });
+
+        // forget the oldest finished jobs
+        while registry
+            .entries
+            .iter()
+            .filter(|it| it.job.status.is_finished())
+            .count()
+            > FINISHED_JOBS_KEPT
+        {
+            if let Some(index) = registry
+                .entries
+                .iter()
+                .position(|it| it.job.status.is_finished())
+            {
+                registry.entries.remove(index);
+            }
+        }
+
+        info!("Job {} ({}) queued, triggered by {}.", id, kind, trigger);
+        JobHandle {
+            id,
+            manager: self.clone(),
+            cancelled,
+            run_guard: None,
+        }
+    }
+
+    /// List the jobs, newest first.
+    pub fn list(&self) -> Vec<Job> {
+        let registry = self.registry.lock().unwrap();
+        registry
+            .entries
+            .iter()
+            .rev()
+            .map(|it| it.job.clone())
+            .collect()
+    }
+
+    pub fn find_by_id(&self, id: u64) -> Option<Job> {
+        let registry = self.registry.lock().unwrap();
+        registry
+            .entries
+            .iter()
+            .find(|it| it.job.id == id)
+            .map(|it| it.job.clone())
+    }
+
+    /**
+    Ask a queued or running job to stop.
+
+    Running jobs stop at their next checkpoint, ex: between two AI calls.
+    Returns None if the job is not found, Err with the job if it is already finished.
+    */
+    pub fn cancel(&self, id: u64) -> Option<Result<Job, Job>> {
+        let registry = self.registry.lock().unwrap();
+        let entry = registry.entries.iter().find(|it| it.job.id == id)?;
+        if entry.job.status.is_finished() {
+            return Some(Err(entry.job.clone()));
+        }
+        entry.cancelled.store(true, Ordering::Relaxed);
+        info!("Job {} cancellation requested.", id);
+        Some(Ok(entry.job.clone()))
+    }
+
+    /// Wait for the running job to finish, the queued jobs wait until the guard is dropped.
+    pub async fn lock_writes(&self) -> OwnedMutexGuard<()> {
+        self.run_lock.clone().lock_owned().await
+    }
+
+    /// Update a job, then publish the event returned by the update, if any.
+    fn update(&self, id: u64, f: impl FnOnce(&mut Job) -> Option<Event>) {
+        let event = {
+            let mut registry = self.registry.lock().unwrap();
+            let Some(entry) = registry.entries.iter_mut().find(|it| it.job.id == id) else {
+                return;
+            };
+            f(&mut entry.job)
+        };
+        if let Some(event) = event {
+            self.events.publish(event);
+        }
+    }
+}
+
+/**
+Handle given to a job to report its progress.
+
+The job is marked as failed if the handle is dropped without being finished, ex: on panic.
+*/
+pub struct JobHandle {
+    id: u64,
+    manager: JobManager,
+    cancelled: Arc<AtomicBool>,
+    run_guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl JobHandle {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Snapshot of the job.
+    pub fn job(&self) -> Option<Job> {
+        self.manager.find_by_id(self.id)
+    }
+
+    /// Wait for the previous jobs to finish, then mark the job as running.
+    /// Returns false if the job has been cancelled while queued.
+    pub async fn wait_turn(&mut self) -> bool {
+        let guard = self.manager.run_lock.clone().lock_owned().await;
+        if self.is_cancelled() {
+            self.finish(Ok(()));
+            return false;
+        }
+
+        self.run_guard = Some(guard);
+        self.manager.update(self.id, |job| {
+            job.status = JobStatus::Running;
+            job.started_at = Some(Utc::now().to_rfc3339());
+            Some(Event::JobStarted { job: job.clone() })
+        });
+        info!("Job {} started.", self.id);
+        true
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_sync_run(&self, id_sync_run: u64) {
+        self.manager
+            .update(self.id, |job| {
+                job.id_sync_run = Some(id_sync_run);
+                None
+            });
+    }
+
+    /// Report progress, ex: `set_progress("classified", 120, 430)`.
+    pub fn set_progress(&self, step: &str, done: usize, total: usize) {
+        self.manager.update(self.id, |job| {
+            job.progress = Some(JobProgress {
+                done,
+                total,
+                message: format!("{step} {done}/{total}"),
+            });
+            Some(Event::JobProgress { job: job.clone() })
+        });
+    }
+
+    /// Mark the job as finished, cancelled if its cancellation has been requested.
+    pub fn finish(&mut self, result: Result<(), String>) {
+        let cancelled = self.is_cancelled();
+        self.manager.update(self.id, |job| {
+            if job.status.is_finished() {
+                return None;
+            }
+            job.ended_at = Some(Utc::now().to_rfc3339());
+            job.status = match &result {
+                _ if cancelled => JobStatus::Cancelled,
+                Ok(()) => JobStatus::Succeeded,
+                Err(_) => JobStatus::Failed,
+            };
+            job.error = result.err();
+            info!("Job {} finished: {:?}.", job.id, job.status);
+            Some(Event::JobFinished { job: job.clone() })
+        });
+        self.run_guard = None;
+    }
+
+    /// Finish the job and its sync run, the job fails if the run has errors.
+    pub fn finish_with_sync_run(&mut self, sync_run_db: &SyncRunsDb, mut run: SyncRun) {
+        if self.is_cancelled() {
+            run.status = SyncRunStatus::Cancelled;
+        }
+        let result = if run.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(run.errors.join("\n"))
+        };
+        // saved before the run lock is released, so it does not interleave with a restore
+        sync_run_db.finish(run);
+        self.finish(result);
+    }
+}
+
+impl Drop for JobHandle {
+    fn drop(&mut self) {
+        if let Some(job) = self.job()
+            && !job.status.is_finished()
+        {
+            warn!("Job {} ended without being finished.", self.id);
+            self.finish(Err("Job ended unexpectedly".to_string()));
+        }
+    }
+}
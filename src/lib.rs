@@ -1,6 +1,12 @@
 pub mod powens;
 pub mod db;
+pub mod backup;
 pub mod csv;
+pub mod currency;
+pub mod events;
 pub mod genai;
+pub mod jobs;
+pub mod reconciliation;
 pub mod handlers;
 pub mod app_state;
+pub mod config;
@@ -1,19 +1,43 @@
-use axum::{routing::get, Router};
+use axum::extract::DefaultBodyLimit;
+use axum::middleware;
+use axum::{routing::{get, post, put}, Router};
 use clokwerk::{Job, Scheduler, TimeUnits};
 use powens_maybe_finance_connector::app_state::AppState;
-use powens_maybe_finance_connector::db::{AccountsDb, TransactionExtrasDb, TransactionsDb};
-use powens_maybe_finance_connector::genai::run_ai_guess_on_all_transactions;
+use powens_maybe_finance_connector::backup;
+use powens_maybe_finance_connector::config::env_or_default;
+use powens_maybe_finance_connector::db::{
+    AccountsDb, BalanceSnapshotsDb, ChangeSource, ChangesDb, Encryptable, ExportBatchesDb, InvestmentSyncStatesDb, InvestmentsDb, is_key_file_used, MarketOrdersDb,
+    migrate_json_to_sqlite, ReconciliationsDb, rotate_encryption_key, sqlite_path, SyncRunKind,
+    SyncRunsDb, SyncStatesDb, SyncTrigger, TransactionExtrasDb, TransactionsDb,
+};
+use powens_maybe_finance_connector::events::EventBus;
+use powens_maybe_finance_connector::genai;
+use powens_maybe_finance_connector::jobs::JobManager;
 use powens_maybe_finance_connector::handlers::{
-    accounts_to_csv_handler, fetch_transactions_from_powens_handler, list_accounts_handler,
-    list_transactions_handler, run_fetch_transactions_from_powens_job, transactions_to_csv_handler,
+    account_balances_to_csv_handler, accounts_to_csv_handler, accounts_totals_handler,
+    active_investment_accounts, backfill_transactions_handler, backup_handler, cancel_job_handler,
+    changed_since_export_handler, events_handler, export_batch_to_csv_handler,
+    export_transactions_handler, fetch_accounts_from_powens_handler, fetch_investments_from_powens,
+    fetch_investments_from_powens_handler, fetch_transactions_from_powens_handler,
+    get_export_batch_handler, get_job_handler, get_sync_run_handler, investments_to_csv_handler,
+    list_account_balances_handler, list_accounts_handler, list_connections_handler,
+    list_export_batches_handler, list_investments_handler, list_jobs_handler,
+    list_market_orders_handler, list_reconciliations_handler, list_sync_runs_handler,
+    list_transactions_handler, powens_webhook_handler, refresh_accounts_from_powens,
+    require_admin_token, restore_handler, revert_transaction_extras_handler,
+    roll_back_export_batch_handler, rotate_encryption_key_handler,
+    run_fetch_transactions_from_powens_job, schema_diagnostics_handler, sync_connection_handler,
+    trades_to_csv_handler, transaction_history_handler, transactions_to_csv_handler,
+    update_transaction_extras_handler,
 };
-use powens_maybe_finance_connector::powens::PowensApi;
+use powens_maybe_finance_connector::powens::{PowensApi, TransactionsQuery};
 use std::time::Duration;
 use tokio::signal;
 use tower_http::timeout::TimeoutLayer;
 use tower_http::trace::TraceLayer;
 use tracing::info;
 use tracing::error;
+use tracing::warn;
 
 #[tokio::main]
 async fn main() {
@@ -22,6 +46,12 @@ async fn main() {
         .with_max_level(tracing::Level::DEBUG)
         .init();
 
+    // one-shot commands
+    if let Some(command) = std::env::args().nth(1) {
+        run_command(&command);
+        return;
+    }
+
     // init file DBs
     let account_db: AccountsDb = match AccountsDb::new_account_db() {
         Ok(db) => db,
@@ -31,6 +61,80 @@ async fn main() {
         }
     };
 
+    let balance_snapshot_db: BalanceSnapshotsDb =
+        match BalanceSnapshotsDb::new_balance_snapshot_db() {
+            Ok(db) => db,
+            Err(e) => {
+                error!("Error creating BalanceSnapshotDb: {:#?}", e);
+                return;
+            }
+        };
+
+    let change_db: ChangesDb = match ChangesDb::new_change_db() {
+        Ok(db) => db,
+        Err(e) => {
+            error!("Error creating ChangeDb: {:#?}", e);
+            return;
+        }
+    };
+
+    let export_batch_db: ExportBatchesDb = match ExportBatchesDb::new_export_batch_db() {
+        Ok(db) => db,
+        Err(e) => {
+            error!("Error creating ExportBatchDb: {:#?}", e);
+            return;
+        }
+    };
+
+    let investment_db: InvestmentsDb = match InvestmentsDb::new_investment_db() {
+        Ok(db) => db,
+        Err(e) => {
+            error!("Error creating InvestmentDb: {:#?}", e);
+            return;
+        }
+    };
+
+    let investment_sync_state_db: InvestmentSyncStatesDb =
+        match InvestmentSyncStatesDb::new_investment_sync_state_db() {
+            Ok(db) => db,
+            Err(e) => {
+                error!("Error creating InvestmentSyncStateDb: {:#?}", e);
+                return;
+            }
+        };
+
+    let market_order_db: MarketOrdersDb = match MarketOrdersDb::new_market_order_db() {
+        Ok(db) => db,
+        Err(e) => {
+            error!("Error creating MarketOrderDb: {:#?}", e);
+            return;
+        }
+    };
+
+    let reconciliation_db: ReconciliationsDb = match ReconciliationsDb::new_reconciliation_db() {
+        Ok(db) => db,
+        Err(e) => {
+            error!("Error creating ReconciliationDb: {:#?}", e);
+            return;
+        }
+    };
+
+    let sync_run_db: SyncRunsDb = match SyncRunsDb::new_sync_run_db() {
+        Ok(db) => db,
+        Err(e) => {
+            error!("Error creating SyncRunDb: {:#?}", e);
+            return;
+        }
+    };
+
+    let sync_state_db: SyncStatesDb = match SyncStatesDb::new_sync_state_db() {
+        Ok(db) => db,
+        Err(e) => {
+            error!("Error creating SyncStateDb: {:#?}", e);
+            return;
+        }
+    };
+
     let transaction_db: TransactionsDb = match TransactionsDb::new_transaction_db() {
         Ok(db) => db,
         Err(e) => {
@@ -57,14 +161,31 @@ async fn main() {
         }
     };
 
+    // publish what the connector does to the event streams
+    let event_bus = EventBus::new();
+
     // App State
     let app_state = AppState {
         account_db,
+        balance_snapshot_db,
+        change_db,
+        export_batch_db,
+        investment_db,
+        investment_sync_state_db,
+        market_order_db,
+        reconciliation_db,
+        sync_run_db,
+        sync_state_db,
         transaction_db,
         transaction_extras_db,
+        event_bus: event_bus.clone(),
+        job_manager: JobManager::new(event_bus.clone()),
         powens_api,
     };
 
+    // drop the expired changes now, then every day
+    prune_changes(&app_state).await;
+
     // check and get initial data from powens if needed
     if let Err(e) = get_initial_powens_data_if_empty(&app_state).await {
         error!("Error initializing data: {:#?}", e);
@@ -79,16 +200,40 @@ async fn main() {
     // Create a new scheduler
     let mut scheduler = Scheduler::new();
     {
-        let app_state = (&app_state).clone();
+        let app_state = app_state.clone();
         scheduler
             .every(1.day())
             .at(&dotenv::var("SCHEDULER_FETCH_TRANSACTION_AT").unwrap())
             .run(move || {
                 let app_state = app_state.clone();
-                run_fetch_transactions_from_powens_job(app_state);
+                if let Err(job) =
+                    run_fetch_transactions_from_powens_job(app_state, SyncTrigger::Scheduler)
+                {
+                    warn!("Scheduled fetch skipped, job {} is already {:?}.", job.id, job.status);
+                }
             });
     }
 
+    {
+        let app_state = app_state.clone();
+        scheduler.every(1.day()).run(move || {
+            let app_state = app_state.clone();
+            tokio::spawn(async move { prune_changes(&app_state).await });
+        });
+    }
+
+    // optional scheduled backup
+    if let Ok(backup_at) = dotenv::var("BACKUP_AT")
+        && !backup_at.is_empty()
+    {
+        let app_state = app_state.clone();
+        scheduler.every(1.day()).at(&backup_at).run(move || {
+            if let Err(e) = backup::run_scheduled_backup(&app_state) {
+                error!("Error running scheduled backup: {:#?}", e);
+            }
+        });
+    }
+
     // Run scheduler loop in a spawned task
     tokio::spawn(async move {
         info!("Scheduler started.");
@@ -98,47 +243,208 @@ async fn main() {
         }
     });
 
+    // long running routes have their own timeout
+    let long_running_timeout = match env_or_default("LONG_RUNNING_REQUEST_TIMEOUT_SECS", 600) {
+        Ok(timeout) => timeout,
+        Err(e) => {
+            error!("Error reading LONG_RUNNING_REQUEST_TIMEOUT_SECS: {:#?}", e);
+            return;
+        }
+    };
+    // admin routes need the ADMIN_TOKEN
+    let admin_routes = Router::new()
+        .route("/admin/backup", get(backup_handler))
+        .route(
+            "/admin/restore",
+            post(restore_handler).layer(DefaultBodyLimit::max(backup::MAX_BACKUP_SIZE)),
+        )
+        .route(
+            "/admin/rotate-encryption-key",
+            post(rotate_encryption_key_handler),
+        )
+        .route_layer(middleware::from_fn(require_admin_token));
+    let long_running_routes = Router::new()
+        // writes wait for the running job to finish
+        .route("/transactions/backfill", post(backfill_transactions_handler))
+        .route(
+            "/transactions/{id}/extras",
+            put(update_transaction_extras_handler),
+        )
+        .route(
+            "/transactions/{id}/extras/revert",
+            post(revert_transaction_extras_handler),
+        )
+        // Powens calls may be retried for longer than the default timeout
+        .route("/accounts/fetch", get(fetch_accounts_from_powens_handler))
+        .route(
+            "/investments/fetch",
+            get(fetch_investments_from_powens_handler),
+        )
+        .route("/connections/{id}/sync", post(sync_connection_handler))
+        .route("/webhooks/powens/{event}", post(powens_webhook_handler))
+        .route("/transactions/csv", get(transactions_to_csv_handler))
+        .route("/transactions/exports", post(export_transactions_handler))
+        .route(
+            "/transactions/exports/{id}/rollback",
+            post(roll_back_export_batch_handler),
+        )
+        .merge(admin_routes)
+        .layer(TimeoutLayer::new(Duration::from_secs(long_running_timeout)));
+
     // build our application with a route
     let app = Router::new()
         // `GET /` goes to `root`
         .route("/", get(root))
         .route("/transactions", get(list_transactions_handler))
-        .route("/transactions/csv", get(transactions_to_csv_handler))
+        .route("/transactions/exports", get(list_export_batches_handler))
+        .route(
+            "/transactions/exports/changed",
+            get(changed_since_export_handler),
+        )
+        .route("/transactions/exports/{id}", get(get_export_batch_handler))
+        .route(
+            "/transactions/exports/{id}/csv",
+            get(export_batch_to_csv_handler),
+        )
+        .route(
+            "/transactions/{id}/history",
+            get(transaction_history_handler),
+        )
+        .route(
+            "/transactions/reconciliations",
+            get(list_reconciliations_handler),
+        )
         .route(
             "/transactions/fetch",
             get(fetch_transactions_from_powens_handler),
         )
         .route("/accounts", get(list_accounts_handler))
         .route("/accounts/csv", get(accounts_to_csv_handler))
+        .route("/accounts/totals", get(accounts_totals_handler))
+        .route("/accounts/{id}/balances", get(list_account_balances_handler))
+        .route(
+            "/accounts/{id}/balances/csv",
+            get(account_balances_to_csv_handler),
+        )
+        .route("/investments", get(list_investments_handler))
+        .route("/investments/csv", get(investments_to_csv_handler))
+        .route("/trades", get(list_market_orders_handler))
+        .route("/trades/csv", get(trades_to_csv_handler))
+        .route("/connections", get(list_connections_handler))
+        .route("/diagnostics/schema", get(schema_diagnostics_handler))
+        .route("/sync-runs", get(list_sync_runs_handler))
+        .route("/sync-runs/{id}", get(get_sync_run_handler))
+        .route("/jobs", get(list_jobs_handler))
+        .route("/jobs/{id}", get(get_job_handler))
+        .route("/jobs/{id}/cancel", post(cancel_job_handler))
+        .route("/events", get(events_handler))
+        // Graceful shutdown will wait for outstanding requests to complete. Add a timeout so
+        // requests don't hang forever.
+        .layer(TimeoutLayer::new(Duration::from_secs(10)))
+        // routes which may take longer than the default timeout
+        .merge(long_running_routes)
         .with_state(app_state)
-        .layer((
-            TraceLayer::new_for_http(),
-            // Graceful shutdown will wait for outstanding requests to complete. Add a timeout so
-            // requests don't hang forever.
-            TimeoutLayer::new(Duration::from_secs(10)),
-        ));
+        .layer(TraceLayer::new_for_http());
 
     // run our app with hyper, listening globally on port AXUM_PORT
     let port = dotenv::var("AXUM_PORT").unwrap();
     let bind_addr = format!("0.0.0.0:{}", port);
     let listener = tokio::net::TcpListener::bind(bind_addr).await.unwrap();
     axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
+        .with_graceful_shutdown(shutdown_signal(event_bus))
         .await
         .unwrap();
 }
 
+/// Run a one-shot command instead of the server.
+fn run_command(command: &str) {
+    match command {
+        "migrate-json-to-sqlite" => {
+            let sqlite_path = match sqlite_path() {
+                Ok(sqlite_path) => sqlite_path,
+                Err(e) => {
+                    error!("Error reading DB_SQLITE_PATH: {:#?}", e);
+                    return;
+                }
+            };
+            if let Err(e) = migrate_json_to_sqlite(&sqlite_path) {
+                error!("Error migrating JSON files to SQLite: {:#?}", e);
+            }
+        }
+        "rotate-encryption-key" => {
+            if let Err(e) = rotate_encryption_key_of_dbs() {
+                error!("Error rotating encryption key: {:#?}", e);
+            }
+        }
+        _ => error!(
+            "Unknown command: {}. Supported commands: migrate-json-to-sqlite, rotate-encryption-key",
+            command
+        ),
+    }
+}
+
+/// Delete the changes older than `CHANGES_RETENTION_DAYS` days, waiting for the running job.
+async fn prune_changes(app_state: &AppState) {
+    let _write_guard = app_state.job_manager.lock_writes().await;
+    if let Err(e) = app_state.change_db.prune() {
+        error!("Error deleting expired changes: {:#?}", e);
+    }
+}
+
+/// Re-encrypt all DBs with a new key, the server must be stopped.
+fn rotate_encryption_key_of_dbs() -> Result<(), Box<dyn std::error::Error>> {
+    let account_db = AccountsDb::new_account_db()?;
+    let balance_snapshot_db = BalanceSnapshotsDb::new_balance_snapshot_db()?;
+    let change_db = ChangesDb::new_change_db()?;
+    let export_batch_db = ExportBatchesDb::new_export_batch_db()?;
+    let investment_db = InvestmentsDb::new_investment_db()?;
+    let investment_sync_state_db = InvestmentSyncStatesDb::new_investment_sync_state_db()?;
+    let market_order_db = MarketOrdersDb::new_market_order_db()?;
+    let reconciliation_db = ReconciliationsDb::new_reconciliation_db()?;
+    let sync_run_db = SyncRunsDb::new_sync_run_db()?;
+    let sync_state_db = SyncStatesDb::new_sync_state_db()?;
+    let transaction_db = TransactionsDb::new_transaction_db()?;
+    let transaction_extras_db = TransactionExtrasDb::new_transaction_extras_db()?;
+
+    let dbs: [&dyn Encryptable; 12] = [
+        &account_db,
+        &balance_snapshot_db,
+        &change_db,
+        &export_batch_db,
+        &investment_db,
+        &investment_sync_state_db,
+        &market_order_db,
+        &reconciliation_db,
+        &sync_run_db,
+        &sync_state_db,
+        &transaction_db,
+        &transaction_extras_db,
+    ];
+    // printed before the DBs are rewritten, so the key is not lost if the rewrite is interrupted
+    rotate_encryption_key(&dbs, |new_key, previous_keys| {
+        if !is_key_file_used() {
+            println!("New encryption key, to set as DB_ENCRYPTION_KEY: {new_key}");
+            if !previous_keys.is_empty() {
+                println!(
+                    "Previous keys, to set as DB_ENCRYPTION_PREVIOUS_KEYS: {}",
+                    previous_keys.join(",")
+                );
+            }
+        }
+    })?;
+    Ok(())
+}
+
 async fn root() -> String {
     "ok".to_string()
 }
 
 fn run_ai_guess_job(app_state: &AppState) {
     let app_state = app_state.clone();
-    tokio::spawn(async move {
-        if let Err(e) = run_ai_guess_on_all_transactions(app_state).await {
-            error!("Error running AI guessing: {:#?}", e);
-        }
-    });
+    let run = app_state
+        .sync_run_db
+        .start_or_unrecorded(SyncRunKind::Classify, SyncTrigger::Startup);
+    genai::run_ai_guess_job(app_state, SyncTrigger::Startup, None, run);
 }
 
 async fn get_initial_powens_data_if_empty(
@@ -146,14 +452,41 @@ async fn get_initial_powens_data_if_empty(
 ) -> Result<(), Box<dyn std::error::Error>> {
     if app_state.account_db.is_data_empty() {
         info!("No data found in account DB, getting data from Powens.");
-        let accounts = app_state.powens_api.get_accounts().await?;
-        app_state.account_db.save(accounts)?;
+        refresh_accounts_from_powens(app_state).await?;
     }
 
     if app_state.transaction_db.is_data_empty() {
         info!("No data found in transaction DB, getting data from Powens.");
-        let transactions = app_state.powens_api.get_transactions(None).await?;
-        app_state.transaction_db.save(transactions)?;
+        let transactions = app_state
+            .powens_api
+            .get_transactions(&TransactionsQuery::default())
+            .await?;
+        app_state.change_db.upsert_recorded(
+            &app_state.transaction_db,
+            transactions,
+            ChangeSource::PowensSync,
+        )?;
+    }
+
+    // an empty investment DB is normal without investment accounts, so check each account's state
+    let never_fetched = active_investment_accounts(app_state)
+        .iter()
+        .any(|it| !app_state.investment_sync_state_db.has_fetched(it.id));
+    if never_fetched {
+        info!("Investments never fetched, getting data from Powens.");
+        // failures are recorded and retried on the next start, the server starts anyway
+        let mut run = app_state
+            .sync_run_db
+            .start_or_unrecorded(SyncRunKind::Fetch, SyncTrigger::Startup);
+        match fetch_investments_from_powens(app_state).await {
+            Ok(errors) => {
+                for (context, e) in errors {
+                    run.add_error(&app_state.event_bus, &context, e);
+                }
+            }
+            Err(e) => run.add_error(&app_state.event_bus, "Error fetching investments", e),
+        }
+        app_state.sync_run_db.finish(run);
     }
 
     info!("Powens data initialized.");
@@ -161,7 +494,7 @@ async fn get_initial_powens_data_if_empty(
     Ok(())
 }
 
-async fn shutdown_signal() {
+async fn shutdown_signal(event_bus: EventBus) {
     let ctrl_c = async {
         signal::ctrl_c()
             .await
@@ -187,4 +520,7 @@ async fn shutdown_signal() {
             info!("Received terminate signal, shutting down.");
         },
     }
+
+    // end event streams, otherwise graceful shutdown waits for their clients to disconnect
+    event_bus.close();
 }
@@ -1,10 +1,22 @@
 mod transaction;
 mod account;
+mod connection;
+mod investment;
+mod market_order;
+mod schema_drift;
 mod api;
+mod error;
+mod webhook;
 
 pub use self::transaction::*;
 pub use self::account::*;
+pub use self::connection::*;
+pub use self::investment::*;
+pub use self::market_order::*;
+pub use self::schema_drift::*;
 pub use self::api::*;
+pub use self::error::*;
+pub use self::webhook::*;
 
 pub trait HasId {
     fn id(&self) -> u64;
@@ -22,6 +34,18 @@ impl HasId for Account {
     }
 }
 
+impl HasId for Investment {
+    fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl HasId for MarketOrder {
+    fn id(&self) -> u64 {
+        self.id
+    }
+}
+
 pub trait Sortable {
     fn sortable_value(&self) -> impl Ord;
 }
@@ -37,3 +61,15 @@ impl Sortable for Transaction {
         self.date.clone()
     }
 }
+
+impl Sortable for Investment {
+    fn sortable_value(&self) -> impl Ord {
+        (self.id_account, self.id)
+    }
+}
+
+impl Sortable for MarketOrder {
+    fn sortable_value(&self) -> impl Ord {
+        (self.date.clone(), self.id)
+    }
+}
@@ -3,7 +3,8 @@ Structs and Enums related to Powens accounts APIs' responses.
 */
 
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use std::collections::BTreeMap;
+use serde_json::{Map, Value};
 
 /**
 Structure representing a Powens Bank Account.
@@ -15,6 +16,8 @@ Has totally 30 fields during my test, but not all fields are documented.
 Removed some fields base on a check of latest 100 transactions:
 - fields which are always null.
 - information field which is always an empty object.
+
+Removed fields and fields added later by Powens are kept in `extra`.
 */
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Account {
@@ -67,6 +70,9 @@ pub struct Account {
     /// Technical code of the account type.
     #[serde(rename = "type")]
     pub type_field: AccountType ,
+    /// All other fields returned by Powens, including the removed ones, so that they are not lost.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
 /**
@@ -140,6 +146,20 @@ pub enum AccountType {
     Unknown,
 }
 
+impl AccountType {
+    /// Whether the account holds investments, which can be retrieved with the investments API.
+    pub fn is_investment(&self) -> bool {
+        matches!(
+            self,
+            AccountType::Market
+                | AccountType::Pea
+                | AccountType::Pee
+                | AccountType::Per
+                | AccountType::Lifeinsurance
+        )
+    }
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Currency {
     /// ex: EUR
@@ -155,8 +175,5 @@ pub struct Currency {
     pub name: String,
 }
 
-#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-pub struct Balances {
-    #[serde(rename = "EUR")]
-    pub eur: f64,
-}
+/// Balances by currency id, ex: {"EUR": 123.45, "USD": 67.89}
+pub type Balances = BTreeMap<String, f64>;
@@ -1,63 +1,345 @@
 //! Struct and methods to call Powens' APIs
 
-use chrono::{DateTime, Utc};
-use super::{Account, AccountsResponse, Transaction, TransactionsResponse};
-use tracing::{debug, error};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use crate::config::env_or_default;
+use super::{
+    Account, AccountsResponse, Connection, ConnectionsResponse, Investment, InvestmentsResponse,
+    MarketOrder, MarketOrdersResponse, PowensError, SchemaDrift, Transaction, TransactionsResponse,
+};
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Client, Method, Response, StatusCode, Url};
+use std::time::Duration;
+use tracing::{debug, error, info, warn};
 
 pub const POWENS_DATETIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+pub const POWENS_DATE_FORMAT: &str = "%Y-%m-%d";
+
+/// Default number of transactions requested per page.
+const DEFAULT_TRANSACTIONS_PAGE_SIZE: u32 = 1000;
+/// Default maximum number of pages followed in one `get_transactions` call.
+const DEFAULT_TRANSACTIONS_MAX_PAGES: u32 = 100;
+/// Default timeout of a whole request, in seconds.
+const DEFAULT_HTTP_TIMEOUT_SECS: u64 = 60;
+/// Default timeout to connect to Powens, in seconds.
+const DEFAULT_HTTP_CONNECT_TIMEOUT_SECS: u64 = 10;
+/// Default number of retries of a failed request, not counting the first try.
+const DEFAULT_MAX_RETRIES: u32 = 3;
+/// Default delay before the first retry, doubled on each following retry.
+const DEFAULT_RETRY_BASE_DELAY_MS: u64 = 1000;
+/// Maximum delay between two retries, also applied on Retry-After.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(120);
+/// Max length of a response body kept in errors and logs.
+const MAX_LOGGED_BODY_LENGTH: usize = 500;
+
+/**
+Filters of the transactions to get, all optional.
+*/
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct TransactionsQuery {
+    /// Only transactions of this account.
+    pub id_account: Option<u64>,
+    /// Only transactions updated after this datetime.
+    pub last_update: Option<DateTime<Utc>>,
+    /// Only transactions dated on or after this date.
+    pub min_date: Option<NaiveDate>,
+    /// Only transactions dated on or before this date.
+    pub max_date: Option<NaiveDate>,
+}
 
 #[derive(Clone)]
 pub struct PowensApi {
+    /// Shared HTTP client, it is internally reference counted and keeps a connection pool.
+    client: Client,
     token: String,
     domain: String,
+    transactions_page_size: u32,
+    transactions_max_pages: u32,
+    max_retries: u32,
+    retry_base_delay: Duration,
+    /// Unmodelled fields of transactions and accounts returned by Powens.
+    schema_drift: SchemaDrift,
 }
 
 impl PowensApi {
     pub fn new() -> Result<Self, Box<dyn std::error::Error>> {
+        let client = Client::builder()
+            .timeout(Duration::from_secs(env_or_default(
+                "POWENS_HTTP_TIMEOUT_SECS",
+                DEFAULT_HTTP_TIMEOUT_SECS,
+            )?))
+            .connect_timeout(Duration::from_secs(env_or_default(
+                "POWENS_HTTP_CONNECT_TIMEOUT_SECS",
+                DEFAULT_HTTP_CONNECT_TIMEOUT_SECS,
+            )?))
+            .build()?;
+
         Ok(Self {
+            client,
             token: dotenv::var("POWENS_TOKEN")?,
             domain: dotenv::var("POWENS_APP_DOMAIN")?,
+            transactions_page_size: env_or_default(
+                "POWENS_TRANSACTIONS_PAGE_SIZE",
+                DEFAULT_TRANSACTIONS_PAGE_SIZE,
+            )?,
+            transactions_max_pages: env_or_default(
+                "POWENS_TRANSACTIONS_MAX_PAGES",
+                DEFAULT_TRANSACTIONS_MAX_PAGES,
+            )?,
+            max_retries: env_or_default("POWENS_MAX_RETRIES", DEFAULT_MAX_RETRIES)?,
+            retry_base_delay: Duration::from_millis(env_or_default(
+                "POWENS_RETRY_BASE_DELAY_MS",
+                DEFAULT_RETRY_BASE_DELAY_MS,
+            )?),
+            schema_drift: SchemaDrift::default(),
         })
     }
 
-    async fn get<T>(&self, path: &str) -> Result<T, Box<dyn std::error::Error>>
+    pub fn schema_drift(&self) -> &SchemaDrift {
+        &self.schema_drift
+    }
+
+    async fn get<T>(&self, path: &str) -> Result<T, PowensError>
+    where
+        T: for<'de> serde::Deserialize<'de>,
+    {
+        self.call(Method::GET, path).await
+    }
+
+    async fn put<T>(&self, path: &str) -> Result<T, PowensError>
     where
         T: for<'de> serde::Deserialize<'de>,
     {
-        let api = format!("{}{}", self.domain, path);
-        let token = &self.token;
+        self.call(Method::PUT, path).await
+    }
 
-        debug!("Calling Powens API: {}", api);
+    /// Call an API, retrying with an exponential backoff while the error is retryable.
+    async fn call<T>(&self, method: Method, path: &str) -> Result<T, PowensError>
+    where
+        T: for<'de> serde::Deserialize<'de>,
+    {
+        let api = self.api_url(path)?;
+
+        let mut attempt: u32 = 0;
+        loop {
+            debug!("Calling Powens API: {} {}", method, api);
 
-        let client = reqwest::Client::new();
-        let text = client.get(&api).bearer_auth(&token).send().await?.text().await?;
-        let json_res = serde_json::from_str::<T>(&text);
-        if let Ok(json) = json_res {
-            Ok(json)
-        } else {
-            error!("Failed to decode Powens API response: {:?}", &text);
-            Err(Box::new(json_res.err().unwrap()))
+            match self.call_once(method.clone(), &api).await {
+                Ok(json) => return Ok(json),
+                Err(e) if e.is_retryable() && attempt < self.max_retries => {
+                    // exponential backoff, unless Powens tells how long to wait
+                    let backoff = self.retry_base_delay.saturating_mul(2u32.saturating_pow(attempt));
+                    let delay = match &e {
+                        PowensError::RateLimited {
+                            retry_after: Some(retry_after),
+                        } => *retry_after,
+                        _ => backoff,
+                    }
+                    .min(MAX_RETRY_DELAY);
+
+                    attempt += 1;
+                    warn!(
+                        "{}, retrying {} {} in {:?} ({}/{}).",
+                        e, method, api, delay, attempt, self.max_retries
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                Err(e) => {
+                    error!("Powens API call {} {} failed: {}", method, api, e);
+                    return Err(e);
+                }
+            }
         }
     }
 
-    pub async fn get_transactions(&self, latest_last_update: Option<DateTime<Utc>>) -> Result<Vec<Transaction>, Box<dyn std::error::Error>> {
-        let last_update: String = if let Some(latest_last_update) = latest_last_update {
-            let value = latest_last_update.format(POWENS_DATETIME_FORMAT).to_string();
-            format!("&last_update={}", value)
-        } else {
-            String::new()
+    /**
+    Absolute URL of an API path.
+
+    `_links` returned by Powens may be absolute URLs or paths relative to the domain. Absolute URLs
+    must be on the Powens domain, the token is never sent to another host.
+    */
+    fn api_url(&self, path: &str) -> Result<String, PowensError> {
+        if !path.starts_with("http://") && !path.starts_with("https://") {
+            return Ok(format!("{}{}", self.domain, path));
+        }
+
+        let untrusted = || PowensError::UntrustedUrl {
+            url: path.to_string(),
         };
-        
-        let resp = self
-            .get::<TransactionsResponse>(&format!("/2.0/users/me/transactions?limit=1000{last_update}"))
+        let url = Url::parse(path).map_err(|_| untrusted())?;
+        let domain = Url::parse(&self.domain).map_err(|_| untrusted())?;
+        if url.origin() != domain.origin() {
+            return Err(untrusted());
+        }
+        Ok(path.to_string())
+    }
+
+    async fn call_once<T>(&self, method: Method, api: &str) -> Result<T, PowensError>
+    where
+        T: for<'de> serde::Deserialize<'de>,
+    {
+        let response = self
+            .client
+            .request(method, api)
+            .bearer_auth(&self.token)
+            .send()
             .await?;
-        Ok(resp.transactions)
+
+        let status = response.status();
+        let retry_after = retry_after(&response);
+        let text = response.text().await?;
+
+        if !status.is_success() {
+            let body = truncate(&text);
+            return Err(match status {
+                StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => PowensError::Unauthorized {
+                    status: status.as_u16(),
+                    body,
+                },
+                StatusCode::TOO_MANY_REQUESTS => PowensError::RateLimited { retry_after },
+                _ if status.is_server_error() => PowensError::ServerError {
+                    status: status.as_u16(),
+                    body,
+                },
+                _ => PowensError::ClientError {
+                    status: status.as_u16(),
+                    body,
+                },
+            });
+        }
+
+        serde_json::from_str::<T>(&text).map_err(|source| {
+            debug!("Undecodable Powens API response: {}", &text);
+            PowensError::Decode {
+                source,
+                body: truncate(&text),
+            }
+        })
+    }
+
+    /// Get transactions matching the query, following the `_links.next` pagination until the last page.
+    /// Fails if more than `POWENS_TRANSACTIONS_MAX_PAGES` pages would be needed.
+    pub async fn get_transactions(&self, query: &TransactionsQuery) -> Result<Vec<Transaction>, PowensError> {
+        let mut params = format!("limit={}", self.transactions_page_size);
+        if let Some(last_update) = query.last_update {
+            params.push_str(&format!(
+                "&last_update={}",
+                last_update.format(POWENS_DATETIME_FORMAT)
+            ));
+        }
+        if let Some(min_date) = query.min_date {
+            params.push_str(&format!("&min_date={}", min_date.format(POWENS_DATE_FORMAT)));
+        }
+        if let Some(max_date) = query.max_date {
+            params.push_str(&format!("&max_date={}", max_date.format(POWENS_DATE_FORMAT)));
+        }
+        let path = match query.id_account {
+            Some(id_account) => format!("/2.0/users/me/accounts/{id_account}/transactions?{params}"),
+            None => format!("/2.0/users/me/transactions?{params}"),
+        };
+
+        let mut transactions: Vec<Transaction> = Vec::new();
+        let mut next_path = Some(path);
+        let mut page = 0;
+
+        while let Some(path) = next_path {
+            if page >= self.transactions_max_pages {
+                // a truncated result would let the caller move its cursor past missing transactions
+                return Err(PowensError::TooManyPages {
+                    max_pages: self.transactions_max_pages,
+                    next: path,
+                });
+            }
+
+            let resp = self.get::<TransactionsResponse>(&path).await?;
+            page += 1;
+            debug!(
+                "Fetched page {} with {} transactions (total {}).",
+                page,
+                resp.transactions.len(),
+                resp.total
+            );
+
+            // an empty page means there is nothing more to follow, even if a next link is given
+            next_path = if resp.transactions.is_empty() {
+                None
+            } else {
+                resp.links.next.map(|link| link.href)
+            };
+            for transaction in resp.transactions.iter() {
+                self.schema_drift.record("Transaction", &transaction.extra);
+            }
+            transactions.extend(resp.transactions);
+        }
+
+        info!("Fetched {} transactions in {} pages.", transactions.len(), page);
+        Ok(transactions)
     }
 
-    pub async fn get_accounts(&self) -> Result<Vec<Account>, Box<dyn std::error::Error>> {
+    /// Get all accounts, including the disabled ones.
+    pub async fn get_accounts(&self) -> Result<Vec<Account>, PowensError> {
         let resp = self
-            .get::<AccountsResponse>("/2.0/users/me/accounts")
+            .get::<AccountsResponse>("/2.0/users/me/accounts?all")
             .await?;
+        for account in resp.accounts.iter() {
+            self.schema_drift.record("Account", &account.extra);
+        }
         Ok(resp.accounts)
     }
+
+    pub async fn get_investments(&self, id_account: u64) -> Result<Vec<Investment>, PowensError> {
+        let resp = self
+            .get::<InvestmentsResponse>(&format!("/2.0/users/me/accounts/{id_account}/investments"))
+            .await?;
+        Ok(resp.investments)
+    }
+
+    pub async fn get_market_orders(&self, id_account: u64) -> Result<Vec<MarketOrder>, PowensError> {
+        let resp = self
+            .get::<MarketOrdersResponse>(&format!("/2.0/users/me/accounts/{id_account}/marketorders"))
+            .await?;
+        Ok(resp.marketorders)
+    }
+
+    pub async fn get_connections(&self) -> Result<Vec<Connection>, PowensError> {
+        let resp = self
+            .get::<ConnectionsResponse>("/2.0/users/me/connections")
+            .await?;
+        Ok(resp.connections)
+    }
+
+    /// Ask Powens to synchronize a connection with its bank now.
+    pub async fn sync_connection(&self, id_connection: u64) -> Result<Connection, PowensError> {
+        self.put::<Connection>(&format!("/2.0/users/me/connections/{id_connection}"))
+            .await
+    }
+}
+
+/// Parse the Retry-After header, which is either a number of seconds or an HTTP date.
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?;
+    if let Ok(seconds) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    (date.with_timezone(&Utc) - Utc::now()).to_std().ok()
+}
+
+fn truncate(text: &str) -> String {
+    if text.len() <= MAX_LOGGED_BODY_LENGTH {
+        return text.to_string();
+    }
+
+    let mut end = MAX_LOGGED_BODY_LENGTH;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}...", &text[..end])
+}
+
+/// Parse a datetime returned by Powens, which are in UTC.
+pub fn parse_powens_datetime(value: &str) -> Option<DateTime<Utc>> {
+    NaiveDateTime::parse_from_str(value, POWENS_DATETIME_FORMAT)
+        .ok()
+        .map(|it| it.and_utc())
 }
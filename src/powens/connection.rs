@@ -0,0 +1,47 @@
+/*!
+Structs related to Powens connections APIs' responses.
+*/
+
+use serde::{Deserialize, Serialize};
+
+/**
+Structure representing a Powens Connection, the link between a user and a bank.
+
+See https://docs.powens.com/api-reference/user-connections/connections#connection-object
+*/
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Connection {
+    /// ID of the connection.
+    pub id: u64,
+    /// ID of the related user.
+    pub id_user: u64,
+    /// ID of the related connector.
+    pub id_connector: u64,
+    /// Last successful update of the connection.
+    pub last_update: Option<String>,
+    /// Creation date of the connection.
+    pub created: Option<String>,
+    /// Whether the connection is active and will be automatically synced.
+    pub active: bool,
+    /// Last time a push was made by the bank.
+    pub last_push: Option<String>,
+    /// Expiration date of the connection, a new SCA will be needed after this date.
+    pub expire: Option<String>,
+    /// If the last update has failed, the state code, ex: SCARequired, wrongpass, actionNeeded, bug.
+    pub state: Option<String>,
+    /// Deprecated, same as state.
+    pub error: Option<String>,
+    /// If the last update has failed, an optional error message from the bank.
+    pub error_message: Option<String>,
+    /// Date of the next automatic synchronization attempt.
+    pub next_try: Option<String>,
+}
+
+/**
+Response of /2.0/users/me/connections
+*/
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConnectionsResponse {
+    pub connections: Vec<Connection>,
+    pub total: u64,
+}
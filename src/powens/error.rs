@@ -0,0 +1,84 @@
+/*!
+Errors returned by Powens APIs calls.
+*/
+
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+#[derive(Debug)]
+pub enum PowensError {
+    /// 401 or 403, the token is invalid, expired or has not the required scope.
+    Unauthorized { status: u16, body: String },
+    /// 429, too many requests. `retry_after` is the delay asked by Powens, if any.
+    RateLimited { retry_after: Option<Duration> },
+    /// 5xx, Powens or the bank behind it is unavailable.
+    ServerError { status: u16, body: String },
+    /// Any other non successful status, the request itself is wrong.
+    ClientError { status: u16, body: String },
+    /// The response is not the expected JSON.
+    Decode { source: serde_json::Error, body: String },
+    /// The request could not be sent, or the response could not be read.
+    Network(reqwest::Error),
+    /// A link returned by Powens points outside of `POWENS_APP_DOMAIN`, it is not followed.
+    UntrustedUrl { url: String },
+    /// More pages than `POWENS_TRANSACTIONS_MAX_PAGES` would be needed, the result would be incomplete.
+    TooManyPages { max_pages: u32, next: String },
+}
+
+impl PowensError {
+    /// Whether the same request may succeed if it is sent again later.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            PowensError::RateLimited { .. } | PowensError::ServerError { .. } => true,
+            PowensError::Network(e) => e.is_timeout() || e.is_connect(),
+            _ => false,
+        }
+    }
+}
+
+impl Display for PowensError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PowensError::Unauthorized { status, .. } => {
+                write!(f, "Powens API unauthorized ({status}), check POWENS_TOKEN")
+            }
+            PowensError::RateLimited { retry_after } => {
+                write!(f, "Powens API rate limited, retry after: {retry_after:?}")
+            }
+            PowensError::ServerError { status, body } => {
+                write!(f, "Powens API server error ({status}): {body}")
+            }
+            PowensError::ClientError { status, body } => {
+                write!(f, "Powens API client error ({status}): {body}")
+            }
+            PowensError::Decode { source, .. } => {
+                write!(f, "Failed to decode Powens API response: {source}")
+            }
+            PowensError::Network(e) => write!(f, "Powens API network error: {e}"),
+            PowensError::UntrustedUrl { url } => {
+                write!(f, "Powens API link {url} is not on POWENS_APP_DOMAIN")
+            }
+            PowensError::TooManyPages { max_pages, next } => write!(
+                f,
+                "Powens API returned more than {max_pages} pages, next page {next} not fetched, \
+                increase POWENS_TRANSACTIONS_MAX_PAGES"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PowensError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PowensError::Decode { source, .. } => Some(source),
+            PowensError::Network(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for PowensError {
+    fn from(e: reqwest::Error) -> Self {
+        PowensError::Network(e)
+    }
+}
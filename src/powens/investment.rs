@@ -0,0 +1,54 @@
+/*!
+Structs related to Powens investments APIs' responses.
+*/
+
+use serde::{Deserialize, Serialize};
+
+/**
+Structure representing a Powens Investment, a position held in an investment account (market, PEA, life insurance...).
+
+See https://docs.powens.com/api-reference/products/wealth-aggregation/investments#investment-object
+*/
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Investment {
+    /// ID of the investment.
+    pub id: u64,
+    /// ID of the related account.
+    pub id_account: u64,
+    /// ID of the related security.
+    pub id_security: Option<u64>,
+    /// Label of the investment, as seen on the bank.
+    pub label: String,
+    /// Investment code, usually an ISIN.
+    pub code: Option<String>,
+    /// Type of the code, ex: ISIN, AMF.
+    pub code_type: Option<String>,
+    /// Ticker of the security.
+    pub stock_symbol: Option<String>,
+    /// Quantity of units held.
+    pub quantity: f64,
+    /// Average buy price of one unit.
+    pub unitprice: Option<f64>,
+    /// Current value of one unit.
+    pub unitvalue: Option<f64>,
+    /// Total current value of the investment.
+    pub valuation: f64,
+    /// Share of the investment in the portfolio, between 0 and 1.
+    pub portfolio_share: Option<f64>,
+    /// Difference between the current valuation and the buy price.
+    pub diff: Option<f64>,
+    /// Difference in percent between the current valuation and the buy price.
+    pub diff_percent: Option<f64>,
+    /// Date of the unit value.
+    pub vdate: Option<String>,
+    /// Last update of the investment.
+    pub last_update: Option<String>,
+}
+
+/**
+Response of /2.0/users/me/accounts/{id_account}/investments
+*/
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InvestmentsResponse {
+    pub investments: Vec<Investment>,
+}
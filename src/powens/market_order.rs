@@ -0,0 +1,70 @@
+/*!
+Structs related to Powens market orders APIs' responses.
+*/
+
+use serde::{Deserialize, Serialize};
+
+/// States of orders which have not been executed, so which have no effect on holdings.
+const NOT_EXECUTED_STATES: [&str; 5] = ["pending", "cancelled", "canceled", "rejected", "expired"];
+
+/**
+Structure representing a Powens Market Order, a buy or sell of a security in an investment account.
+
+See https://docs.powens.com/api-reference/products/wealth-aggregation/market-orders#marketorder-object
+*/
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MarketOrder {
+    /// ID of the market order.
+    pub id: u64,
+    /// ID of the related investment account.
+    #[serde(alias = "id_account")]
+    pub id_invest_account: u64,
+    /// ID of the related security.
+    pub id_security: Option<u64>,
+    /// Label of the security.
+    pub label: Option<String>,
+    /// Code of the security, usually an ISIN.
+    pub code: Option<String>,
+    /// Ticker of the security.
+    pub stock_symbol: Option<String>,
+    /// Direction of the order: buy or sell.
+    pub direction: Option<String>,
+    /// Type of the order, ex: market, limit.
+    pub order_type: Option<String>,
+    /// State of the order, ex: pending, executed, cancelled.
+    pub state: Option<String>,
+    /// Quantity of units ordered.
+    pub quantity: Option<f64>,
+    /// Execution price of one unit.
+    pub unitprice: Option<f64>,
+    /// Value of one unit at the order date.
+    pub unitvalue: Option<f64>,
+    /// Date of the order.
+    pub date: Option<String>,
+    /// Execution date of the order.
+    pub exec_date: Option<String>,
+}
+
+impl MarketOrder {
+    pub fn is_executed(&self) -> bool {
+        match &self.state {
+            Some(state) => !NOT_EXECUTED_STATES.contains(&state.to_lowercase().as_str()),
+            None => true,
+        }
+    }
+
+    pub fn is_sell(&self) -> bool {
+        self.direction
+            .as_deref()
+            .is_some_and(|it| it.eq_ignore_ascii_case("sell"))
+    }
+}
+
+/**
+Response of /2.0/users/me/accounts/{id_account}/marketorders
+*/
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MarketOrdersResponse {
+    #[serde(alias = "market_orders")]
+    pub marketorders: Vec<MarketOrder>,
+}
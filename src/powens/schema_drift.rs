@@ -0,0 +1,115 @@
+/*!
+Tracking of the fields returned by Powens which are not modelled in structs.
+
+`Transaction` and `Account` keep unmodelled fields in their `extra` map, this module reports which of them appear,
+so that we notice when Powens starts to fill a field which was always null, or adds a new field.
+*/
+
+use chrono::Utc;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+
+/**
+Statistics of an unmodelled field.
+*/
+#[derive(Default, Debug, Clone, PartialEq, Serialize)]
+pub struct UnmodelledField {
+    /// Number of records having the field.
+    pub occurrences: u64,
+    /// Number of records having the field with a non null value.
+    pub non_null_occurrences: u64,
+    /// JSON types of the non null values, ex: string, number, object.
+    pub value_types: Vec<String>,
+    /// RFC 3339 datetime of the first record having the field, for fields observed at runtime.
+    pub first_seen: Option<String>,
+    /// RFC 3339 datetime of the last record having the field, for fields observed at runtime.
+    pub last_seen: Option<String>,
+}
+
+/// Unmodelled fields by struct name, then by field name.
+pub type SchemaDriftReport = BTreeMap<String, BTreeMap<String, UnmodelledField>>;
+
+/**
+Tracker of unmodelled fields of Powens responses, shared by clones.
+*/
+#[derive(Default, Clone)]
+pub struct SchemaDrift {
+    fields: Arc<Mutex<SchemaDriftReport>>,
+}
+
+impl SchemaDrift {
+    /// Record the unmodelled fields of a record, and log a warning for new fields or newly populated fields.
+    pub fn record(&self, struct_name: &str, extra: &Map<String, Value>) {
+        if extra.is_empty() {
+            return;
+        }
+
+        let now = Utc::now().to_rfc3339();
+        let mut fields = self.fields.lock().unwrap();
+        let struct_fields = fields.entry(struct_name.to_string()).or_default();
+
+        for (key, value) in extra {
+            let field = struct_fields.entry(key.clone()).or_default();
+            if field.occurrences == 0 {
+                warn!("Powens returned an unmodelled field: {}.{}", struct_name, key);
+                field.first_seen = Some(now.clone());
+            }
+            if !value.is_null() && field.non_null_occurrences == 0 {
+                warn!(
+                    "Powens returned a value for the unmodelled field: {}.{}",
+                    struct_name, key
+                );
+            }
+
+            add_occurrence(field, value);
+            field.last_seen = Some(now.clone());
+        }
+    }
+
+    /// Unmodelled fields observed since the start of the app.
+    pub fn report(&self) -> SchemaDriftReport {
+        self.fields.lock().unwrap().clone()
+    }
+
+    /// Unmodelled fields of the given records, without first and last seen datetimes.
+    pub fn report_of<'a>(
+        struct_name: &str,
+        extras: impl Iterator<Item = &'a Map<String, Value>>,
+    ) -> SchemaDriftReport {
+        let mut struct_fields: BTreeMap<String, UnmodelledField> = BTreeMap::new();
+        for extra in extras {
+            for (key, value) in extra {
+                add_occurrence(struct_fields.entry(key.clone()).or_default(), value);
+            }
+        }
+
+        let mut report = SchemaDriftReport::new();
+        if !struct_fields.is_empty() {
+            report.insert(struct_name.to_string(), struct_fields);
+        }
+        report
+    }
+}
+
+fn add_occurrence(field: &mut UnmodelledField, value: &Value) {
+    field.occurrences += 1;
+    if value.is_null() {
+        return;
+    }
+
+    field.non_null_occurrences += 1;
+    let value_type = match value {
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+        Value::Null => "null",
+    };
+    if !field.value_types.iter().any(|it| it == value_type) {
+        field.value_types.push(value_type.to_string());
+    }
+}
@@ -3,6 +3,8 @@ Structs and Enums related to Powens transactions APIs' responses.
 */
 
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use super::Currency;
 
 /**
 Structure representing a Powens Bank Transaction.
@@ -17,6 +19,8 @@ Removed some fields base on a check of latest 100 transactions:
 - state which has no official doc and is always "parsed".
 - documents_count which has no official doc and is always 0.
 - information field which is always an empty object.
+
+Removed fields and fields added later by Powens are kept in `extra`.
 */
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 // #[serde(rename_all = "camelCase")]
@@ -86,6 +90,17 @@ pub struct Transaction {
     /// Example: "-20,00 €"
     // #[serde(rename = "formatted_value")]
     pub formatted_value: String,
+
+    /// Value of the transaction in its original currency, if it is not the currency of the account.
+    /// Example: a card payment made abroad.
+    pub original_value: Option<f64>,
+
+    /// Original currency of the transaction, if it is not the currency of the account.
+    pub original_currency: Option<Currency>,
+
+    /// All other fields returned by Powens, including the removed ones, so that they are not lost.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
 /**
@@ -164,3 +179,12 @@ pub enum TransactionType {
     /// Differs from bank type because it considers only tax/commission
     Fee,
 }
+
+impl TransactionType {
+    /// Whether the transaction is a trade of securities, exported as a Maybe trade rather than a transaction.
+    ///
+    /// Market fees are not trades, they are kept as transactions.
+    pub fn is_trade(&self) -> bool {
+        matches!(self, TransactionType::MarketOrder | TransactionType::Arbitrage)
+    }
+}
@@ -0,0 +1,215 @@
+/*!
+Structs related to Powens webhooks' payloads, and webhook signature verification.
+
+See https://docs.powens.com/documentation/integration-guides/webhooks
+*/
+
+use super::{Account, Transaction};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+/// Header containing the base64 encoded HMAC-SHA256 signature of a webhook.
+pub const WEBHOOK_SIGNATURE_HEADER: &str = "BI-Signature";
+/// Header containing the date used to build the signed message of a webhook.
+pub const WEBHOOK_SIGNATURE_DATE_HEADER: &str = "BI-Signature-Date";
+/// Default maximum age of a webhook signature, in seconds, older webhooks are rejected as replays.
+pub const DEFAULT_WEBHOOK_TOLERANCE_SECS: i64 = 300;
+
+/**
+Webhook events handled by the connector.
+*/
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, strum::Display, strum::EnumString)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+#[strum(serialize_all = "SCREAMING_SNAKE_CASE", ascii_case_insensitive)]
+pub enum WebhookEvent {
+    /// A connection has been synchronized, payload contains its accounts and new transactions.
+    ConnectionSynced,
+    /// An account has been synchronized, payload is the account with its new transactions.
+    AccountSynced,
+}
+
+/**
+Payload of the CONNECTION_SYNCED webhook.
+
+Only the fields needed by the connector are kept.
+*/
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConnectionSyncedPayload {
+    pub connection: WebhookConnection,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WebhookConnection {
+    /// ID of the connection.
+    pub id: u64,
+    /// Synchronized accounts of the connection.
+    #[serde(default)]
+    pub accounts: Vec<WebhookAccount>,
+}
+
+/**
+An account pushed by a webhook, it is a Powens Bank Account with the list of its new or updated transactions.
+
+Also the payload of the ACCOUNT_SYNCED webhook.
+*/
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WebhookAccount {
+    #[serde(flatten)]
+    pub account: Account,
+    #[serde(default)]
+    pub transactions: Vec<Transaction>,
+}
+
+/**
+Verify the signature of a webhook.
+
+The signature is the base64 encoded HMAC-SHA256, keyed with the webhook secret, of:
+`POST.{path}.{signature date}.{body}`.
+*/
+pub fn verify_webhook_signature(
+    secret: &str,
+    path: &str,
+    signature_date: &str,
+    body: &[u8],
+    signature: &str,
+) -> bool {
+    // anyone can sign with an empty key
+    if secret.is_empty() {
+        return false;
+    }
+
+    let expected = match BASE64.decode(signature.trim()) {
+        Ok(expected) => expected,
+        Err(_) => return false,
+    };
+
+    let mut mac = match Hmac::<Sha256>::new_from_slice(secret.as_bytes()) {
+        Ok(mac) => mac,
+        Err(_) => return false,
+    };
+    mac.update(format!("POST.{path}.{signature_date}.").as_bytes());
+    mac.update(body);
+
+    // constant time comparison
+    mac.verify_slice(&expected).is_ok()
+}
+
+/**
+Whether the signature date of a webhook is within `tolerance_secs` of now, in the past or in the
+future, so a captured webhook can't be replayed later.
+
+The date is an HTTP date, ex: `Tue, 16 Jun 2020 16:16:08 GMT`, or an RFC 3339 datetime.
+*/
+pub fn is_signature_date_recent(
+    signature_date: &str,
+    now: DateTime<Utc>,
+    tolerance_secs: i64,
+) -> bool {
+    let signature_date = signature_date.trim();
+    let Ok(date) = DateTime::parse_from_rfc2822(signature_date)
+        .or_else(|_| DateTime::parse_from_rfc3339(signature_date))
+    else {
+        return false;
+    };
+    (now - date.with_timezone(&Utc)).num_seconds().abs() <= tolerance_secs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const SECRET: &str = "webhook-secret";
+    const PATH: &str = "/webhooks/powens/account_synced";
+    const DATE: &str = "Tue, 16 Jun 2020 16:16:08 GMT";
+    const BODY: &[u8] = br#"{"id":1,"transactions":[]}"#;
+
+    fn sign(secret: &str, path: &str, signature_date: &str, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(format!("POST.{path}.{signature_date}.").as_bytes());
+        mac.update(body);
+        BASE64.encode(mac.finalize().into_bytes())
+    }
+
+    #[test]
+    fn valid_signature_is_accepted() {
+        let signature = sign(SECRET, PATH, DATE, BODY);
+        assert!(verify_webhook_signature(
+            SECRET, PATH, DATE, BODY, &signature
+        ));
+    }
+
+    #[test]
+    fn tampered_body_is_rejected() {
+        let signature = sign(SECRET, PATH, DATE, BODY);
+        let tampered = br#"{"id":2,"transactions":[]}"#;
+        assert!(!verify_webhook_signature(
+            SECRET, PATH, DATE, tampered, &signature
+        ));
+    }
+
+    #[test]
+    fn tampered_date_or_path_is_rejected() {
+        let signature = sign(SECRET, PATH, DATE, BODY);
+        let other_date = "Tue, 16 Jun 2020 16:16:09 GMT";
+        assert!(!verify_webhook_signature(
+            SECRET, PATH, other_date, BODY, &signature
+        ));
+        let other_path = "/webhooks/powens/connection_synced";
+        assert!(!verify_webhook_signature(
+            SECRET, other_path, DATE, BODY, &signature
+        ));
+    }
+
+    #[test]
+    fn wrong_secret_is_rejected() {
+        let signature = sign("another-secret", PATH, DATE, BODY);
+        assert!(!verify_webhook_signature(
+            SECRET, PATH, DATE, BODY, &signature
+        ));
+    }
+
+    #[test]
+    fn empty_secret_is_rejected() {
+        let signature = sign("", PATH, DATE, BODY);
+        assert!(!verify_webhook_signature("", PATH, DATE, BODY, &signature));
+    }
+
+    #[test]
+    fn missing_or_invalid_signature_is_rejected() {
+        assert!(!verify_webhook_signature(SECRET, PATH, DATE, BODY, ""));
+        assert!(!verify_webhook_signature(
+            SECRET,
+            PATH,
+            DATE,
+            BODY,
+            "not base64!"
+        ));
+    }
+
+    #[test]
+    fn signature_date_within_tolerance_is_accepted() {
+        let now = Utc.with_ymd_and_hms(2020, 6, 16, 16, 20, 0).unwrap();
+        assert!(is_signature_date_recent(DATE, now, 300));
+        assert!(is_signature_date_recent("2020-06-16T16:24:00Z", now, 300));
+    }
+
+    #[test]
+    fn signature_date_outside_tolerance_is_rejected() {
+        let now = Utc.with_ymd_and_hms(2020, 6, 16, 16, 21, 9).unwrap();
+        assert!(!is_signature_date_recent(DATE, now, 300));
+        let past = Utc.with_ymd_and_hms(2020, 6, 16, 16, 11, 7).unwrap();
+        assert!(!is_signature_date_recent(DATE, past, 300));
+    }
+
+    #[test]
+    fn missing_or_invalid_signature_date_is_rejected() {
+        let now = Utc.with_ymd_and_hms(2020, 6, 16, 16, 16, 8).unwrap();
+        assert!(!is_signature_date_recent("", now, 300));
+        assert!(!is_signature_date_recent("yesterday", now, 300));
+    }
+}
@@ -0,0 +1,353 @@
+/*!
+Reconciliation of coming transactions with the posted transactions replacing them.
+
+When a coming transaction is posted, Powens often deletes it and creates a new transaction with a new id,
+so the coming one would stay in DB forever, with its transaction extras.
+*/
+
+use crate::app_state::AppState;
+use crate::config::env_or_default;
+use crate::db::{ChangeSource, Reconciliation, TransactionExtras};
+use crate::powens::Transaction;
+use chrono::{NaiveDate, Utc};
+use std::collections::HashSet;
+use tracing::{debug, info};
+
+/// Default max number of days between the dates of a coming transaction and its posted transaction.
+const DEFAULT_DATE_WINDOW_DAYS: i64 = 7;
+/// Default min wording similarity, between 0 and 1, for a posted transaction to match a coming one.
+const DEFAULT_MIN_WORDING_SIMILARITY: f64 = 0.5;
+/// Max difference of value for two transactions to be considered of the same amount.
+const VALUE_TOLERANCE: f64 = 0.005;
+
+/**
+Match posted transactions to coming transactions in DB, by account, value, date window and wording similarity.
+
+Only newly inserted posted transactions are matched, as the others have already been. Coming
+transactions in `fetched_ids`, the IDs returned by Powens in the same fetch, still exist on Powens
+and are not matched.
+
+For each match, categories and tags of the coming transaction are carried over to the posted transaction,
+then the coming transaction and its extras are removed. Matches are recorded in the reconciliations DB.
+*/
+pub fn reconcile_coming_transactions(
+    app_state: &AppState,
+    new_transactions: &[Transaction],
+    fetched_ids: &HashSet<u64>,
+) -> Result<Vec<Reconciliation>, Box<dyn std::error::Error>> {
+    let date_window_days: i64 =
+        env_or_default("RECONCILE_DATE_WINDOW_DAYS", DEFAULT_DATE_WINDOW_DAYS)?;
+    let min_similarity: f64 = env_or_default(
+        "RECONCILE_MIN_WORDING_SIMILARITY",
+        DEFAULT_MIN_WORDING_SIMILARITY,
+    )?;
+
+    let mut coming_transactions: Vec<Transaction> = app_state.transaction_db.data();
+    coming_transactions.retain(|it| it.coming && !fetched_ids.contains(&it.id));
+    if coming_transactions.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut matched_coming_ids: HashSet<u64> = HashSet::new();
+    let mut reconciliations: Vec<Reconciliation> = Vec::new();
+
+    for posted in new_transactions.iter().filter(|it| !it.coming) {
+        let best_match = find_best_match(
+            &coming_transactions,
+            posted,
+            &matched_coming_ids,
+            date_window_days,
+            min_similarity,
+        );
+
+        if let Some((coming, similarity)) = best_match {
+            debug!(
+                "Coming transaction {} is posted as transaction {} (similarity {:.2}).",
+                coming.id, posted.id, similarity
+            );
+            matched_coming_ids.insert(coming.id);
+            reconciliations.push(merge(app_state, coming, posted, similarity)?);
+        }
+    }
+
+    if !reconciliations.is_empty() {
+        info!(
+            "Reconciled {} coming transactions with posted transactions.",
+            reconciliations.len()
+        );
+    }
+
+    Ok(reconciliations)
+}
+
+/// Most similar coming transaction matching a posted transaction, then the closest by date.
+fn find_best_match<'a>(
+    coming_transactions: &'a [Transaction],
+    posted: &Transaction,
+    matched_coming_ids: &HashSet<u64>,
+    date_window_days: i64,
+    min_similarity: f64,
+) -> Option<(&'a Transaction, f64)> {
+    coming_transactions
+        .iter()
+        .filter(|coming| {
+            coming.id != posted.id
+                && coming.id_account == posted.id_account
+                && !matched_coming_ids.contains(&coming.id)
+                && (coming.value - posted.value).abs() < VALUE_TOLERANCE
+                && days_between(&coming.date, &posted.date)
+                    .is_some_and(|days| days <= date_window_days)
+        })
+        .map(|coming| (coming, wording_similarity(coming, posted)))
+        .filter(|(_, similarity)| *similarity >= min_similarity)
+        .max_by(|(a, a_similarity), (b, b_similarity)| {
+            // most similar, then closest date
+            a_similarity.total_cmp(b_similarity).then_with(|| {
+                days_between(&b.date, &posted.date).cmp(&days_between(&a.date, &posted.date))
+            })
+        })
+}
+
+fn merge(
+    app_state: &AppState,
+    coming: &Transaction,
+    posted: &Transaction,
+    similarity: f64,
+) -> Result<Reconciliation, Box<dyn std::error::Error>> {
+    // carry over categories and tags
+    let coming_extras = app_state.transaction_extras_db.find_by_id(coming.id);
+    let mut carried_over_extras = false;
+    if let Some(coming_extras) = &coming_extras {
+        let posted_extras = app_state.transaction_extras_db.find_by_id(posted.id);
+        let mut extras = posted_extras.unwrap_or(TransactionExtras {
+            id: posted.id,
+            categories: vec![],
+            tags: vec![],
+        });
+
+        if extras.categories.is_empty() {
+            extras.categories = coming_extras.categories.clone();
+        }
+        for tag in coming_extras.tags.iter() {
+            if !extras.tags.contains(tag) {
+                extras.tags.push(tag.clone());
+            }
+        }
+
+        app_state.change_db.upsert_recorded(
+            &app_state.transaction_extras_db,
+            [extras],
+            ChangeSource::PowensSync,
+        )?;
+        app_state.change_db.delete_recorded(
+            &app_state.transaction_extras_db,
+            coming.id,
+            ChangeSource::PowensSync,
+        )?;
+        carried_over_extras = true;
+    }
+
+    // remove the ghost coming transaction
+    app_state.change_db.delete_recorded(
+        &app_state.transaction_db,
+        coming.id,
+        ChangeSource::PowensSync,
+    )?;
+
+    let reconciliation = Reconciliation {
+        id: posted.id,
+        coming_id: coming.id,
+        id_account: posted.id_account,
+        value: posted.value,
+        coming_date: coming.date.clone(),
+        posted_date: posted.date.clone(),
+        coming_wording: coming.wording.clone(),
+        posted_wording: posted.wording.clone(),
+        wording_similarity: similarity,
+        carried_over_extras,
+        reconciled_at: Utc::now().to_rfc3339(),
+    };
+    app_state.reconciliation_db.upsert(reconciliation.clone())?;
+
+    Ok(reconciliation)
+}
+
+/// Number of days between two dates in format %Y-%m-%d.
+fn days_between(a: &str, b: &str) -> Option<i64> {
+    let a = NaiveDate::parse_from_str(a, "%Y-%m-%d").ok()?;
+    let b = NaiveDate::parse_from_str(b, "%Y-%m-%d").ok()?;
+    Some((a - b).num_days().abs())
+}
+
+/// Jaccard similarity of the words of the wordings, between 0 and 1.
+/// An empty wording is similar to nothing, there is nothing to compare.
+fn wording_similarity(a: &Transaction, b: &Transaction) -> f64 {
+    let words = |t: &Transaction| -> HashSet<String> {
+        let wording = if t.stemmed_wording.is_empty() {
+            &t.original_wording
+        } else {
+            &t.stemmed_wording
+        };
+        wording
+            .to_lowercase()
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|it| !it.is_empty())
+            .map(String::from)
+            .collect()
+    };
+
+    let a = words(a);
+    let b = words(b);
+    if a.is_empty() || b.is_empty() {
+        return 0.0;
+    }
+
+    let intersection = a.intersection(&b).count() as f64;
+    let union = a.union(&b).count() as f64;
+    intersection / union
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transaction(id: u64, date: &str, value: f64, wording: &str, coming: bool) -> Transaction {
+        Transaction {
+            id,
+            id_account: 1,
+            date: date.to_string(),
+            value,
+            original_wording: wording.to_string(),
+            coming,
+            ..Default::default()
+        }
+    }
+
+    fn best_match_id(coming_transactions: &[Transaction], posted: &Transaction) -> Option<u64> {
+        find_best_match(
+            coming_transactions,
+            posted,
+            &HashSet::new(),
+            DEFAULT_DATE_WINDOW_DAYS,
+            DEFAULT_MIN_WORDING_SIMILARITY,
+        )
+        .map(|(coming, _)| coming.id)
+    }
+
+    #[test]
+    fn days_between_is_absolute() {
+        assert_eq!(days_between("2026-10-01", "2026-10-08"), Some(7));
+        assert_eq!(days_between("2026-10-08", "2026-10-01"), Some(7));
+        assert_eq!(days_between("2026-10-01", "2026-10-01"), Some(0));
+        assert_eq!(days_between("2026-10-01", ""), None);
+    }
+
+    #[test]
+    fn identical_wordings_are_fully_similar() {
+        let a = transaction(1, "2026-10-01", -10.0, "CB CARREFOUR 01/10", true);
+        let b = transaction(2, "2026-10-02", -10.0, "cb carrefour 01/10", false);
+        assert_eq!(wording_similarity(&a, &b), 1.0);
+    }
+
+    #[test]
+    fn empty_wordings_are_not_similar() {
+        let empty = transaction(1, "2026-10-01", -10.0, "", true);
+        let other_empty = transaction(2, "2026-10-01", -10.0, "", false);
+        let carrefour = transaction(3, "2026-10-01", -10.0, "CARREFOUR", false);
+        assert_eq!(wording_similarity(&empty, &other_empty), 0.0);
+        assert_eq!(wording_similarity(&empty, &carrefour), 0.0);
+    }
+
+    #[test]
+    fn stemmed_wording_is_preferred() {
+        let mut a = transaction(
+            1,
+            "2026-10-01",
+            -10.0,
+            "CB CARREFOUR 01/10 CARTE 1234",
+            true,
+        );
+        a.stemmed_wording = "carrefour".to_string();
+        let mut b = transaction(2, "2026-10-02", -10.0, "PRLV CARREFOUR", false);
+        b.stemmed_wording = "carrefour".to_string();
+        assert_eq!(wording_similarity(&a, &b), 1.0);
+    }
+
+    #[test]
+    fn matches_within_date_window() {
+        let coming = [transaction(1, "2026-10-01", -10.0, "CB CARREFOUR", true)];
+        let posted = transaction(2, "2026-10-08", -10.0, "CB CARREFOUR", false);
+        assert_eq!(best_match_id(&coming, &posted), Some(1));
+    }
+
+    #[test]
+    fn date_just_outside_window_does_not_match() {
+        let coming = [transaction(1, "2026-10-01", -10.0, "CB CARREFOUR", true)];
+        let posted = transaction(2, "2026-10-09", -10.0, "CB CARREFOUR", false);
+        assert_eq!(best_match_id(&coming, &posted), None);
+    }
+
+    #[test]
+    fn value_within_tolerance_matches() {
+        let coming = [transaction(1, "2026-10-01", -10.0, "CB CARREFOUR", true)];
+        let posted = transaction(2, "2026-10-01", -10.004, "CB CARREFOUR", false);
+        assert_eq!(best_match_id(&coming, &posted), Some(1));
+    }
+
+    #[test]
+    fn value_outside_tolerance_does_not_match() {
+        let coming = [transaction(1, "2026-10-01", -10.0, "CB CARREFOUR", true)];
+        let posted = transaction(2, "2026-10-01", -10.01, "CB CARREFOUR", false);
+        assert_eq!(best_match_id(&coming, &posted), None);
+    }
+
+    #[test]
+    fn similarity_threshold_is_inclusive() {
+        // 1 common word out of 2 words: similarity 0.5
+        let coming = [transaction(1, "2026-10-01", -10.0, "CARREFOUR", true)];
+        let posted = transaction(2, "2026-10-01", -10.0, "CARREFOUR MARKET", false);
+        assert_eq!(best_match_id(&coming, &posted), Some(1));
+
+        // 1 common word out of 3 words: similarity 0.33
+        let posted = transaction(2, "2026-10-01", -10.0, "CARREFOUR CITY PARIS", false);
+        assert_eq!(best_match_id(&coming, &posted), None);
+    }
+
+    #[test]
+    fn empty_wordings_do_not_match() {
+        let coming = [transaction(1, "2026-10-01", -10.0, "", true)];
+        let posted = transaction(2, "2026-10-01", -10.0, "", false);
+        assert_eq!(best_match_id(&coming, &posted), None);
+    }
+
+    #[test]
+    fn most_similar_then_closest_date_wins() {
+        let coming = [
+            transaction(1, "2026-10-01", -10.0, "CB CARREFOUR MARKET", true),
+            transaction(2, "2026-10-03", -10.0, "CB CARREFOUR", true),
+            transaction(3, "2026-10-05", -10.0, "CB CARREFOUR", true),
+        ];
+        let posted = transaction(4, "2026-10-06", -10.0, "CB CARREFOUR", false);
+        assert_eq!(best_match_id(&coming, &posted), Some(3));
+    }
+
+    #[test]
+    fn other_account_and_already_matched_are_skipped() {
+        let mut other_account = transaction(1, "2026-10-01", -10.0, "CB CARREFOUR", true);
+        other_account.id_account = 2;
+        let coming = [
+            other_account,
+            transaction(2, "2026-10-01", -10.0, "CB CARREFOUR", true),
+        ];
+        let posted = transaction(3, "2026-10-01", -10.0, "CB CARREFOUR", false);
+        let matched = HashSet::from([2]);
+        let best_match = find_best_match(
+            &coming,
+            &posted,
+            &matched,
+            DEFAULT_DATE_WINDOW_DAYS,
+            DEFAULT_MIN_WORDING_SIMILARITY,
+        );
+        assert!(best_match.is_none());
+    }
+}